@@ -0,0 +1,891 @@
+use std::collections::BTreeMap;
+use std::ffi::{OsStr, OsString};
+use std::io;
+#[cfg(unix)]
+use std::path::{Path, PathBuf};
+
+use crate::{ReexecError, Strategy};
+
+#[cfg(unix)]
+use std::os::unix::prelude::*;
+#[cfg(windows)]
+use std::os::windows::prelude::*;
+
+/// A builder for safely re-executing the current program.
+///
+/// By default, the program is re-executed with the arguments it was originally started with and
+/// with the current environment. Both can be changed with the methods on this struct, which mirror
+/// the ones on [`std::process::Command`].
+///
+/// Unlike [`reexecve()`](crate::reexecve), this takes care of building the `argv` and `envp`
+/// arrays. The environment is snapshotted with [`std::env::vars_os()`], which holds the standard
+/// library's environment lock while copying it, so this is sound even if other threads are
+/// modifying the environment (via `std::env::set_var()` and friends).
+#[derive(Clone, Debug, Default)]
+pub struct Reexec {
+    arg0: Option<OsString>,
+    args: Option<Vec<OsString>>,
+    env_clear: bool,
+    env: BTreeMap<OsString, Option<OsString>>,
+    pub(crate) strategies: Option<Vec<Strategy>>,
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    pub(crate) recover_deleted: bool,
+    #[cfg(unix)]
+    pub(crate) preserve_signal_state: bool,
+    #[cfg(unix)]
+    pub(crate) default_signals: Vec<i32>,
+    #[cfg(unix)]
+    pub(crate) close_fds_above: Option<RawFd>,
+    #[cfg(unix)]
+    pub(crate) keep_fds: Vec<RawFd>,
+    #[cfg(unix)]
+    pub(crate) pass_fds: Vec<(String, RawFd)>,
+    #[cfg(unix)]
+    pub(crate) children: Vec<u32>,
+    #[cfg(unix)]
+    reason: Option<String>,
+    /// Don't pass a lineage to the new program (used when spawning a new process, which isn't a
+    /// re-exec).
+    #[cfg(unix)]
+    pub(crate) omit_lineage: bool,
+    #[cfg(unix)]
+    pub(crate) loop_guard: Option<crate::LoopGuard>,
+    #[cfg(unix)]
+    pub(crate) current_dir: Option<PathBuf>,
+    #[cfg(unix)]
+    pub(crate) umask: Option<u32>,
+}
+
+impl Reexec {
+    /// Create a new builder that will re-execute the program with its current arguments and
+    /// environment.
+    #[inline]
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Set the value of `argv[0]` for the new program.
+    ///
+    /// By default, the current `argv[0]` is preserved.
+    pub fn arg0<S: AsRef<OsStr>>(&mut self, arg0: S) -> &mut Self {
+        self.arg0 = Some(arg0.as_ref().into());
+        self
+    }
+
+    /// Add an argument to pass to the new program.
+    ///
+    /// The first time this (or [`Self::args()`]) is called, the program's original arguments
+    /// (excluding `argv[0]`) are discarded.
+    pub fn arg<S: AsRef<OsStr>>(&mut self, arg: S) -> &mut Self {
+        self.args
+            .get_or_insert_with(Vec::new)
+            .push(arg.as_ref().into());
+        self
+    }
+
+    /// Add multiple arguments to pass to the new program.
+    ///
+    /// See [`Self::arg()`].
+    pub fn args<I, S>(&mut self, args: I) -> &mut Self
+    where
+        I: IntoIterator<Item = S>,
+        S: AsRef<OsStr>,
+    {
+        self.args
+            .get_or_insert_with(Vec::new)
+            .extend(args.into_iter().map(|arg| arg.as_ref().into()));
+        self
+    }
+
+    /// Set an environment variable for the new program.
+    pub fn env<K, V>(&mut self, key: K, val: V) -> &mut Self
+    where
+        K: AsRef<OsStr>,
+        V: AsRef<OsStr>,
+    {
+        self.env
+            .insert(key.as_ref().into(), Some(val.as_ref().into()));
+        self
+    }
+
+    /// Set multiple environment variables for the new program.
+    pub fn envs<I, K, V>(&mut self, vars: I) -> &mut Self
+    where
+        I: IntoIterator<Item = (K, V)>,
+        K: AsRef<OsStr>,
+        V: AsRef<OsStr>,
+    {
+        for (key, val) in vars {
+            self.env(key, val);
+        }
+        self
+    }
+
+    /// Remove an environment variable from the new program's environment.
+    pub fn env_remove<K: AsRef<OsStr>>(&mut self, key: K) -> &mut Self {
+        self.env.insert(key.as_ref().into(), None);
+        self
+    }
+
+    /// Clear the new program's environment, so it will only contain variables explicitly added
+    /// with [`Self::env()`] or [`Self::envs()`].
+    pub fn env_clear(&mut self) -> &mut Self {
+        self.env_clear = true;
+        self.env.clear();
+        self
+    }
+
+    /// Set the list of strategies that will be tried (in order) to locate the executable.
+    ///
+    /// By default, the list returned by [`strategies()`](crate::strategies) is used.
+    pub fn strategies<I: IntoIterator<Item = Strategy>>(&mut self, strategies: I) -> &mut Self {
+        self.strategies = Some(strategies.into_iter().collect());
+        self
+    }
+
+    /// Set whether to recover if the executable has been deleted (or replaced by another file).
+    ///
+    /// If this is enabled and [`is_exe_deleted()`](crate::is_exe_deleted) returns `true`,
+    /// [`Self::exec()`] will copy the image of the running program into a sealed memfd (see
+    /// [`snapshot_running_image()`](crate::snapshot_running_image)) and execute that, so the exact
+    /// same version of the program is re-executed. By default, this is disabled.
+    #[cfg_attr(docsrs, doc(cfg(any(target_os = "linux", target_os = "android"))))]
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    pub fn recover_deleted(&mut self, recover: bool) -> &mut Self {
+        self.recover_deleted = recover;
+        self
+    }
+
+    /// Set whether to preserve the signal mask and interval timers when re-executing the program.
+    ///
+    /// By default, right before calling `execve()`, all signals are unblocked (since the signal
+    /// mask is inherited by the new program, re-executing from a signal handler would otherwise
+    /// leave that signal blocked), all interval timers (including those set by `alarm()`) are
+    /// cancelled, and the signals passed to [`Self::default_signals()`] are reset to their default
+    /// dispositions. This ensures that the new program starts in a predictable state. If
+    /// re-executing the program fails, all of these changes are undone.
+    ///
+    /// Signals that are pending when they're unblocked are not delivered to this program's
+    /// handlers: every signal with a handler is reset to its default disposition first (as
+    /// `execve()` would do), so a pending signal is handled just as it would be in the new program.
+    /// Dispositions are process-wide, so other threads also see the default dispositions until
+    /// `execve()` succeeds or the changes are undone.
+    ///
+    /// Passing `true` disables this.
+    #[cfg_attr(docsrs, doc(cfg(unix)))]
+    #[cfg(unix)]
+    pub fn preserve_signal_state(&mut self, preserve: bool) -> &mut Self {
+        self.preserve_signal_state = preserve;
+        self
+    }
+
+    /// Reset the given signals to their default dispositions before re-executing the program.
+    ///
+    /// `execve()` already does this for signals that have handlers installed, but signals that are
+    /// ignored (`SIG_IGN`) stay ignored in the new program. This has no effect if
+    /// [`Self::preserve_signal_state()`] is enabled.
+    #[cfg_attr(docsrs, doc(cfg(unix)))]
+    #[cfg(unix)]
+    pub fn default_signals<I: IntoIterator<Item = i32>>(&mut self, signals: I) -> &mut Self {
+        self.default_signals.extend(signals);
+        self
+    }
+
+    /// Close every file descriptor above `min_fd` when re-executing the program, except those
+    /// passed to [`Self::keep_fd()`].
+    ///
+    /// For example, `close_fds_above(2)` makes the new program start with only standard input,
+    /// output, and error open. This is implemented by setting `FD_CLOEXEC` on the file descriptors
+    /// right before calling `execve()`, using `close_range(CLOSE_RANGE_CLOEXEC)` on Linux if the
+    /// kernel supports it, and otherwise walking `/proc/self/fd` (or, failing that, trying every
+    /// file descriptor up to the `RLIMIT_NOFILE` limit). If re-executing the program fails, the
+    /// file descriptors stay open, but they are left marked close-on-exec.
+    #[cfg_attr(docsrs, doc(cfg(unix)))]
+    #[cfg(unix)]
+    pub fn close_fds_above(&mut self, min_fd: RawFd) -> &mut Self {
+        self.close_fds_above = Some(min_fd);
+        self
+    }
+
+    /// Keep the given file descriptor open when [`Self::close_fds_above()`] is used.
+    ///
+    /// Note that this doesn't clear `FD_CLOEXEC` on the file descriptor; if it's already marked
+    /// close-on-exec, it will still be closed.
+    ///
+    /// File descriptors passed with [`Self::pass_fd()`] are placed starting at file descriptor 3,
+    /// which would overwrite a kept file descriptor in that range. If that would happen,
+    /// [`Self::exec()`] fails with `EINVAL`.
+    #[cfg_attr(docsrs, doc(cfg(unix)))]
+    #[cfg(unix)]
+    pub fn keep_fd(&mut self, fd: RawFd) -> &mut Self {
+        self.keep_fds.push(fd);
+        self
+    }
+
+    /// Pass the given file descriptor to the new program under the given name.
+    ///
+    /// Right before calling `execve()`, all of the file descriptors passed to this method are
+    /// duplicated (in the order they were added) into consecutive file descriptors starting at 3,
+    /// without `FD_CLOEXEC` set, and the `LISTEN_FDS`, `LISTEN_FDNAMES`, and `LISTEN_PID`
+    /// environment variables are set to describe them. This is the same protocol used by systemd
+    /// for socket activation, so the new program can reclaim them with
+    /// [`inherited::take()`](crate::inherited::take) (or any other library that understands
+    /// `sd_listen_fds_with_names()`). If re-executing the program fails, whatever was open at
+    /// those file descriptors beforehand is put back.
+    ///
+    /// Names may not contain colons or control characters, and may be at most 255 bytes long;
+    /// otherwise, [`Self::exec()`] fails with [`io::ErrorKind::InvalidInput`]. The file
+    /// descriptor is not duplicated until the program is re-executed, so it must remain open until
+    /// then.
+    ///
+    /// File descriptors can't be passed with [`PreparedReexec`](crate::PreparedReexec), since
+    /// `LISTEN_PID` has to be computed when the program is re-executed.
+    #[cfg_attr(docsrs, doc(cfg(unix)))]
+    #[cfg(unix)]
+    pub fn pass_fd<S, F>(&mut self, name: S, fd: &F) -> &mut Self
+    where
+        S: Into<String>,
+        F: AsRawFd + ?Sized,
+    {
+        self.pass_fds.push((name.into(), fd.as_raw_fd()));
+        self
+    }
+
+    /// Pass the given [`Payload`](crate::handoff::Payload) to the new program, which can read it
+    /// with [`Payload::take()`](crate::handoff::Payload::take).
+    ///
+    /// The payload is passed with [`Self::pass_fd()`], under the name `reexec-payload`.
+    #[cfg_attr(docsrs, doc(cfg(any(target_os = "linux", target_os = "android"))))]
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    pub fn payload(&mut self, payload: &crate::handoff::Payload) -> &mut Self {
+        self.pass_fd(crate::handoff::PAYLOAD_FD_NAME, payload)
+    }
+
+    /// Pass the given [`SharedRegion`](crate::handoff::SharedRegion) to the new program, which
+    /// can map it again with [`SharedRegion::open()`](crate::handoff::SharedRegion::open).
+    ///
+    /// The region is passed with [`Self::pass_fd()`], under its name prefixed with
+    /// `reexec-region.`.
+    #[cfg_attr(docsrs, doc(cfg(any(target_os = "linux", target_os = "android"))))]
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    pub fn shared_region(&mut self, region: &crate::handoff::SharedRegion) -> &mut Self {
+        self.pass_fd(region.fd_name(), region)
+    }
+
+    /// Record the given child process, so the new program can adopt it with
+    /// [`inherited::children()`](crate::inherited::children).
+    ///
+    /// Child processes remain children of the re-executed program, but it has no way to know
+    /// about them (and wait for them) otherwise. The process IDs are passed in the
+    /// `REEXEC_CHILDREN` environment variable. On Linux, a pidfd is also opened for each child
+    /// right before calling `execve()`, and passed with [`Self::pass_fd()`] (under the name
+    /// `reexec-child.<pid>`), so the new program can't mistake an unrelated process for the child
+    /// if the child is reaped by something else and its process ID is reused.
+    ///
+    /// Like [`Self::pass_fd()`], this is not supported with
+    /// [`PreparedReexec`](crate::PreparedReexec).
+    #[cfg_attr(docsrs, doc(cfg(unix)))]
+    #[cfg(unix)]
+    pub fn child(&mut self, pid: u32) -> &mut Self {
+        self.children.push(pid);
+        self
+    }
+
+    /// Set the reason the program is being re-executed (for example, `"upgrade"` or `"config"`).
+    ///
+    /// The new program can get this with [`lineage()`](crate::lineage). The lineage is always
+    /// passed to the new program, even if [`Self::env_clear()`] is used.
+    #[cfg_attr(docsrs, doc(cfg(unix)))]
+    #[cfg(unix)]
+    pub fn reason<S: Into<String>>(&mut self, reason: S) -> &mut Self {
+        self.reason = Some(reason.into());
+        self
+    }
+
+    /// Set a guard against re-executing the program in a loop.
+    ///
+    /// Before re-executing the program, [`Self::exec()`] checks how many times it has been
+    /// re-executed recently (according to its [`Lineage`](crate::Lineage)), and either waits for
+    /// the guard's backoff delay or takes the guard's action if the limit has been reached. See
+    /// [`LoopGuard`](crate::LoopGuard).
+    ///
+    /// Loop guards are not supported with [`PreparedReexec`](crate::PreparedReexec).
+    #[cfg_attr(docsrs, doc(cfg(unix)))]
+    #[cfg(unix)]
+    pub fn loop_guard(&mut self, guard: crate::LoopGuard) -> &mut Self {
+        self.loop_guard = Some(guard);
+        self
+    }
+
+    /// Set the working directory for the new program.
+    ///
+    /// The current process changes to this directory right before calling `execve()`, so any
+    /// relative paths tried by the strategies are resolved relative to it. If re-executing the
+    /// program fails, the original working directory is restored.
+    #[cfg_attr(docsrs, doc(cfg(unix)))]
+    #[cfg(unix)]
+    pub fn current_dir<P: AsRef<Path>>(&mut self, dir: P) -> &mut Self {
+        self.current_dir = Some(dir.as_ref().into());
+        self
+    }
+
+    /// Set the file mode creation mask (umask) for the new program.
+    ///
+    /// Like [`Self::current_dir()`], this is set right before calling `execve()`, and restored if
+    /// re-executing the program fails.
+    #[cfg_attr(docsrs, doc(cfg(unix)))]
+    #[cfg(unix)]
+    pub fn umask(&mut self, mask: u32) -> &mut Self {
+        self.umask = Some(mask);
+        self
+    }
+
+    /// Create a builder that will re-execute the program the way it was originally started, as
+    /// recorded in the given [`LaunchContext`](crate::LaunchContext).
+    ///
+    /// The original arguments (including `argv[0]`) and environment are replayed, and the
+    /// original working directory and umask are restored (see [`Self::current_dir()`] and
+    /// [`Self::umask()`]). Variables that describe file descriptors and child processes passed to
+    /// the current program (such as `LISTEN_FDS`) are left out of the environment, since they no
+    /// longer apply.
+    #[cfg_attr(docsrs, doc(cfg(unix)))]
+    #[cfg(unix)]
+    pub fn from_launch_context(ctx: &crate::LaunchContext) -> Self {
+        let mut reexec = Self::new();
+
+        // An empty list means the arguments couldn't be captured, so the current ones are used
+        let mut args = ctx.args().iter();
+        if let Some(arg0) = args.next() {
+            reexec.arg0(arg0).args(args);
+        }
+        reexec.env_clear().envs(
+            ctx.env()
+                .iter()
+                .filter(|(key, _)| !crate::launch::is_handoff_var(key))
+                .map(|(key, val)| (key, val)),
+        );
+
+        if let Some(cwd) = ctx.cwd() {
+            reexec.current_dir(cwd);
+        }
+        reexec.umask(ctx.umask());
+        reexec
+    }
+
+    /// Get the file descriptors passed to [`Self::keep_fd()`], sorted and deduplicated.
+    #[cfg(unix)]
+    pub(crate) fn sorted_keep_fds(&self) -> Vec<RawFd> {
+        let mut keep = self.keep_fds.clone();
+        keep.sort_unstable();
+        keep.dedup();
+        keep
+    }
+
+    /// Build the final argument list.
+    pub(crate) fn build_argv(&self) -> Vec<OsString> {
+        let mut orig_args = std::env::args_os();
+
+        let arg0 = match self.arg0 {
+            Some(ref arg0) => {
+                orig_args.next();
+                arg0.clone()
+            }
+            None => orig_args.next().unwrap_or_default(),
+        };
+
+        let mut argv = vec![arg0];
+        match self.args {
+            Some(ref args) => argv.extend(args.iter().cloned()),
+            None => argv.extend(orig_args),
+        }
+        argv
+    }
+
+    /// Build the final environment, snapshotting the current environment if necessary.
+    pub(crate) fn build_env(&self) -> Vec<(OsString, OsString)> {
+        let mut env: BTreeMap<OsString, OsString> = if self.env_clear {
+            BTreeMap::new()
+        } else {
+            // vars_os() takes the environment lock while it copies the environment
+            std::env::vars_os().collect()
+        };
+
+        for (key, val) in self.env.iter() {
+            match val {
+                Some(val) => {
+                    env.insert(key.clone(), val.clone());
+                }
+                None => {
+                    env.remove(key);
+                }
+            }
+        }
+
+        env.into_iter().collect()
+    }
+
+    /// Build the environment that will actually be passed to `execve()`: the final environment,
+    /// plus the variables describing the lineage and the file descriptors and child processes
+    /// passed to the new program.
+    #[cfg(unix)]
+    pub(crate) fn build_exec_env(&self, fds: &[(String, RawFd)]) -> BTreeMap<OsString, OsString> {
+        let mut env = self.build_env().into_iter().collect::<BTreeMap<_, _>>();
+        let pid = std::process::id().to_string();
+
+        // Don't pass on variables that were meant for this process (unless they were set
+        // explicitly)
+        for key in crate::inherited::consumed_vars() {
+            if !self.env.contains_key(OsStr::new(key)) {
+                env.remove(OsStr::new(key));
+            }
+        }
+
+        if self.omit_lineage {
+            env.remove(OsStr::new(crate::lineage::LINEAGE_VAR));
+        } else {
+            env.insert(
+                crate::lineage::LINEAGE_VAR.into(),
+                crate::lineage::next_env(self.reason.as_deref()),
+            );
+        }
+
+        if !fds.is_empty() {
+            let names = fds
+                .iter()
+                .map(|(name, _)| name.as_str())
+                .collect::<Vec<_>>()
+                .join(":");
+            env.insert("LISTEN_FDS".into(), fds.len().to_string().into());
+            env.insert("LISTEN_FDNAMES".into(), names.into());
+            env.insert("LISTEN_PID".into(), pid.clone().into());
+        }
+
+        if !self.children.is_empty() {
+            let children = self
+                .children
+                .iter()
+                .map(|child| child.to_string())
+                .collect::<Vec<_>>()
+                .join(",");
+            env.insert(
+                crate::inherited::CHILDREN_VAR.into(),
+                format!("{}:{}", pid, children).into(),
+            );
+        }
+
+        env
+    }
+
+    /// Re-execute the current program.
+    ///
+    /// This will only return if an error occurs (see [`reexecve()`](crate::reexecve) for
+    /// details). If any of the arguments or environment variables contain NUL bytes, an error
+    /// with kind [`io::ErrorKind::InvalidInput`] is returned.
+    pub fn exec(&self) -> io::Error {
+        #[cfg(any(target_os = "linux", target_os = "android"))]
+        if self.recover_deleted && crate::is_exe_deleted() {
+            return match crate::snapshot_running_image() {
+                Ok(handle) => self.exec_handle(&handle),
+                Err(e) => e,
+            };
+        }
+
+        let strategies = match self.strategies {
+            Some(ref strategies) => strategies,
+            None => crate::strategies(),
+        };
+
+        #[cfg(unix)]
+        return self
+            .exec_impl(|argv, envp, _| unsafe { crate::imp::reexecve(strategies, argv, envp) });
+        #[cfg(windows)]
+        return self
+            .exec_impl(|argv, envp| unsafe { crate::imp::wreexecve(strategies, argv, envp) });
+    }
+
+    /// Build the `argv` and `envp` arrays ahead of time, so the program can later be re-executed
+    /// from an async-signal-safe context.
+    ///
+    /// See [`PreparedReexec`](crate::PreparedReexec).
+    #[cfg_attr(docsrs, doc(cfg(unix)))]
+    #[cfg(unix)]
+    pub fn prepare(&self) -> io::Result<crate::PreparedReexec> {
+        crate::PreparedReexec::new(self)
+    }
+
+    /// Re-execute the program that the given [`ExeHandle`](crate::ExeHandle) refers to.
+    ///
+    /// See [`ExeHandle::reexecve()`](crate::ExeHandle::reexecve) and [`Self::exec()`]. If the
+    /// kernel doesn't support `execveat()`, this falls back on the strategies set with
+    /// [`Self::strategies()`].
+    #[cfg_attr(docsrs, doc(cfg(any(target_os = "linux", target_os = "android"))))]
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    pub fn exec_handle(&self, handle: &crate::ExeHandle) -> io::Error {
+        let strategies = match self.strategies {
+            Some(ref strategies) => strategies,
+            None => crate::strategies(),
+        };
+
+        self.exec_impl(|argv, envp, placed| unsafe {
+            handle.reexecve_via(placed.translate(handle.as_raw_fd()), strategies, argv, envp)
+        })
+    }
+
+    #[cfg(unix)]
+    fn exec_impl<F>(&self, f: F) -> io::Error
+    where
+        F: FnOnce(
+            *const *const libc::c_char,
+            *const *const libc::c_char,
+            &crate::imp::fds::PlacedFds,
+        ) -> ReexecError,
+    {
+        use std::ffi::CString;
+
+        if let Some(ref guard) = self.loop_guard {
+            if let Err(e) = guard.check() {
+                return e;
+            }
+        }
+
+        #[cfg(any(target_os = "linux", target_os = "android"))]
+        let pidfds = crate::imp::pidfd::PidFds::open_all(&self.children);
+
+        #[cfg(any(target_os = "linux", target_os = "android"))]
+        let pass_fds = {
+            use crate::inherited::CHILD_FD_PREFIX;
+
+            let mut pass_fds = self.pass_fds.clone();
+            pass_fds.extend(
+                pidfds
+                    .0
+                    .iter()
+                    .map(|&(pid, fd)| (format!("{}{}", CHILD_FD_PREFIX, pid), fd)),
+            );
+            pass_fds
+        };
+        #[cfg(not(any(target_os = "linux", target_os = "android")))]
+        let pass_fds = self.pass_fds.clone();
+
+        if let Some((name, _)) = pass_fds.iter().find(|(name, _)| !is_valid_fd_name(name)) {
+            return io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("invalid file descriptor name {:?}", name),
+            );
+        }
+
+        let sources = pass_fds.iter().map(|&(_, fd)| fd).collect::<Vec<_>>();
+        let start = crate::imp::fds::LISTEN_FDS_START;
+        if self.keep_fds.iter().any(|&fd| {
+            fd >= start
+                && matches!(sources.get((fd - start) as usize), Some(&source) if source != fd)
+        }) {
+            return io::Error::from_raw_os_error(libc::EINVAL);
+        }
+
+        let argv = match self
+            .build_argv()
+            .iter()
+            .map(|arg| CString::new(arg.as_bytes()).map_err(|_| nul_error()))
+            .collect::<io::Result<Vec<_>>>()
+        {
+            Ok(argv) => argv,
+            Err(e) => return e,
+        };
+
+        let envp = match self
+            .build_exec_env(&pass_fds)
+            .iter()
+            .map(|(key, val)| {
+                let mut buf = Vec::with_capacity(key.len() + val.len() + 1);
+                buf.extend_from_slice(key.as_bytes());
+                buf.push(b'=');
+                buf.extend_from_slice(val.as_bytes());
+                CString::new(buf).map_err(|_| nul_error())
+            })
+            .collect::<io::Result<Vec<_>>>()
+        {
+            Ok(envp) => envp,
+            Err(e) => return e,
+        };
+
+        let cwd = match self.current_dir {
+            Some(ref dir) => match CString::new(dir.as_os_str().as_bytes()) {
+                Ok(dir) => Some(dir),
+                Err(_) => return nul_error(),
+            },
+            None => None,
+        };
+
+        let argv_ptrs = argv
+            .iter()
+            .map(|arg| arg.as_ptr())
+            .chain(std::iter::once(std::ptr::null()))
+            .collect::<Vec<_>>();
+        let envp_ptrs = envp
+            .iter()
+            .map(|var| var.as_ptr())
+            .chain(std::iter::once(std::ptr::null()))
+            .collect::<Vec<_>>();
+
+        if let Some(min_fd) = self.close_fds_above {
+            if let Err(eno) = crate::imp::fds::cloexec_above(min_fd, &self.sorted_keep_fds()) {
+                return io::Error::from_raw_os_error(eno);
+            }
+        }
+
+        let placed = match crate::imp::fds::place_fds(&sources) {
+            Ok(placed) => placed,
+            Err(eno) => return io::Error::from_raw_os_error(eno),
+        };
+
+        let procstate = match unsafe {
+            crate::imp::procstate::apply(
+                cwd.as_deref(),
+                self.umask.map(|mask| mask as libc::mode_t),
+            )
+        } {
+            Ok(procstate) => procstate,
+            Err(eno) => {
+                placed.restore();
+                return io::Error::from_raw_os_error(eno);
+            }
+        };
+
+        let saved = if self.preserve_signal_state {
+            None
+        } else {
+            Some(unsafe { crate::imp::sigstate::normalize(&self.default_signals) })
+        };
+
+        let err = f(argv_ptrs.as_ptr(), envp_ptrs.as_ptr(), &placed);
+
+        if let Some(saved) = saved {
+            unsafe {
+                crate::imp::sigstate::restore(&saved, &self.default_signals);
+            }
+        }
+        unsafe {
+            crate::imp::procstate::restore(procstate);
+        }
+        placed.restore();
+
+        err.into()
+    }
+
+    #[cfg(windows)]
+    fn exec_impl<F>(&self, f: F) -> io::Error
+    where
+        F: FnOnce(*const *const libc::wchar_t, *const *const libc::wchar_t) -> ReexecError,
+    {
+        fn to_wide<I: Iterator<Item = u16>>(it: I) -> io::Result<Vec<u16>> {
+            let mut buf = it.collect::<Vec<_>>();
+            if buf.contains(&0) {
+                return Err(nul_error());
+            }
+            buf.push(0);
+            Ok(buf)
+        }
+
+        let argv = match self
+            .build_argv()
+            .iter()
+            .map(|arg| to_wide(arg.encode_wide()))
+            .collect::<io::Result<Vec<_>>>()
+        {
+            Ok(argv) => argv,
+            Err(e) => return e,
+        };
+
+        let envp = match self
+            .build_env()
+            .iter()
+            .map(|(key, val)| {
+                to_wide(
+                    key.encode_wide()
+                        .chain(std::iter::once(b'=' as u16))
+                        .chain(val.encode_wide()),
+                )
+            })
+            .collect::<io::Result<Vec<_>>>()
+        {
+            Ok(envp) => envp,
+            Err(e) => return e,
+        };
+
+        let argv_ptrs = argv
+            .iter()
+            .map(|arg| arg.as_ptr())
+            .chain(std::iter::once(std::ptr::null()))
+            .collect::<Vec<_>>();
+        let envp_ptrs = envp
+            .iter()
+            .map(|var| var.as_ptr())
+            .chain(std::iter::once(std::ptr::null()))
+            .collect::<Vec<_>>();
+
+        f(argv_ptrs.as_ptr(), envp_ptrs.as_ptr()).into()
+    }
+}
+
+/// Check whether the given name can be passed in `LISTEN_FDNAMES`. These are the same rules that
+/// systemd applies to `FileDescriptorName=`.
+#[cfg(unix)]
+fn is_valid_fd_name(name: &str) -> bool {
+    name.len() <= 255 && !name.bytes().any(|ch| ch == b':' || ch.is_ascii_control())
+}
+
+pub(crate) fn nul_error() -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidInput,
+        "nul byte found in provided data",
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_build_argv() {
+        let orig = std::env::args_os().collect::<Vec<_>>();
+
+        assert_eq!(Reexec::new().build_argv(), orig);
+
+        assert_eq!(
+            Reexec::new().arg0("abc").build_argv(),
+            std::iter::once(OsString::from("abc"))
+                .chain(orig[1..].iter().cloned())
+                .collect::<Vec<_>>(),
+        );
+
+        assert_eq!(
+            Reexec::new().arg("a").args(vec!["b", "c"]).build_argv(),
+            vec![
+                orig[0].clone(),
+                OsString::from("a"),
+                OsString::from("b"),
+                OsString::from("c"),
+            ],
+        );
+
+        assert_eq!(
+            Reexec::new()
+                .arg0("x")
+                .args(Vec::<&str>::new())
+                .build_argv(),
+            vec![OsString::from("x")],
+        );
+    }
+
+    #[test]
+    fn test_build_env() {
+        let env = Reexec::new()
+            .env_clear()
+            .env("A", "1")
+            .envs(vec![("B", "2"), ("C", "3")])
+            .env_remove("B")
+            .build_env();
+        assert_eq!(
+            env,
+            vec![
+                (OsString::from("A"), OsString::from("1")),
+                (OsString::from("C"), OsString::from("3")),
+            ],
+        );
+
+        let env = Reexec::new()
+            .env("REEXEC_TEST_BUILD_ENV", "1")
+            .env_remove("PATH")
+            .build_env();
+        assert!(env.contains(&(OsString::from("REEXEC_TEST_BUILD_ENV"), OsString::from("1"))));
+        assert!(!env.iter().any(|(key, _)| key == "PATH"));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_signal_state_child() {
+        let stage = match std::env::var("REEXEC_TEST_SIGSTATE") {
+            Ok(stage) => stage,
+            Err(_) => return,
+        };
+
+        unsafe {
+            let mut mask = std::mem::MaybeUninit::uninit();
+            libc::sigemptyset(mask.as_mut_ptr());
+            let mut mask = mask.assume_init();
+            libc::sigaddset(&mut mask, libc::SIGUSR1);
+
+            if stage == "exec" {
+                // Re-execute with SIGUSR1 blocked
+                libc::pthread_sigmask(libc::SIG_BLOCK, &mask, std::ptr::null_mut());
+                let err = Reexec::new().env("REEXEC_TEST_SIGSTATE", "check").exec();
+                panic!("{}", err);
+            } else {
+                libc::pthread_sigmask(libc::SIG_BLOCK, std::ptr::null(), &mut mask);
+                assert_eq!(libc::sigismember(&mask, libc::SIGUSR1), 0);
+            }
+        }
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_signal_state() {
+        let status = std::process::Command::new(std::env::current_exe().unwrap())
+            .arg("--exact")
+            .arg("builder::tests::test_signal_state_child")
+            .env("REEXEC_TEST_SIGSTATE", "exec")
+            .stdout(std::process::Stdio::null())
+            .status()
+            .unwrap();
+        assert!(status.success());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_close_fds_child() {
+        let stage = match std::env::var("REEXEC_TEST_CLOSE_FDS") {
+            Ok(stage) => stage,
+            Err(_) => return,
+        };
+
+        unsafe {
+            if stage == "exec" {
+                assert_eq!(libc::dup2(0, 600), 600);
+                assert_eq!(libc::dup2(0, 601), 601);
+                let err = Reexec::new()
+                    .env("REEXEC_TEST_CLOSE_FDS", "check")
+                    .close_fds_above(2)
+                    .keep_fd(601)
+                    .exec();
+                panic!("{}", err);
+            } else {
+                assert!(libc::fcntl(600, libc::F_GETFD) < 0);
+                assert!(libc::fcntl(601, libc::F_GETFD) >= 0);
+            }
+        }
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_close_fds() {
+        let status = std::process::Command::new(std::env::current_exe().unwrap())
+            .arg("--exact")
+            .arg("builder::tests::test_close_fds_child")
+            .env("REEXEC_TEST_CLOSE_FDS", "exec")
+            .stdout(std::process::Stdio::null())
+            .status()
+            .unwrap();
+        assert!(status.success());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_exec_nul() {
+        let err = Reexec::new().arg("a\0b").exec();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+
+        let err = Reexec::new().env("A", "a\0b").exec();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+    }
+}
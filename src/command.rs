@@ -0,0 +1,106 @@
+use std::io;
+use std::os::unix::process::CommandExt as _;
+use std::process::Command;
+
+/// Extensions to [`std::process::Command`] for running the current program.
+pub trait CommandExt: Sized {
+    /// Create a `Command` that runs the current program.
+    ///
+    /// The executable is located with the strategies in [`strategies()`](crate::strategies), and
+    /// `argv[0]` is set to this process's original `argv[0]` (as [`Reexec`](crate::Reexec)
+    /// does). Arguments, environment variables, and everything else can then be configured as
+    /// usual.
+    ///
+    /// On Linux, the executable is opened with [`ExeHandle::open()`](crate::ExeHandle::open),
+    /// which falls through to the next strategy if a candidate path can't be opened. The command
+    /// then executes it through `/proc/self/fd`, so it runs the same file even if that path is
+    /// removed or replaced before the command is spawned. The handle is owned by a `pre_exec()`
+    /// hook, so it stays open for as long as the `Command` exists.
+    ///
+    /// On other platforms, or if the executable can't be opened or `/proc/self/fd` isn't
+    /// available, the command runs the path returned by
+    /// [`get_reexec_path()`](crate::get_reexec_path). In that case the path is only resolved
+    /// once: if it's removed or replaced before the command is spawned, spawning fails (or runs
+    /// the replacement), and no other strategies are tried.
+    fn current_program() -> io::Result<Self>;
+}
+
+impl CommandExt for Command {
+    fn current_program() -> io::Result<Self> {
+        #[cfg(any(target_os = "linux", target_os = "android"))]
+        if let Ok(handle) = crate::ExeHandle::open() {
+            let mut buf = [0; 32];
+            let path = handle.proc_path(&mut buf);
+            if unsafe { libc::access(path.as_ptr(), libc::X_OK) } == 0 {
+                use std::os::unix::ffi::OsStrExt;
+
+                let mut cmd = Command::new(std::ffi::OsStr::from_bytes(path.to_bytes()));
+                set_arg0(&mut cmd);
+                unsafe {
+                    // The file descriptor is close-on-exec, but it's still open when execve()
+                    // looks up the path
+                    cmd.pre_exec(move || {
+                        let _ = &handle;
+                        Ok(())
+                    });
+                }
+                return Ok(cmd);
+            }
+        }
+
+        let mut cmd = Command::new(crate::get_reexec_path()?.as_os_str());
+        set_arg0(&mut cmd);
+        Ok(cmd)
+    }
+}
+
+fn set_arg0(cmd: &mut Command) {
+    if let Some(arg0) = std::env::args_os().next() {
+        cmd.arg0(arg0);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::process::Stdio;
+
+    #[test]
+    fn test_current_program() {
+        let status = Command::current_program()
+            .unwrap()
+            .args(vec!["--exact", "__reexec_no_such_test"])
+            .stdout(Stdio::null())
+            .status()
+            .unwrap();
+        assert!(status.success());
+    }
+
+    #[test]
+    fn test_current_program_child() {
+        if std::env::var_os("REEXEC_TEST_COMMAND").is_none() {
+            return;
+        }
+
+        assert_eq!(
+            std::env::args_os().next().unwrap(),
+            std::env::var_os("REEXEC_TEST_COMMAND").unwrap()
+        );
+    }
+
+    #[test]
+    fn test_current_program_arg0() {
+        let status = Command::current_program()
+            .unwrap()
+            .args(vec![
+                "--exact",
+                "command::tests::test_current_program_child",
+            ])
+            .env("REEXEC_TEST_COMMAND", std::env::args_os().next().unwrap())
+            .stdout(Stdio::null())
+            .status()
+            .unwrap();
+        assert!(status.success());
+    }
+}
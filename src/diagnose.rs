@@ -0,0 +1,166 @@
+use std::fmt;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use crate::Strategy;
+
+/// The result of trying a single [`Strategy`]; part of a [`Diagnosis`].
+#[derive(Clone, Debug)]
+pub struct Probe {
+    strategy: Strategy,
+    path: Result<PathBuf, i32>,
+    access: Option<i32>,
+}
+
+impl Probe {
+    #[inline]
+    pub(crate) fn new(strategy: Strategy, path: Result<PathBuf, i32>, access: Option<i32>) -> Self {
+        Self {
+            strategy,
+            path,
+            access,
+        }
+    }
+
+    /// Get the strategy that was tried.
+    #[inline]
+    pub fn strategy(&self) -> Strategy {
+        self.strategy
+    }
+
+    /// Get the candidate path that the strategy produced, or the `errno` value it failed with.
+    #[inline]
+    pub fn path(&self) -> Result<&Path, i32> {
+        self.path.as_deref().map_err(|&eno| eno)
+    }
+
+    /// Get the result of checking whether the candidate path is executable (with
+    /// `eaccess(X_OK)`).
+    ///
+    /// This returns `None` if the strategy didn't produce a path, `Some(0)` if the check
+    /// succeeded, and `Some(errno)` if it failed.
+    #[inline]
+    pub fn access(&self) -> Option<i32> {
+        self.access
+    }
+
+    /// Check whether this strategy produced a usable path.
+    #[inline]
+    pub fn is_usable(&self) -> bool {
+        self.path.is_ok() && self.access == Some(0)
+    }
+}
+
+impl fmt::Display for Probe {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}: ", self.strategy)?;
+
+        match self.path {
+            Ok(ref path) => {
+                write!(f, "{}", path.display())?;
+                match self.access {
+                    Some(0) => f.write_str(" (executable)"),
+                    Some(eno) => write!(
+                        f,
+                        " (not executable: {})",
+                        io::Error::from_raw_os_error(eno)
+                    ),
+                    None => Ok(()),
+                }
+            }
+            Err(eno) => write!(f, "failed: {}", io::Error::from_raw_os_error(eno)),
+        }
+    }
+}
+
+/// A report describing how the current program's executable would be located.
+///
+/// This is returned by [`diagnose()`]. It can be printed with either `Debug` or `Display`.
+#[derive(Clone, Debug)]
+pub struct Diagnosis {
+    probes: Vec<Probe>,
+    chosen: Option<usize>,
+}
+
+impl Diagnosis {
+    /// Get the results of trying every strategy in [`strategies()`](crate::strategies), in order.
+    #[inline]
+    pub fn probes(&self) -> &[Probe] {
+        &self.probes
+    }
+
+    /// Get the result for the strategy that would be chosen by
+    /// [`get_reexec_path()`](crate::get_reexec_path), if any.
+    ///
+    /// This is determined by actually calling `get_reexec_path()` (so it follows exactly the same
+    /// rules), and then finding the first probe that produced the same path. It reflects the
+    /// global [`strategies()`](crate::strategies), not any set with
+    /// [`Reexec::strategies()`](crate::Reexec::strategies). If the filesystem changes between
+    /// the probes and the call, this may be `None`.
+    #[inline]
+    pub fn chosen(&self) -> Option<&Probe> {
+        self.chosen.map(|i| &self.probes[i])
+    }
+}
+
+impl fmt::Display for Diagnosis {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.probes.is_empty() {
+            return f.write_str("no strategies are configured");
+        }
+
+        for (i, probe) in self.probes.iter().enumerate() {
+            if i != 0 {
+                f.write_str("\n")?;
+            }
+            write!(f, "{}", probe)?;
+            if self.chosen == Some(i) {
+                f.write_str(" [chosen]")?;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Try every strategy in [`strategies()`](crate::strategies) and report the results.
+///
+/// Unlike [`get_reexec_path()`](crate::get_reexec_path), this doesn't stop at the first usable
+/// path; every strategy is tried, and the path it produced (or the error it failed with) is
+/// recorded. This is intended to help debug failures to re-execute the program.
+pub fn diagnose() -> Diagnosis {
+    let probes = crate::strategies()
+        .iter()
+        .map(|&strategy| crate::imp::probe(strategy))
+        .collect::<Vec<_>>();
+    let chosen = crate::get_reexec_path().ok().and_then(|path| {
+        probes
+            .iter()
+            .position(|probe| probe.is_usable() && probe.path() == Ok(path.as_ref()))
+    });
+
+    Diagnosis { probes, chosen }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_diagnose() {
+        let diag = diagnose();
+        assert_eq!(diag.probes().len(), crate::strategies().len());
+        for (probe, &strategy) in diag.probes().iter().zip(crate::strategies().iter()) {
+            assert_eq!(probe.strategy(), strategy);
+            assert_eq!(probe.path().is_ok(), probe.access().is_some());
+        }
+
+        let chosen = diag.chosen().unwrap();
+        assert!(chosen.is_usable());
+        assert_eq!(chosen.path().unwrap(), crate::get_reexec_path().unwrap());
+
+        let msg = diag.to_string();
+        assert_eq!(msg.lines().count(), diag.probes().len());
+        assert!(msg.contains(" [chosen]"));
+    }
+}
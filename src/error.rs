@@ -0,0 +1,234 @@
+use std::fmt;
+use std::io;
+
+use crate::Strategy;
+
+/// The maximum number of attempts that a [`ReexecError`] can record.
+const MAX_ATTEMPTS: usize = 8;
+
+/// A single failed attempt to locate (or execute) the current program.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct Attempt {
+    strategy: Strategy,
+    errno: i32,
+}
+
+impl Attempt {
+    /// Get the strategy that was tried.
+    #[inline]
+    pub fn strategy(&self) -> Strategy {
+        self.strategy
+    }
+
+    /// Get the `errno` value that the strategy failed with.
+    #[inline]
+    pub fn raw_os_error(&self) -> i32 {
+        self.errno
+    }
+}
+
+/// An error from re-executing or locating the current program.
+///
+/// This records every strategy that was tried, along with the error each one produced. Creating
+/// one does not allocate memory, so it can be used from async-signal-safe contexts.
+///
+/// [`ReexecError::raw_os_error()`] gives a single summary `errno` value: the error from the last
+/// `execve()` attempt (for [`reexecve()`](crate::reexecve)), or `ENOENT`/`EACCES` (for
+/// [`get_reexec_path()`](crate::get_reexec_path) and [`get_exe_path()`](crate::get_exe_path)).
+#[derive(Copy, Clone)]
+pub struct ReexecError {
+    errno: i32,
+    attempts: [Attempt; MAX_ATTEMPTS],
+    nattempts: usize,
+    deleted: bool,
+}
+
+impl ReexecError {
+    #[inline]
+    pub(crate) fn new(errno: i32) -> Self {
+        Self {
+            errno,
+            attempts: [Attempt {
+                strategy: Strategy::Procfs,
+                errno: 0,
+            }; MAX_ATTEMPTS],
+            nattempts: 0,
+            deleted: false,
+        }
+    }
+
+    #[cfg(unix)]
+    #[inline]
+    pub(crate) fn set_errno(&mut self, errno: i32) {
+        self.errno = errno;
+    }
+
+    #[cfg_attr(not(any(target_os = "linux", target_os = "android")), allow(dead_code))]
+    #[inline]
+    pub(crate) fn set_deleted(&mut self) {
+        self.deleted = true;
+    }
+
+    /// Record a failed attempt. If too many attempts have already been recorded, the attempt is
+    /// silently dropped.
+    #[inline]
+    pub(crate) fn push(&mut self, strategy: Strategy, errno: i32) {
+        if self.nattempts < MAX_ATTEMPTS {
+            self.attempts[self.nattempts] = Attempt { strategy, errno };
+            self.nattempts += 1;
+        }
+    }
+
+    /// Get the summary `errno` value for this error.
+    #[inline]
+    pub fn raw_os_error(&self) -> i32 {
+        self.errno
+    }
+
+    /// Get a list of the strategies that were tried, in order, and the errors they failed with.
+    #[inline]
+    pub fn attempts(&self) -> &[Attempt] {
+        &self.attempts[..self.nattempts]
+    }
+
+    /// Check whether this error occurred because the executable was deleted (or replaced by
+    /// another file).
+    ///
+    /// When this is detected, no further strategies are tried, since they might find a different
+    /// executable that has been put in place of the original one.
+    #[inline]
+    pub fn is_deleted(&self) -> bool {
+        self.deleted
+    }
+
+    /// Get the error that the given strategy failed with, if it was tried.
+    pub fn strategy_error(&self, strategy: Strategy) -> Option<i32> {
+        self.attempts()
+            .iter()
+            .find(|attempt| attempt.strategy == strategy)
+            .map(|attempt| attempt.errno)
+    }
+}
+
+impl fmt::Debug for ReexecError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("ReexecError")
+            .field("errno", &self.errno)
+            .field("attempts", &self.attempts())
+            .field("deleted", &self.deleted)
+            .finish()
+    }
+}
+
+impl fmt::Display for ReexecError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", io::Error::from_raw_os_error(self.errno))?;
+
+        if self.deleted {
+            f.write_str(" (the executable has been deleted)")?;
+        }
+
+        if self.nattempts == 0 {
+            return f.write_str(" (no strategies were tried)");
+        }
+
+        f.write_str(" (")?;
+        for (i, attempt) in self.attempts().iter().enumerate() {
+            if i != 0 {
+                f.write_str("; ")?;
+            }
+            write!(
+                f,
+                "{}: {}",
+                attempt.strategy,
+                io::Error::from_raw_os_error(attempt.errno)
+            )?;
+        }
+        f.write_str(")")
+    }
+}
+
+impl std::error::Error for ReexecError {}
+
+impl From<ReexecError> for io::Error {
+    /// Convert a `ReexecError` into an `io::Error`.
+    ///
+    /// The resulting error has the same [`io::ErrorKind`] as the summary `errno` value, and the
+    /// original `ReexecError` can be retrieved with [`io::Error::get_ref()`].
+    fn from(err: ReexecError) -> Self {
+        io::Error::new(io::Error::from_raw_os_error(err.errno).kind(), err)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_attempts() {
+        let mut err = ReexecError::new(libc::ENOENT);
+        assert_eq!(err.raw_os_error(), libc::ENOENT);
+        assert_eq!(err.attempts(), &[]);
+        assert_eq!(err.strategy_error(Strategy::Procfs), None);
+
+        err.push(Strategy::Procfs, libc::EACCES);
+        err.push(Strategy::AuxExecFn, libc::ENOENT);
+        #[cfg(unix)]
+        {
+            err.set_errno(libc::EACCES);
+            assert_eq!(err.raw_os_error(), libc::EACCES);
+        }
+
+        assert_eq!(err.attempts().len(), 2);
+        assert_eq!(err.attempts()[0].strategy(), Strategy::Procfs);
+        assert_eq!(err.attempts()[0].raw_os_error(), libc::EACCES);
+        assert_eq!(err.strategy_error(Strategy::AuxExecFn), Some(libc::ENOENT));
+        assert_eq!(err.strategy_error(Strategy::ProcInfo), None);
+
+        for _ in 0..MAX_ATTEMPTS {
+            err.push(Strategy::ProcInfo, libc::ENOENT);
+        }
+        assert_eq!(err.attempts().len(), MAX_ATTEMPTS);
+    }
+
+    #[test]
+    fn test_display() {
+        let mut err = ReexecError::new(libc::ENOENT);
+        assert!(err.to_string().ends_with("(no strategies were tried)"));
+
+        err.push(Strategy::Procfs, libc::EACCES);
+        err.push(Strategy::Argv0, libc::ENOENT);
+
+        let msg = err.to_string();
+        assert!(!msg.contains("deleted"));
+        assert!(msg.contains(&format!(
+            "procfs: {}",
+            io::Error::from_raw_os_error(libc::EACCES)
+        )));
+        assert!(msg.contains(&format!(
+            "argv[0]: {}",
+            io::Error::from_raw_os_error(libc::ENOENT)
+        )));
+
+        err.set_deleted();
+        assert!(err.is_deleted());
+        assert!(err
+            .to_string()
+            .contains("(the executable has been deleted)"));
+    }
+
+    #[test]
+    fn test_into_io_error() {
+        let mut err = ReexecError::new(libc::EACCES);
+        err.push(Strategy::Procfs, libc::EACCES);
+
+        let ioerr = io::Error::from(err);
+        assert_eq!(ioerr.kind(), io::ErrorKind::PermissionDenied);
+        let inner = ioerr
+            .get_ref()
+            .unwrap()
+            .downcast_ref::<ReexecError>()
+            .unwrap();
+        assert_eq!(inner.attempts(), err.attempts());
+    }
+}
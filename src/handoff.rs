@@ -0,0 +1,577 @@
+//! Hand state over to the re-executed program.
+//!
+//! A [`Payload`] is an opaque blob of bytes stored in a sealed memfd. It's passed to the new
+//! program with [`Reexec::payload()`](crate::Reexec::payload), which can then read it back with
+//! [`Payload::take()`].
+//!
+//! State that's too large to copy can be kept in a [`SharedRegion`] instead: a named,
+//! memfd-backed block of memory that the new program maps again with [`SharedRegion::open()`],
+//! without copying it.
+
+use std::fs::File;
+use std::io;
+use std::os::unix::prelude::*;
+
+use crate::imp::memfd::{memfd_create, SEALS};
+
+/// The name that payloads are passed under (see [`Reexec::pass_fd()`](crate::Reexec::pass_fd)).
+pub(crate) const PAYLOAD_FD_NAME: &str = "reexec-payload";
+
+const PAYLOAD_MAGIC: &[u8; 8] = b"REEXECPL";
+const PAYLOAD_FORMAT: u32 = 1;
+const PAYLOAD_HEADER_LEN: usize = 32;
+
+/// The prefix for the names that shared regions are passed under.
+const REGION_FD_PREFIX: &str = "reexec-region.";
+
+const REGION_MAGIC: &[u8; 8] = b"REEXECSR";
+const REGION_FORMAT: u32 = 1;
+const REGION_HEADER_LEN: usize = 32;
+
+/// The seals that keep a region's mapping valid.
+const REGION_SEALS: libc::c_int = libc::F_SEAL_GROW | libc::F_SEAL_SHRINK;
+
+/// Compute the 64-bit FNV-1a hash of the given data.
+fn fnv1a(data: &[u8]) -> u64 {
+    data.iter().fold(0xcbf2_9ce4_8422_2325, |hash, &byte| {
+        (hash ^ byte as u64).wrapping_mul(0x100_0000_01b3)
+    })
+}
+
+fn invalid_data(msg: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+/// Write all of `data` to `fd`.
+fn write_all(fd: RawFd, mut data: &[u8]) -> io::Result<()> {
+    while !data.is_empty() {
+        let n = unsafe { libc::write(fd, data.as_ptr() as *const _, data.len()) };
+        if n < 0 {
+            let err = io::Error::last_os_error();
+            if err.kind() != io::ErrorKind::Interrupted {
+                return Err(err);
+            }
+        } else {
+            data = &data[n as usize..];
+        }
+    }
+    Ok(())
+}
+
+/// Build the header for a payload with the given version and contents.
+fn encode_header(version: u32, data: &[u8]) -> [u8; PAYLOAD_HEADER_LEN] {
+    let mut header = [0; PAYLOAD_HEADER_LEN];
+    header[..8].copy_from_slice(PAYLOAD_MAGIC);
+    header[8..12].copy_from_slice(&PAYLOAD_FORMAT.to_le_bytes());
+    header[12..16].copy_from_slice(&version.to_le_bytes());
+    header[16..24].copy_from_slice(&(data.len() as u64).to_le_bytes());
+    header[24..32].copy_from_slice(&fnv1a(data).to_le_bytes());
+    header
+}
+
+fn read_u32(buf: &[u8], i: usize) -> u32 {
+    let mut bytes = [0; 4];
+    bytes.copy_from_slice(&buf[i..i + 4]);
+    u32::from_le_bytes(bytes)
+}
+
+fn read_u64(buf: &[u8], i: usize) -> u64 {
+    let mut bytes = [0; 8];
+    bytes.copy_from_slice(&buf[i..i + 8]);
+    u64::from_le_bytes(bytes)
+}
+
+/// Check the header at the start of `buf` and return the data that follows it.
+fn decode(buf: &[u8], version: u32) -> io::Result<&[u8]> {
+    if buf.len() < PAYLOAD_HEADER_LEN || &buf[..8] != PAYLOAD_MAGIC {
+        return Err(invalid_data("not a reexec payload".into()));
+    }
+
+    let read_u32 = |i| read_u32(buf, i);
+    let read_u64 = |i| read_u64(buf, i);
+
+    let format = read_u32(8);
+    if format != PAYLOAD_FORMAT {
+        return Err(invalid_data(format!(
+            "unsupported payload format {} (expected {})",
+            format, PAYLOAD_FORMAT
+        )));
+    }
+
+    let found = read_u32(12);
+    if found != version {
+        return Err(invalid_data(format!(
+            "payload version mismatch (expected {}, found {})",
+            version, found
+        )));
+    }
+
+    let data = &buf[PAYLOAD_HEADER_LEN..];
+    if read_u64(16) != data.len() as u64 {
+        return Err(invalid_data(format!(
+            "payload length mismatch (expected {}, found {})",
+            read_u64(16),
+            data.len()
+        )));
+    }
+    if read_u64(24) != fnv1a(data) {
+        return Err(invalid_data("payload checksum mismatch".into()));
+    }
+
+    Ok(data)
+}
+
+/// An opaque blob of state to pass to the re-executed program.
+///
+/// The data is written (along with a header containing a version tag, its length, and a
+/// checksum) into a memfd, which is then sealed so it can't be modified by either program. Pass
+/// it to the new program with [`Reexec::payload()`](crate::Reexec::payload); it's closed
+/// when the `Payload` is dropped, so it must be kept alive until then.
+///
+/// The version tag is chosen by the caller, and should be changed whenever the format of the data
+/// changes. The new program has to pass the same version tag to [`Self::take()`], so a newer
+/// version of the program will refuse to load state in a format it doesn't understand.
+#[derive(Debug)]
+pub struct Payload {
+    file: File,
+}
+
+impl Payload {
+    /// Create a new payload containing the given data, tagged with the given version.
+    pub fn new(version: u32, data: &[u8]) -> io::Result<Self> {
+        let fd = memfd_create(b"reexec-payload\0", false).map_err(io::Error::from_raw_os_error)?;
+        let file = unsafe { File::from_raw_fd(fd) };
+
+        write_all(fd, &encode_header(version, data))?;
+        write_all(fd, data)?;
+        if unsafe { libc::fcntl(fd, libc::F_ADD_SEALS, SEALS) } < 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        Ok(Self { file })
+    }
+
+    /// Take the payload passed by the previous program, and return its contents.
+    ///
+    /// This returns `Ok(None)` if no payload was passed (for example, if the program wasn't
+    /// re-executed), and an error with kind [`io::ErrorKind::InvalidData`] if `version` doesn't
+    /// match the version the payload was created with, or if the payload is corrupt. Either way,
+    /// the payload is consumed; later calls return `Ok(None)`.
+    pub fn take(version: u32) -> io::Result<Option<Vec<u8>>> {
+        let file = match crate::inherited::take::<File>(PAYLOAD_FD_NAME) {
+            Some(file) => file,
+            None => return Ok(None),
+        };
+
+        // If the payload isn't sealed, something else may be modifying it while we read it
+        let seals = unsafe { libc::fcntl(file.as_raw_fd(), libc::F_GET_SEALS) };
+        if seals < 0 || seals & SEALS != SEALS {
+            return Err(invalid_data("payload is not sealed".into()));
+        }
+
+        let len = file.metadata()?.len();
+        let mut buf = vec![0; len as usize];
+        // The file offset is shared with the previous program, so don't rely on it
+        file.read_exact_at(&mut buf, 0)?;
+
+        let data = decode(&buf, version)?;
+        Ok(Some(data.into()))
+    }
+}
+
+impl AsRawFd for Payload {
+    #[inline]
+    fn as_raw_fd(&self) -> RawFd {
+        self.file.as_raw_fd()
+    }
+}
+
+fn page_size() -> usize {
+    unsafe { libc::sysconf(libc::_SC_PAGESIZE) as usize }
+}
+
+/// A named region of memory that survives re-executing the program.
+///
+/// The region is backed by a memfd and mapped with `MAP_SHARED`. Pass it to the new program with
+/// [`Reexec::shared_region()`](crate::Reexec::shared_region); the new program can then map the
+/// same memory again with [`Self::open()`], so the contents are available without being copied
+/// or serialized.
+///
+/// The first page of the memfd holds a header with the layout version chosen by the caller and
+/// the length of the region; the data starts on the next page. The memfd is sealed against
+/// growing or shrinking, so the mapping always remains valid. Its contents are only shared with
+/// the re-executed program, not with other processes.
+#[derive(Debug)]
+pub struct SharedRegion {
+    file: File,
+    name: String,
+    version: u32,
+    map: *mut u8,
+    map_len: usize,
+    data_offset: usize,
+    len: usize,
+}
+
+// The mapping is owned by the SharedRegion, and only mutated through &mut self
+unsafe impl Send for SharedRegion {}
+unsafe impl Sync for SharedRegion {}
+
+impl SharedRegion {
+    /// Create a new zero-filled region of `len` bytes with the given name and layout version.
+    ///
+    /// The name is used to find the region again in the new program; it has the same
+    /// restrictions as names passed to [`Reexec::pass_fd()`](crate::Reexec::pass_fd).
+    pub fn create(name: &str, version: u32, len: usize) -> io::Result<Self> {
+        let data_offset = page_size();
+        let file_len = len
+            .checked_add(data_offset)
+            .ok_or_else(|| io::Error::from_raw_os_error(libc::EOVERFLOW))?;
+
+        let fd = memfd_create(b"reexec-region\0", false).map_err(io::Error::from_raw_os_error)?;
+        let file = unsafe { File::from_raw_fd(fd) };
+        file.set_len(file_len as u64)?;
+        if unsafe { libc::fcntl(fd, libc::F_ADD_SEALS, REGION_SEALS | libc::F_SEAL_SEAL) } < 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        let region = Self::map(file, name, version, file_len, data_offset, len)?;
+        let header = unsafe { std::slice::from_raw_parts_mut(region.map, data_offset) };
+        header[..8].copy_from_slice(REGION_MAGIC);
+        header[8..12].copy_from_slice(&REGION_FORMAT.to_le_bytes());
+        header[12..16].copy_from_slice(&version.to_le_bytes());
+        header[16..24].copy_from_slice(&(data_offset as u64).to_le_bytes());
+        header[24..32].copy_from_slice(&(len as u64).to_le_bytes());
+        Ok(region)
+    }
+
+    /// Map the region with the given name that was passed by the previous program.
+    ///
+    /// This returns `Ok(None)` if no region with that name was passed (for example, if the
+    /// program wasn't re-executed), and an error with kind [`io::ErrorKind::InvalidData`] if
+    /// `version` doesn't match the layout version the region was created with. Each region can
+    /// only be opened once.
+    pub fn open(name: &str, version: u32) -> io::Result<Option<Self>> {
+        match crate::inherited::take::<File>(&format!("{}{}", REGION_FD_PREFIX, name)) {
+            Some(file) => Self::from_file(file, name, version).map(Some),
+            None => Ok(None),
+        }
+    }
+
+    fn from_file(file: File, name: &str, version: u32) -> io::Result<Self> {
+        // If the region could shrink, accessing the mapping could raise SIGBUS
+        let seals = unsafe { libc::fcntl(file.as_raw_fd(), libc::F_GET_SEALS) };
+        if seals < 0 || seals & REGION_SEALS != REGION_SEALS {
+            return Err(invalid_data("shared region is not sealed".into()));
+        }
+
+        let file_len = file.metadata()?.len();
+
+        let mut header = [0; REGION_HEADER_LEN];
+        if file_len < header.len() as u64 {
+            return Err(invalid_data("not a reexec shared region".into()));
+        }
+        file.read_exact_at(&mut header, 0)?;
+        if &header[..8] != REGION_MAGIC {
+            return Err(invalid_data("not a reexec shared region".into()));
+        }
+
+        let format = read_u32(&header, 8);
+        if format != REGION_FORMAT {
+            return Err(invalid_data(format!(
+                "unsupported shared region format {} (expected {})",
+                format, REGION_FORMAT
+            )));
+        }
+
+        let found = read_u32(&header, 12);
+        if found != version {
+            return Err(invalid_data(format!(
+                "shared region layout version mismatch (expected {}, found {})",
+                version, found
+            )));
+        }
+
+        let data_offset = read_u64(&header, 16);
+        let len = read_u64(&header, 24);
+        if data_offset < REGION_HEADER_LEN as u64 || data_offset & (page_size() as u64 - 1) != 0 {
+            return Err(invalid_data(format!(
+                "invalid shared region data offset {}",
+                data_offset
+            )));
+        }
+        if data_offset.checked_add(len) != Some(file_len) || file_len > isize::MAX as u64 {
+            return Err(invalid_data(format!(
+                "shared region length mismatch (expected {}, found {})",
+                len,
+                file_len.saturating_sub(data_offset)
+            )));
+        }
+
+        Self::map(
+            file,
+            name,
+            version,
+            file_len as usize,
+            data_offset as usize,
+            len as usize,
+        )
+    }
+
+    fn map(
+        file: File,
+        name: &str,
+        version: u32,
+        map_len: usize,
+        data_offset: usize,
+        len: usize,
+    ) -> io::Result<Self> {
+        let map = unsafe {
+            libc::mmap(
+                std::ptr::null_mut(),
+                map_len,
+                libc::PROT_READ | libc::PROT_WRITE,
+                libc::MAP_SHARED,
+                file.as_raw_fd(),
+                0,
+            )
+        };
+        if map == libc::MAP_FAILED {
+            return Err(io::Error::last_os_error());
+        }
+
+        Ok(Self {
+            file,
+            name: name.into(),
+            version,
+            map: map as *mut u8,
+            map_len,
+            data_offset,
+            len,
+        })
+    }
+
+    /// Get the name of this region.
+    #[inline]
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Get the layout version of this region.
+    #[inline]
+    pub fn version(&self) -> u32 {
+        self.version
+    }
+
+    /// Get the length of the region (not including the header).
+    #[inline]
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    /// Check whether the region is empty.
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Get a pointer to the start of the region. It's aligned to the page size.
+    #[inline]
+    pub fn as_ptr(&self) -> *const u8 {
+        unsafe { self.map.add(self.data_offset) }
+    }
+
+    /// Get a mutable pointer to the start of the region. It's aligned to the page size.
+    #[inline]
+    pub fn as_mut_ptr(&mut self) -> *mut u8 {
+        unsafe { self.map.add(self.data_offset) }
+    }
+
+    /// Get the contents of the region.
+    #[inline]
+    pub fn as_slice(&self) -> &[u8] {
+        unsafe { std::slice::from_raw_parts(self.as_ptr(), self.len) }
+    }
+
+    /// Get the contents of the region mutably.
+    #[inline]
+    pub fn as_mut_slice(&mut self) -> &mut [u8] {
+        unsafe { std::slice::from_raw_parts_mut(self.as_mut_ptr(), self.len) }
+    }
+
+    pub(crate) fn fd_name(&self) -> String {
+        format!("{}{}", REGION_FD_PREFIX, self.name)
+    }
+}
+
+impl AsRawFd for SharedRegion {
+    #[inline]
+    fn as_raw_fd(&self) -> RawFd {
+        self.file.as_raw_fd()
+    }
+}
+
+impl Drop for SharedRegion {
+    fn drop(&mut self) {
+        unsafe {
+            libc::munmap(self.map as *mut _, self.map_len);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::Reexec;
+
+    #[test]
+    fn test_decode() {
+        let data = b"state";
+        let mut buf = encode_header(3, data).to_vec();
+        buf.extend_from_slice(data);
+
+        assert_eq!(decode(&buf, 3).unwrap(), data);
+
+        let err = decode(&buf, 4).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        assert!(err.to_string().contains("version mismatch"));
+
+        let mut corrupt = buf.clone();
+        *corrupt.last_mut().unwrap() ^= 1;
+        assert!(decode(&corrupt, 3)
+            .unwrap_err()
+            .to_string()
+            .contains("checksum"));
+
+        assert!(decode(&buf[..buf.len() - 1], 3)
+            .unwrap_err()
+            .to_string()
+            .contains("length"));
+
+        assert!(decode(b"garbage", 3)
+            .unwrap_err()
+            .to_string()
+            .contains("not a reexec payload"));
+    }
+
+    #[test]
+    fn test_sealed() {
+        let payload = Payload::new(1, b"abc").unwrap();
+        let seals = unsafe { libc::fcntl(payload.as_raw_fd(), libc::F_GET_SEALS) };
+        assert_eq!(seals & SEALS, SEALS);
+        assert_eq!(
+            payload.file.metadata().unwrap().len(),
+            PAYLOAD_HEADER_LEN as u64 + 3
+        );
+    }
+
+    #[test]
+    fn test_shared_region() {
+        let mut region = SharedRegion::create("cache", 2, 10).unwrap();
+        assert_eq!(region.name(), "cache");
+        assert_eq!(region.version(), 2);
+        assert_eq!(region.len(), 10);
+        assert_eq!(region.as_slice(), &[0; 10]);
+        assert_eq!(region.as_ptr() as usize % page_size(), 0);
+        region.as_mut_slice().copy_from_slice(b"0123456789");
+
+        let file = region.file.try_clone().unwrap();
+        let err = SharedRegion::from_file(file, "cache", 3).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        assert!(err.to_string().contains("version mismatch"));
+
+        // A second mapping sees the same memory
+        let file = region.file.try_clone().unwrap();
+        let mut other = SharedRegion::from_file(file, "cache", 2).unwrap();
+        assert_eq!(other.as_slice(), b"0123456789");
+        other.as_mut_slice()[0] = b'x';
+        assert_eq!(region.as_slice(), b"x123456789");
+
+        // The size is sealed
+        assert!(region.file.set_len(0).is_err());
+    }
+
+    /// Create a memfd containing a shared region header with the given data offset and length
+    fn fake_region(data_offset: u64, len: u64, seals: libc::c_int) -> File {
+        let fd = memfd_create(b"reexec-region\0", false).unwrap();
+        let file = unsafe { File::from_raw_fd(fd) };
+
+        let mut header = [0; REGION_HEADER_LEN];
+        header[..8].copy_from_slice(REGION_MAGIC);
+        header[8..12].copy_from_slice(&REGION_FORMAT.to_le_bytes());
+        header[12..16].copy_from_slice(&1u32.to_le_bytes());
+        header[16..24].copy_from_slice(&data_offset.to_le_bytes());
+        header[24..32].copy_from_slice(&len.to_le_bytes());
+        file.set_len(data_offset + len).unwrap();
+        file.write_all_at(&header, 0).unwrap();
+
+        if seals != 0 {
+            assert_eq!(unsafe { libc::fcntl(fd, libc::F_ADD_SEALS, seals) }, 0);
+        }
+        file
+    }
+
+    #[test]
+    fn test_shared_region_invalid() {
+        let page = page_size() as u64;
+
+        let file = fake_region(page, 10, REGION_SEALS);
+        assert_eq!(SharedRegion::from_file(file, "x", 1).unwrap().len(), 10);
+
+        // Not sealed against shrinking
+        for &seals in [0, libc::F_SEAL_GROW].iter() {
+            let err = SharedRegion::from_file(fake_region(page, 10, seals), "x", 1).unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+            assert!(err.to_string().contains("not sealed"));
+        }
+
+        // The data must start on a page boundary, after the header
+        for &data_offset in [0, 16, page + 1].iter() {
+            let file = fake_region(data_offset, page, REGION_SEALS);
+            let err = SharedRegion::from_file(file, "x", 1).unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+            assert!(err.to_string().contains("data offset"));
+        }
+    }
+
+    #[test]
+    fn test_payload_child() {
+        let stage = match std::env::var("REEXEC_TEST_PAYLOAD") {
+            Ok(stage) => stage,
+            Err(_) => return,
+        };
+
+        if stage == "exec" {
+            let payload = Payload::new(7, b"session table").unwrap();
+            let mut region = SharedRegion::create("cache", 1, 1 << 20).unwrap();
+            region.as_mut_slice()[12345] = 42;
+
+            let err = Reexec::new()
+                .env("REEXEC_TEST_PAYLOAD", "check")
+                .payload(&payload)
+                .shared_region(&region)
+                .exec();
+            panic!("{}", err);
+        } else {
+            assert_eq!(Payload::take(7).unwrap().unwrap(), b"session table");
+            assert!(Payload::take(7).unwrap().is_none());
+
+            assert!(SharedRegion::open("other", 1).unwrap().is_none());
+            let region = SharedRegion::open("cache", 1).unwrap().unwrap();
+            assert_eq!(region.len(), 1 << 20);
+            assert_eq!(region.as_slice()[12345], 42);
+        }
+    }
+
+    #[test]
+    fn test_payload() {
+        let status = std::process::Command::new(std::env::current_exe().unwrap())
+            .arg("--exact")
+            .arg("handoff::tests::test_payload_child")
+            .env("REEXEC_TEST_PAYLOAD", "exec")
+            .stdout(std::process::Stdio::null())
+            .status()
+            .unwrap();
+        assert!(status.success());
+    }
+}
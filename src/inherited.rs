@@ -0,0 +1,517 @@
+//! Reclaim file descriptors passed with [`Reexec::pass_fd()`](crate::Reexec::pass_fd).
+//!
+//! File descriptors are passed using the same protocol as systemd socket activation (the
+//! `LISTEN_FDS`, `LISTEN_FDNAMES`, and `LISTEN_PID` environment variables), so this also works
+//! for sockets passed by systemd (or any other service manager that implements the protocol).
+//!
+//! The first time any function in this module is called, the environment variables are parsed,
+//! and `FD_CLOEXEC` is set on all of the file descriptors they describe (so they won't leak into
+//! child processes). Each file descriptor can then be [taken](take) exactly once. The variables
+//! are left in the environment (modifying the environment isn't thread-safe), but from then on
+//! they aren't passed to the program when it's re-executed with [`Reexec`](crate::Reexec), unless
+//! they're set explicitly.
+//!
+//! Child processes recorded with [`Reexec::child()`](crate::Reexec::child) can be adopted with
+//! [`children()`].
+
+use std::io;
+use std::os::unix::prelude::*;
+use std::process::ExitStatus;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
+
+use crate::imp::fds::LISTEN_FDS_START;
+
+/// The environment variable that child processes are passed in, as `<pid>:<child>,<child>,...`
+/// (where `<pid>` serves the same purpose as `LISTEN_PID`).
+pub(crate) const CHILDREN_VAR: &str = "REEXEC_CHILDREN";
+
+/// The prefix for the names that child pidfds are passed under.
+#[cfg(any(target_os = "linux", target_os = "android"))]
+pub(crate) const CHILD_FD_PREFIX: &str = "reexec-child.";
+
+/// The environment variables that file descriptors are passed in.
+const LISTEN_VARS: [&str; 3] = ["LISTEN_PID", "LISTEN_FDS", "LISTEN_FDNAMES"];
+
+/// Whether `children()` has been called (after which `REEXEC_CHILDREN` isn't passed on).
+static CHILDREN_TAKEN: AtomicBool = AtomicBool::new(false);
+
+/// An inherited file descriptor and its name. The file descriptor is set to `None` once it's been
+/// taken (or if it wasn't actually open).
+type Entry = (String, Option<RawFd>);
+
+/// The inherited file descriptors, or `None` if the environment hasn't been parsed yet.
+static INHERITED: Mutex<Option<Vec<Entry>>> = Mutex::new(None);
+
+/// Parse the `LISTEN_*` environment variables.
+fn parse_env() -> Vec<Entry> {
+    let pid = std::env::var("LISTEN_PID").ok();
+    let nfds = std::env::var("LISTEN_FDS").ok();
+    let names = std::env::var("LISTEN_FDNAMES").ok();
+
+    // LISTEN_PID ensures that the variables weren't meant for a parent process that didn't
+    // remove them before spawning this one
+    if pid.and_then(|pid| pid.parse::<u32>().ok()) != Some(std::process::id()) {
+        return Vec::new();
+    }
+
+    let nfds = match nfds.and_then(|nfds| parse_nfds(&nfds)) {
+        Some(nfds) => nfds,
+        None => return Vec::new(),
+    };
+
+    let mut names = names.as_deref().unwrap_or("").split(':');
+
+    (LISTEN_FDS_START..LISTEN_FDS_START + nfds)
+        .map(|fd| {
+            // systemd uses "unknown" for file descriptors that weren't given names
+            let name = match names.next() {
+                Some(name) if !name.is_empty() => name,
+                _ => "unknown",
+            };
+
+            let fd = unsafe {
+                let flags = libc::fcntl(fd, libc::F_GETFD);
+                if flags >= 0 {
+                    libc::fcntl(fd, libc::F_SETFD, flags | libc::FD_CLOEXEC);
+                    Some(fd)
+                } else {
+                    None
+                }
+            };
+
+            (name.to_string(), fd)
+        })
+        .collect()
+}
+
+/// Parse `LISTEN_FDS`.
+///
+/// File descriptors at or above the `RLIMIT_NOFILE` limit can't have been passed, so (like
+/// `sd_listen_fds()`) those are ignored. This also guards against a bogus value making us check
+/// billions of file descriptors.
+fn parse_nfds(nfds: &str) -> Option<RawFd> {
+    let nfds = nfds.parse::<RawFd>().ok().filter(|&nfds| nfds > 0)?;
+
+    let mut rlim = std::mem::MaybeUninit::uninit();
+    let max_fd = if unsafe { libc::getrlimit(libc::RLIMIT_NOFILE, rlim.as_mut_ptr()) } == 0 {
+        let rlim = unsafe { rlim.assume_init() };
+        std::cmp::min(rlim.rlim_cur, RawFd::MAX as libc::rlim_t) as RawFd
+    } else {
+        RawFd::MAX
+    };
+
+    match nfds.min(max_fd.saturating_sub(LISTEN_FDS_START)) {
+        0 => None,
+        nfds => Some(nfds),
+    }
+}
+
+fn with_inherited<T, F: FnOnce(&mut Vec<Entry>) -> T>(f: F) -> T {
+    let mut inherited = INHERITED.lock().unwrap_or_else(|e| e.into_inner());
+    f(inherited.get_or_insert_with(parse_env))
+}
+
+/// Get the environment variables that have been consumed by this process, and so shouldn't be
+/// passed to the next program (unless they're set explicitly).
+pub(crate) fn consumed_vars() -> Vec<&'static str> {
+    let mut vars = Vec::new();
+    if INHERITED
+        .lock()
+        .unwrap_or_else(|e| e.into_inner())
+        .is_some()
+    {
+        vars.extend_from_slice(&LISTEN_VARS);
+    }
+    if CHILDREN_TAKEN.load(Ordering::SeqCst) {
+        vars.push(CHILDREN_VAR);
+    }
+    vars
+}
+
+/// Take ownership of the inherited file descriptor with the given name.
+///
+/// For example, `take::<TcpListener>("http")` reclaims a listener passed with
+/// `Reexec::new().pass_fd("http", &listener)`. If several file descriptors were passed with the
+/// same name, they are returned in order by successive calls.
+///
+/// This returns `None` if no (remaining) file descriptor has the given name. The caller is
+/// responsible for choosing the right type; no checks are done to ensure that (for example) the
+/// file descriptor actually refers to a TCP socket.
+pub fn take<T: FromRawFd>(name: &str) -> Option<T> {
+    with_inherited(|inherited| {
+        inherited
+            .iter_mut()
+            .find(|(n, fd)| n == name && fd.is_some())
+            .and_then(|(_, fd)| fd.take())
+            .map(|fd| unsafe { T::from_raw_fd(fd) })
+    })
+}
+
+/// Get the names of the inherited file descriptors that haven't been taken yet, in order.
+pub fn names() -> Vec<String> {
+    with_inherited(|inherited| {
+        inherited
+            .iter()
+            .filter(|(_, fd)| fd.is_some())
+            .map(|(name, _)| name.clone())
+            .collect()
+    })
+}
+
+/// A child process started by a previous instance of the program, adopted with [`children()`].
+///
+/// This provides a subset of the methods on [`std::process::Child`]. On Linux, if a pidfd was
+/// passed for the child, it's used to signal and wait for the child, so these methods can never
+/// affect an unrelated process that happens to have been given the same process ID.
+#[derive(Debug)]
+pub struct InheritedChild {
+    pid: u32,
+    pidfd: Option<RawFd>,
+    status: Option<ExitStatus>,
+}
+
+impl InheritedChild {
+    /// Get the process ID of the child.
+    #[inline]
+    pub fn id(&self) -> u32 {
+        self.pid
+    }
+
+    /// Get the pidfd referring to the child, if one was passed.
+    #[inline]
+    pub fn pidfd(&self) -> Option<RawFd> {
+        self.pidfd
+    }
+
+    /// Send the given signal to the child.
+    ///
+    /// This fails with [`io::ErrorKind::InvalidInput`] if the child has already been waited for.
+    pub fn signal(&self, sig: i32) -> io::Result<()> {
+        if self.status.is_some() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "invalid argument: can't kill an exited process",
+            ));
+        }
+
+        #[cfg(any(target_os = "linux", target_os = "android"))]
+        if let Some(pidfd) = self.pidfd {
+            return crate::imp::pidfd::send_signal(pidfd, sig);
+        }
+
+        if unsafe { libc::kill(self.pid as libc::pid_t, sig) } < 0 {
+            Err(io::Error::last_os_error())
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Kill the child with `SIGKILL`.
+    #[inline]
+    pub fn kill(&self) -> io::Result<()> {
+        self.signal(libc::SIGKILL)
+    }
+
+    /// Wait for the child to exit, and return its exit status.
+    ///
+    /// As with [`std::process::Child::wait()`], once the child has exited, later calls return the
+    /// same status.
+    pub fn wait(&mut self) -> io::Result<ExitStatus> {
+        match self.wait_impl(false)? {
+            Some(status) => Ok(status),
+            None => unreachable!(),
+        }
+    }
+
+    /// Check whether the child has exited (and if so, return its exit status) without blocking.
+    pub fn try_wait(&mut self) -> io::Result<Option<ExitStatus>> {
+        self.wait_impl(true)
+    }
+
+    fn wait_impl(&mut self, nohang: bool) -> io::Result<Option<ExitStatus>> {
+        if let Some(status) = self.status {
+            return Ok(Some(status));
+        }
+
+        #[cfg(any(target_os = "linux", target_os = "android"))]
+        if let Some(pidfd) = self.pidfd {
+            match crate::imp::pidfd::wait(pidfd, nohang) {
+                Ok(status) => {
+                    self.status = status.map(ExitStatus::from_raw);
+                    return Ok(self.status);
+                }
+                // waitid(P_PIDFD) was added in Linux 5.4; fall back on waitpid()
+                Err(e) if e.raw_os_error() == Some(libc::EINVAL) => (),
+                Err(e) => return Err(e),
+            }
+        }
+
+        let mut status = 0;
+        loop {
+            let res = unsafe {
+                libc::waitpid(
+                    self.pid as libc::pid_t,
+                    &mut status,
+                    if nohang { libc::WNOHANG } else { 0 },
+                )
+            };
+            if res < 0 {
+                let err = io::Error::last_os_error();
+                if err.kind() != io::ErrorKind::Interrupted {
+                    return Err(err);
+                }
+            } else if res == 0 {
+                return Ok(None);
+            } else {
+                self.status = Some(ExitStatus::from_raw(status));
+                return Ok(self.status);
+            }
+        }
+    }
+}
+
+impl Drop for InheritedChild {
+    fn drop(&mut self) {
+        if let Some(pidfd) = self.pidfd {
+            unsafe {
+                libc::close(pidfd);
+            }
+        }
+    }
+}
+
+/// Parse the value of `REEXEC_CHILDREN`, returning the child process IDs if it was meant for this
+/// process.
+fn parse_children(val: &str, pid: u32) -> Vec<u32> {
+    let mut parts = val.splitn(2, ':');
+    if parts.next().and_then(|p| p.parse::<u32>().ok()) != Some(pid) {
+        return Vec::new();
+    }
+
+    parts
+        .next()
+        .unwrap_or("")
+        .split(',')
+        .filter_map(|child| child.parse().ok())
+        .collect()
+}
+
+/// Adopt the child processes recorded by the previous program with
+/// [`Reexec::child()`](crate::Reexec::child).
+///
+/// Only the first call returns the children; later calls return an empty list. The
+/// `REEXEC_CHILDREN` environment variable is left in the environment, but from then on it isn't
+/// passed to the program when it's re-executed.
+pub fn children() -> Vec<InheritedChild> {
+    if CHILDREN_TAKEN.swap(true, Ordering::SeqCst) {
+        return Vec::new();
+    }
+
+    let pids = match std::env::var(CHILDREN_VAR).ok() {
+        Some(val) => parse_children(&val, std::process::id()),
+        None => return Vec::new(),
+    };
+
+    pids.into_iter()
+        .map(|pid| {
+            #[cfg(any(target_os = "linux", target_os = "android"))]
+            let pidfd = take::<RawFd>(&format!("{}{}", CHILD_FD_PREFIX, pid));
+            #[cfg(not(any(target_os = "linux", target_os = "android")))]
+            let pidfd = None;
+
+            InheritedChild {
+                pid,
+                pidfd,
+                status: None,
+            }
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::ffi::OsStr;
+    use std::net::TcpListener;
+
+    use crate::Reexec;
+
+    #[test]
+    fn test_inherited_child() {
+        let stage = match std::env::var("REEXEC_TEST_INHERITED") {
+            Ok(stage) => stage,
+            Err(_) => return,
+        };
+
+        if stage == "exec" {
+            let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+            let port = listener.local_addr().unwrap().port();
+
+            let err = Reexec::new()
+                .env("REEXEC_TEST_INHERITED", "check")
+                .env("REEXEC_TEST_PORT", port.to_string())
+                .close_fds_above(2)
+                .pass_fd("http", &listener)
+                .pass_fd("stdin", &0)
+                .exec();
+            panic!("{}", err);
+        } else {
+            let port = std::env::var("REEXEC_TEST_PORT").unwrap();
+
+            assert_eq!(names(), vec!["http", "stdin"]);
+
+            // The variables are left alone, but not passed on
+            assert!(std::env::var_os("LISTEN_FDS").is_some());
+            let env = Reexec::new().build_exec_env(&[]);
+            assert!(LISTEN_VARS
+                .iter()
+                .all(|&key| !env.contains_key(OsStr::new(key))));
+            let env = Reexec::new().env("LISTEN_FDS", "1").build_exec_env(&[]);
+            assert_eq!(env.get(OsStr::new("LISTEN_FDS")).unwrap(), "1");
+
+            let listener = take::<TcpListener>("http").unwrap();
+            assert_eq!(listener.local_addr().unwrap().port().to_string(), port);
+            assert!(take::<TcpListener>("http").is_none());
+
+            let fd = listener.as_raw_fd();
+            assert_ne!(
+                unsafe { libc::fcntl(fd, libc::F_GETFD) } & libc::FD_CLOEXEC,
+                0
+            );
+
+            assert_eq!(names(), vec!["stdin"]);
+        }
+    }
+
+    #[test]
+    fn test_inherited() {
+        let status = std::process::Command::new(std::env::current_exe().unwrap())
+            .arg("--exact")
+            .arg("inherited::tests::test_inherited_child")
+            .env("REEXEC_TEST_INHERITED", "exec")
+            .stdout(std::process::Stdio::null())
+            .status()
+            .unwrap();
+        assert!(status.success());
+    }
+
+    #[test]
+    fn test_parse_nfds() {
+        assert_eq!(parse_nfds("2"), Some(2));
+        assert_eq!(parse_nfds("0"), None);
+        assert_eq!(parse_nfds("-1"), None);
+        assert_eq!(parse_nfds("abc"), None);
+
+        let mut rlim = std::mem::MaybeUninit::uninit();
+        assert_eq!(
+            unsafe { libc::getrlimit(libc::RLIMIT_NOFILE, rlim.as_mut_ptr()) },
+            0
+        );
+        let limit = std::cmp::min(
+            unsafe { rlim.assume_init() }.rlim_cur,
+            RawFd::MAX as libc::rlim_t,
+        ) as RawFd;
+        assert_eq!(
+            parse_nfds(&RawFd::MAX.to_string()),
+            Some(limit - LISTEN_FDS_START)
+        );
+    }
+
+    #[test]
+    fn test_parse_children() {
+        assert_eq!(parse_children("10:1,2,3", 10), vec![1, 2, 3]);
+        assert_eq!(parse_children("10:1", 10), vec![1]);
+        assert_eq!(parse_children("10:", 10), Vec::<u32>::new());
+        assert_eq!(parse_children("11:1,2,3", 10), Vec::<u32>::new());
+        assert_eq!(parse_children("1,2,3", 10), Vec::<u32>::new());
+    }
+
+    // The children are waited for after the program is re-executed
+    #[allow(clippy::zombie_processes)]
+    #[test]
+    fn test_children_child() {
+        let stage = match std::env::var("REEXEC_TEST_CHILDREN") {
+            Ok(stage) => stage,
+            Err(_) => return,
+        };
+
+        if stage == "exec" {
+            let sleep = std::process::Command::new("sleep")
+                .arg("30")
+                .spawn()
+                .unwrap();
+            let exit = std::process::Command::new("sh")
+                .args(vec!["-c", "exit 3"])
+                .spawn()
+                .unwrap();
+
+            let err = Reexec::new()
+                .env("REEXEC_TEST_CHILDREN", "check")
+                .env("REEXEC_TEST_SLEEP_PID", sleep.id().to_string())
+                .child(sleep.id())
+                .child(exit.id())
+                .exec();
+            panic!("{}", err);
+        } else {
+            let mut adopted = children();
+            assert_eq!(adopted.len(), 2);
+            assert!(children().is_empty());
+            assert!(std::env::var_os(CHILDREN_VAR).is_some());
+            let env = Reexec::new().build_exec_env(&[]);
+            assert!(!env.contains_key(OsStr::new(CHILDREN_VAR)));
+
+            let sleep_pid = std::env::var("REEXEC_TEST_SLEEP_PID").unwrap();
+            assert_eq!(adopted[0].id().to_string(), sleep_pid);
+            #[cfg(any(target_os = "linux", target_os = "android"))]
+            assert!(adopted[0].pidfd().is_some());
+
+            use std::os::unix::process::ExitStatusExt;
+
+            adopted[0].kill().unwrap();
+            let status = adopted[0].wait().unwrap();
+            assert_eq!(status.signal(), Some(libc::SIGKILL));
+            assert_eq!(adopted[0].try_wait().unwrap(), Some(status));
+            assert!(adopted[0].kill().is_err());
+
+            assert_eq!(adopted[1].wait().unwrap().code(), Some(3));
+        }
+    }
+
+    #[test]
+    fn test_children() {
+        let status = std::process::Command::new(std::env::current_exe().unwrap())
+            .arg("--exact")
+            .arg("inherited::tests::test_children_child")
+            .env("REEXEC_TEST_CHILDREN", "exec")
+            .stdout(std::process::Stdio::null())
+            .status()
+            .unwrap();
+        assert!(status.success());
+    }
+
+    #[test]
+    fn test_invalid_name() {
+        let err = Reexec::new().pass_fd("a:b", &0).exec();
+        assert_eq!(err.kind(), std::io::ErrorKind::InvalidInput);
+    }
+
+    #[test]
+    fn test_keep_fd_overwritten() {
+        let mut reexec = Reexec::new();
+        reexec
+            .args(vec!["--exact", "__reexec_no_such_test"])
+            .pass_fd("a", &0)
+            .pass_fd("b", &1);
+
+        let err = reexec.clone().keep_fd(4).exec();
+        assert_eq!(err.raw_os_error(), Some(libc::EINVAL));
+
+        // Only file descriptors in the range that's overwritten are rejected (this fails later,
+        // because of the NUL byte)
+        let err = reexec.clone().arg("\0").keep_fd(2).keep_fd(5).exec();
+        assert_eq!(err.kind(), std::io::ErrorKind::InvalidInput);
+        assert_eq!(err.raw_os_error(), None);
+    }
+}
@@ -0,0 +1,485 @@
+use std::ffi::{OsStr, OsString};
+use std::os::unix::prelude::*;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+/// A list of environment variables.
+type Env = Vec<(OsString, OsString)>;
+
+/// The launch context of the current process, or `None` if it hasn't been captured yet.
+static LAUNCH_CONTEXT: Mutex<Option<LaunchContext>> = Mutex::new(None);
+
+/// Environment variables used to pass file descriptors and child processes to a new program.
+/// These are only meaningful to the program they were passed to, so they're never replayed.
+const HANDOFF_VARS: &[&str] = &[
+    "LISTEN_PID",
+    "LISTEN_FDS",
+    "LISTEN_FDNAMES",
+    crate::inherited::CHILDREN_VAR,
+    crate::lineage::LINEAGE_VAR,
+];
+
+// With the "launch-context" feature, capture the launch context before main() runs. glibc passes
+// the original argc/argv/envp to functions in .init_array; elsewhere, they come from /proc (or the
+// standard library).
+#[cfg(all(feature = "launch-context", target_os = "linux", target_env = "gnu"))]
+#[used]
+#[link_section = ".init_array"]
+static INIT_LAUNCH_CONTEXT: extern "C" fn(
+    libc::c_int,
+    *const *const libc::c_char,
+    *const *const libc::c_char,
+) = init_launch_context;
+
+#[cfg(all(feature = "launch-context", target_os = "linux", target_env = "gnu"))]
+extern "C" fn init_launch_context(
+    argc: libc::c_int,
+    argv: *const *const libc::c_char,
+    envp: *const *const libc::c_char,
+) {
+    unsafe fn collect(ptrs: *const *const libc::c_char, max: usize) -> Vec<Vec<u8>> {
+        let mut items = Vec::new();
+        if !ptrs.is_null() {
+            while items.len() < max && !(*ptrs.add(items.len())).is_null() {
+                items.push(
+                    std::ffi::CStr::from_ptr(*ptrs.add(items.len()))
+                        .to_bytes()
+                        .to_vec(),
+                );
+            }
+        }
+        items
+    }
+
+    crate::imp::capture_startup_execfn();
+
+    let (args, env) = unsafe { (collect(argv, argc as usize), collect(envp, usize::MAX)) };
+    let mut launch = LAUNCH_CONTEXT.lock().unwrap_or_else(|e| e.into_inner());
+    launch.get_or_insert_with(|| {
+        LaunchContext::capture(
+            Some(args.into_iter().map(OsString::from_vec).collect()),
+            Some(env.iter().filter_map(|var| split_var(var)).collect()),
+            false,
+        )
+    });
+}
+
+#[cfg(all(
+    feature = "launch-context",
+    any(
+        all(target_os = "linux", not(target_env = "gnu")),
+        target_os = "android",
+        target_os = "freebsd",
+        target_os = "netbsd",
+        target_os = "openbsd",
+        target_os = "dragonfly",
+        target_os = "illumos",
+        target_os = "solaris",
+    ),
+))]
+#[used]
+#[link_section = ".init_array"]
+static INIT_LAUNCH_CONTEXT: extern "C" fn() = init_launch_context;
+
+#[cfg(all(feature = "launch-context", target_os = "macos"))]
+#[used]
+#[link_section = "__DATA,__mod_init_func"]
+static INIT_LAUNCH_CONTEXT: extern "C" fn() = init_launch_context;
+
+#[cfg(all(
+    feature = "launch-context",
+    not(all(target_os = "linux", target_env = "gnu")),
+))]
+extern "C" fn init_launch_context() {
+    crate::imp::capture_startup_execfn();
+
+    // On most of these platforms, the standard library only records the arguments right before
+    // main() runs, so std::env::args_os() is empty here. If they can't be read from /proc either,
+    // they're filled in by init() or launch_context() (see fill_args()).
+    let (args, env) = read_proc();
+    let mut launch = LAUNCH_CONTEXT.lock().unwrap_or_else(|e| e.into_inner());
+    launch.get_or_insert_with(|| LaunchContext::capture(args, env, false));
+}
+
+/// The context the current program was originally launched in.
+///
+/// This holds the original arguments, environment, working directory, umask, and resource
+/// limits, so the program can later be re-executed exactly as it was started (with
+/// [`Reexec::from_launch_context()`](crate::Reexec::from_launch_context)), even if it has since
+/// modified its environment or changed directories.
+///
+/// The context is captured once per process:
+///
+/// - With the `launch-context` feature, it's captured by a constructor before `main()` runs.
+/// - Otherwise, call [`init()`] as early as possible in `main()` (before changing any of the
+///   above).
+/// - If neither happens, it's captured the first time [`launch_context()`] is called. In that
+///   case, the arguments and environment are read from `/proc/self/cmdline` and
+///   `/proc/self/environ` where possible (which reflect the original values), but the working
+///   directory and umask are the current ones, and [`Self::is_late()`] returns `true`.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct LaunchContext {
+    pub(crate) args: Vec<OsString>,
+    pub(crate) env: Env,
+    pub(crate) cwd: Option<PathBuf>,
+    pub(crate) umask: u32,
+    /// `(resource, soft limit, hard limit)`
+    pub(crate) rlimits: Vec<(i32, u64, u64)>,
+    pub(crate) late: bool,
+}
+
+impl LaunchContext {
+    /// Get the original arguments, including `argv[0]`.
+    #[inline]
+    pub fn args(&self) -> &[OsString] {
+        &self.args
+    }
+
+    /// Get the original environment.
+    #[inline]
+    pub fn env(&self) -> &[(OsString, OsString)] {
+        &self.env
+    }
+
+    /// Get the original working directory, or `None` if it couldn't be determined.
+    #[inline]
+    pub fn cwd(&self) -> Option<&Path> {
+        self.cwd.as_deref()
+    }
+
+    /// Get the original umask.
+    #[inline]
+    pub fn umask(&self) -> u32 {
+        self.umask
+    }
+
+    /// Get the original soft and hard limits for the given resource (for example,
+    /// `libc::RLIMIT_NOFILE`), or `None` if they weren't recorded.
+    ///
+    /// Unlimited values (`libc::RLIM_INFINITY`) are represented by `u64::MAX`, whatever the size
+    /// and signedness of `libc::rlim_t` on the current platform.
+    pub fn rlimit(&self, resource: i32) -> Option<(u64, u64)> {
+        self.rlimits
+            .iter()
+            .find(|&&(res, _, _)| res == resource)
+            .map(|&(_, soft, hard)| (soft, hard))
+    }
+
+    /// Check whether this context was captured late (by [`launch_context()`], rather than by
+    /// [`init()`] or the constructor), in which case the working directory and umask may not be
+    /// the original ones.
+    #[inline]
+    pub fn is_late(&self) -> bool {
+        self.late
+    }
+
+    /// Capture the current working directory, umask, and resource limits, along with the given
+    /// arguments and environment (or the current ones if `None`).
+    fn capture(args: Option<Vec<OsString>>, env: Option<Env>, late: bool) -> Self {
+        Self {
+            args: args.unwrap_or_else(|| std::env::args_os().collect()),
+            env: env.unwrap_or_else(|| std::env::vars_os().collect()),
+            cwd: std::env::current_dir().ok(),
+            umask: get_umask(),
+            rlimits: get_rlimits(),
+            late,
+        }
+    }
+}
+
+/// Fill in the arguments of a context that was captured before the standard library recorded
+/// them. An empty list is never taken to be the real arguments.
+fn fill_args(ctx: &mut LaunchContext) {
+    if ctx.args.is_empty() {
+        ctx.args = std::env::args_os().collect();
+    }
+}
+
+/// Split a `KEY=VALUE` environment entry.
+fn split_var(var: &[u8]) -> Option<(OsString, OsString)> {
+    // A leading '=' is part of the key (like in std::env)
+    let pos = var.iter().skip(1).position(|&ch| ch == b'=')? + 1;
+    Some((
+        OsStr::from_bytes(&var[..pos]).into(),
+        OsStr::from_bytes(&var[pos + 1..]).into(),
+    ))
+}
+
+/// Split the contents of a NUL-separated file like `/proc/self/cmdline`.
+fn split_nul(data: &[u8]) -> impl Iterator<Item = &[u8]> {
+    data.strip_suffix(b"\0")
+        .unwrap_or(data)
+        .split(|&ch| ch == 0)
+        .filter(move |_| !data.is_empty())
+}
+
+/// Read the original arguments and environment from `/proc/self`, if possible.
+fn read_proc() -> (Option<Vec<OsString>>, Option<Env>) {
+    let args = std::fs::read("/proc/self/cmdline")
+        .ok()
+        .filter(|data| !data.is_empty())
+        .map(|data| {
+            split_nul(&data)
+                .map(|arg| OsStr::from_bytes(arg).into())
+                .collect()
+        });
+    let env = std::fs::read("/proc/self/environ")
+        .ok()
+        .map(|data| split_nul(&data).filter_map(split_var).collect());
+    (args, env)
+}
+
+fn get_umask() -> u32 {
+    // Reading the umask from /proc avoids briefly changing it, which would race with other threads
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    if let Some(mask) = std::fs::read_to_string("/proc/self/status")
+        .ok()
+        .and_then(|status| {
+            let line = status.lines().find(|line| line.starts_with("Umask:"))?;
+            u32::from_str_radix(line["Umask:".len()..].trim(), 8).ok()
+        })
+    {
+        return mask;
+    }
+
+    unsafe {
+        let mask = libc::umask(0o022);
+        libc::umask(mask);
+        mask as u32
+    }
+}
+
+/// Convert a resource limit to the representation stored in a [`LaunchContext`].
+#[allow(clippy::unnecessary_cast)]
+pub(crate) fn rlim_to_u64(val: libc::rlim_t) -> u64 {
+    if val == libc::RLIM_INFINITY {
+        u64::MAX
+    } else {
+        val as u64
+    }
+}
+
+/// Convert a resource limit stored in a [`LaunchContext`] back to an `rlim_t`. Values that can't
+/// be represented are treated as unlimited.
+#[allow(clippy::unnecessary_cast)]
+pub(crate) fn rlim_from_u64(val: u64) -> libc::rlim_t {
+    if val >= libc::RLIM_INFINITY as u64 {
+        libc::RLIM_INFINITY
+    } else {
+        val as libc::rlim_t
+    }
+}
+
+fn get_rlimits() -> Vec<(i32, u64, u64)> {
+    let resources = [
+        libc::RLIMIT_CPU,
+        libc::RLIMIT_FSIZE,
+        libc::RLIMIT_DATA,
+        libc::RLIMIT_STACK,
+        libc::RLIMIT_CORE,
+        libc::RLIMIT_NOFILE,
+        #[cfg(not(target_os = "openbsd"))]
+        libc::RLIMIT_AS,
+        #[cfg(not(any(target_os = "illumos", target_os = "solaris")))]
+        libc::RLIMIT_NPROC,
+        #[cfg(not(any(target_os = "illumos", target_os = "solaris")))]
+        libc::RLIMIT_MEMLOCK,
+    ];
+
+    resources
+        .iter()
+        .filter_map(|&resource| {
+            let mut rlim = libc::rlimit {
+                rlim_cur: 0,
+                rlim_max: 0,
+            };
+            #[allow(clippy::unnecessary_cast)]
+            if unsafe { libc::getrlimit(resource, &mut rlim) } == 0 {
+                Some((
+                    resource as i32,
+                    rlim_to_u64(rlim.rlim_cur),
+                    rlim_to_u64(rlim.rlim_max),
+                ))
+            } else {
+                None
+            }
+        })
+        .collect()
+}
+
+/// Check whether the given environment variable is one of the ones used to pass file descriptors
+/// and child processes to a new program.
+pub(crate) fn is_handoff_var(key: &OsStr) -> bool {
+    HANDOFF_VARS.iter().any(|&var| key == var)
+}
+
+/// Capture the current program's [`LaunchContext`], if it hasn't been captured already.
+///
+/// This should be called as early as possible in `main()`, before the program modifies its
+/// environment, changes directories, or changes its umask. It isn't necessary with the
+/// `launch-context` feature, which captures the context before `main()` runs.
+///
+/// This also records the working directory used to resolve a relative `AT_EXECFN` (see
+/// [`Strategy::AuxExecFn`](crate::Strategy::AuxExecFn)).
+pub fn init() {
+    crate::imp::capture_startup_execfn();
+
+    let mut launch = LAUNCH_CONTEXT.lock().unwrap_or_else(|e| e.into_inner());
+    fill_args(launch.get_or_insert_with(|| LaunchContext::capture(None, None, false)));
+}
+
+/// Get the current program's [`LaunchContext`].
+///
+/// If it wasn't captured by [`init()`] (or the `launch-context` feature's constructor), it's
+/// captured now; see [`LaunchContext::is_late()`].
+pub fn launch_context() -> LaunchContext {
+    let mut launch = LAUNCH_CONTEXT.lock().unwrap_or_else(|e| e.into_inner());
+    let ctx = launch.get_or_insert_with(|| {
+        let (args, env) = read_proc();
+        LaunchContext::capture(args, env, true)
+    });
+    fill_args(ctx);
+    ctx.clone()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_split() {
+        assert_eq!(split_nul(b"").count(), 0);
+        assert_eq!(
+            split_nul(b"a\0\0b=c\0").collect::<Vec<_>>(),
+            vec![&b"a"[..], b"", b"b=c"]
+        );
+
+        assert_eq!(split_var(b"a"), None);
+        assert_eq!(split_var(b"a=b=c"), Some(("a".into(), "b=c".into())));
+        assert_eq!(split_var(b"=a=b"), Some(("=a".into(), "b".into())));
+        assert_eq!(split_var(b"a="), Some(("a".into(), "".into())));
+    }
+
+    #[test]
+    fn test_read_proc() {
+        if !Path::new("/proc/self/cmdline").exists() {
+            return;
+        }
+
+        let (args, env) = read_proc();
+        assert_eq!(args.unwrap(), std::env::args_os().collect::<Vec<_>>());
+        assert!(env.unwrap().iter().any(|(key, _)| key == "PATH"));
+    }
+
+    #[test]
+    fn test_launch_context() {
+        init();
+        let ctx = launch_context();
+        assert!(!ctx.is_late());
+        assert_eq!(ctx.args(), std::env::args_os().collect::<Vec<_>>());
+        assert_eq!(ctx.umask(), get_umask());
+
+        let mut rlim = libc::rlimit {
+            rlim_cur: 0,
+            rlim_max: 0,
+        };
+        assert_eq!(
+            unsafe { libc::getrlimit(libc::RLIMIT_NOFILE, &mut rlim) },
+            0
+        );
+        #[allow(clippy::unnecessary_cast)]
+        let resource = libc::RLIMIT_NOFILE as i32;
+        assert_eq!(
+            ctx.rlimit(resource),
+            Some((rlim_to_u64(rlim.rlim_cur), rlim_to_u64(rlim.rlim_max)))
+        );
+    }
+
+    // The constructor runs before the standard library records the arguments on these platforms
+    #[cfg(all(
+        feature = "launch-context",
+        not(all(target_os = "linux", target_env = "gnu")),
+    ))]
+    #[test]
+    fn test_constructor_args() {
+        let ctx = launch_context();
+        assert!(!ctx.is_late());
+        assert!(!ctx.args().is_empty());
+        assert_eq!(ctx.args(), std::env::args_os().collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn test_fill_args() {
+        let mut ctx = LaunchContext::capture(Some(Vec::new()), Some(Vec::new()), false);
+        fill_args(&mut ctx);
+        assert_eq!(ctx.args(), std::env::args_os().collect::<Vec<_>>());
+
+        // Missing arguments are never replayed
+        ctx.args.clear();
+        assert_eq!(
+            crate::Reexec::from_launch_context(&ctx).build_argv(),
+            crate::Reexec::new().build_argv()
+        );
+    }
+
+    #[test]
+    fn test_rlim_conversion() {
+        assert_eq!(rlim_to_u64(libc::RLIM_INFINITY), u64::MAX);
+        assert_eq!(rlim_from_u64(u64::MAX), libc::RLIM_INFINITY);
+        assert_eq!(rlim_to_u64(1024), 1024);
+        assert_eq!(rlim_from_u64(1024), 1024);
+    }
+
+    #[test]
+    fn test_from_launch_context_child() {
+        if std::env::var_os("REEXEC_TEST_LAUNCH").is_none() {
+            return;
+        }
+
+        init();
+        let generation = crate::lineage().generation();
+        if generation == 0 {
+            // Change everything the launch context restores, then re-execute
+            std::env::set_current_dir("/").unwrap();
+            unsafe {
+                libc::umask(0o077);
+            }
+            std::env::set_var("REEXEC_TEST_LAUNCH_CHANGED", "1");
+
+            let ctx = launch_context();
+            panic!("{}", crate::Reexec::from_launch_context(&ctx).exec());
+        }
+
+        assert_eq!(generation, 1);
+        assert_eq!(
+            std::env::current_dir().unwrap(),
+            std::env::var_os("REEXEC_TEST_LAUNCH_CWD").unwrap()
+        );
+        assert_eq!(get_umask(), 0o027);
+        assert_eq!(std::env::var_os("REEXEC_TEST_LAUNCH_CHANGED"), None);
+    }
+
+    #[test]
+    fn test_from_launch_context() {
+        use std::process::{Command, Stdio};
+
+        let dir = std::env::temp_dir().canonicalize().unwrap();
+        let mut cmd = Command::new(std::env::current_exe().unwrap());
+        cmd.args(vec![
+            "--exact",
+            "launch::tests::test_from_launch_context_child",
+        ])
+        .current_dir(&dir)
+        .env("REEXEC_TEST_LAUNCH", "1")
+        .env("REEXEC_TEST_LAUNCH_CWD", &dir)
+        .stdout(Stdio::null());
+        unsafe {
+            cmd.pre_exec(|| {
+                libc::umask(0o027);
+                Ok(())
+            });
+        }
+
+        assert!(cmd.status().unwrap().success());
+    }
+}
@@ -1,11 +1,75 @@
 #![cfg_attr(docsrs, feature(doc_cfg))]
+// ReexecError is deliberately stored inline (rather than boxed) so that creating one never
+// allocates memory
+#![allow(clippy::result_large_err)]
 
 use std::borrow::Cow;
+#[cfg(unix)]
+use std::ffi::CStr;
 use std::path::Path;
 
+mod builder;
+#[cfg(unix)]
+mod command;
+mod diagnose;
+mod error;
+#[cfg_attr(docsrs, doc(cfg(any(target_os = "linux", target_os = "android"))))]
+#[cfg(any(target_os = "linux", target_os = "android"))]
+pub mod handoff;
 #[cfg_attr(unix, path = "unix/mod.rs")]
 #[cfg_attr(windows, path = "windows.rs")]
 mod imp;
+#[cfg_attr(docsrs, doc(cfg(unix)))]
+#[cfg(unix)]
+pub mod inherited;
+#[cfg(unix)]
+mod launch;
+#[cfg(unix)]
+mod lineage;
+#[cfg(unix)]
+mod prepared;
+#[cfg_attr(docsrs, doc(cfg(unix)))]
+#[cfg(unix)]
+pub mod record;
+#[cfg(unix)]
+mod restart;
+#[cfg(unix)]
+mod spawn;
+mod strategy;
+
+pub use builder::Reexec;
+#[cfg_attr(docsrs, doc(cfg(unix)))]
+#[cfg(unix)]
+pub use command::CommandExt;
+pub use diagnose::{diagnose, Diagnosis, Probe};
+pub use error::{Attempt, ReexecError};
+#[cfg_attr(docsrs, doc(cfg(unix)))]
+#[cfg(unix)]
+pub use imp::{exe_info, ExeInfo};
+#[cfg_attr(docsrs, doc(cfg(any(target_os = "linux", target_os = "android"))))]
+#[cfg(any(target_os = "linux", target_os = "android"))]
+pub use imp::{
+    is_exe_deleted, leaked_fds, reexecve_running_image, reexecve_sealed, snapshot_running_image,
+    snapshot_to_memfd, ExeHandle,
+};
+#[cfg_attr(docsrs, doc(cfg(unix)))]
+#[cfg(unix)]
+pub use launch::{init, launch_context, LaunchContext};
+#[cfg_attr(docsrs, doc(cfg(unix)))]
+#[cfg(unix)]
+pub use lineage::{lineage, Lineage, LoopAction, LoopDetected, LoopGuard};
+#[cfg_attr(docsrs, doc(cfg(unix)))]
+#[cfg(unix)]
+pub use prepared::PreparedReexec;
+#[cfg_attr(docsrs, doc(cfg(unix)))]
+#[cfg(unix)]
+pub use restart::{restart_on_signal, RestartOptions};
+#[cfg_attr(docsrs, doc(cfg(unix)))]
+#[cfg(unix)]
+pub use spawn::{spawn_self, SpawnOptions};
+#[cfg(unix)]
+pub use strategy::CustomStrategy;
+pub use strategy::{set_strategies, strategies, Strategy};
 
 #[cfg(any(target_os = "solaris", target_os = "illumos"))]
 use libc::___errno as errno_ptr;
@@ -24,9 +88,13 @@ extern "cdecl" {
 /// Re-execute the currently running program with the specified `argv` and `envp`.
 ///
 /// The error from `execve()` is returned. If it was impossible to get the path of this process's
-/// executable, `ENOENT` or `EACCES` may be returned instead.
+/// executable, `ENOENT` or `EACCES` may be returned instead. (See [`ReexecError::attempts()`] for
+/// the individual errors from each [`Strategy`] that was tried.)
+///
+/// The strategies returned by [`strategies()`] are tried in order.
 ///
-/// On Unix-like systems, this function is async-signal-safe.
+/// On Unix-like systems, this function is async-signal-safe (as long as any
+/// [custom strategies](Strategy::Custom) are).
 ///
 /// # Safety
 ///
@@ -38,10 +106,14 @@ extern "cdecl" {
 /// - Multithreaded programs (and async-signal-safe functions)
 /// - Thread-safety of accessing the environment (especially in Rust)
 ///
-/// If any of these are unfamiliar, you should not be using this function.
+/// If any of these are unfamiliar, you should not be using this function. [`Reexec`] provides a
+/// safe interface.
 #[inline]
-pub unsafe fn reexecve(argv: *const *const libc::c_char, envp: *const *const libc::c_char) -> i32 {
-    imp::reexecve(argv, envp)
+pub unsafe fn reexecve(
+    argv: *const *const libc::c_char,
+    envp: *const *const libc::c_char,
+) -> ReexecError {
+    imp::reexecve(strategies(), argv, envp)
 }
 
 /// Re-execute the currently running program with the specified `argv` and `envp`.
@@ -58,8 +130,8 @@ pub unsafe fn reexecve(argv: *const *const libc::c_char, envp: *const *const lib
 pub unsafe fn wreexecve(
     argv: *const *const libc::wchar_t,
     envp: *const *const libc::wchar_t,
-) -> i32 {
-    imp::wreexecve(argv, envp)
+) -> ReexecError {
+    imp::wreexecve(strategies(), argv, envp)
 }
 
 /// If possible, get a path that can be used to re-execute this program.
@@ -71,17 +143,46 @@ pub unsafe fn wreexecve(
 /// `/proc` that points to the executable. If you need the actual executable path, use
 /// [`get_exe_path()`].
 #[inline]
-pub fn get_reexec_path() -> Result<Cow<'static, Path>, i32> {
-    imp::get_reexec_path()
+pub fn get_reexec_path() -> Result<Cow<'static, Path>, ReexecError> {
+    imp::get_reexec_path(strategies())
 }
 
 /// Get the actual path to the current executable.
 ///
 /// This does the same thing as `std::env::current_exe()`, though on some platforms it may be more
 /// reliable.
+///
+/// On Linux, if the executable has been deleted (or replaced by another file), this fails with an
+/// error for which [`ReexecError::is_deleted()`] returns `true`, rather than returning a path that
+/// might refer to a different program.
+#[inline]
+pub fn get_exe_path() -> Result<Cow<'static, Path>, ReexecError> {
+    imp::get_exe_path(strategies())
+}
+
+/// Like [`get_reexec_path()`], but write the path into the given buffer instead of allocating.
+///
+/// On success, the returned `CStr` refers to the start of `buf`. If the path produced by a
+/// strategy doesn't fit in `buf`, that strategy fails with `ENAMETOOLONG` and the next one is
+/// tried. A buffer of `PATH_MAX + 1` bytes is always large enough.
+///
+/// This function is async-signal-safe, so it can be used from signal handlers, or in the child
+/// after `fork()`ing a multithreaded process.
+#[cfg_attr(docsrs, doc(cfg(unix)))]
+#[cfg(unix)]
 #[inline]
-pub fn get_exe_path() -> Result<Cow<'static, Path>, i32> {
-    imp::get_exe_path()
+pub fn get_reexec_path_into(buf: &mut [u8]) -> Result<&CStr, ReexecError> {
+    imp::get_reexec_path_into(strategies(), buf)
+}
+
+/// Like [`get_exe_path()`], but write the path into the given buffer instead of allocating.
+///
+/// See [`get_reexec_path_into()`] for details. This function is async-signal-safe.
+#[cfg_attr(docsrs, doc(cfg(unix)))]
+#[cfg(unix)]
+#[inline]
+pub fn get_exe_path_into(buf: &mut [u8]) -> Result<&CStr, ReexecError> {
+    imp::get_exe_path_into(strategies(), buf)
 }
 
 #[cfg(test)]
@@ -97,6 +198,76 @@ mod tests {
     #[cfg(windows)]
     use std::os::windows::prelude::*;
 
+    /// Fork, and call `f` in the child with `argv`/`envp` arrays that re-run the test binary in a
+    /// way that exits successfully without running any tests. `f` should re-execute the program;
+    /// the parent checks that the child exits successfully.
+    #[cfg(unix)]
+    pub(crate) fn check_reexec_child<F>(f: F)
+    where
+        F: FnOnce(*const *const libc::c_char, *const *const libc::c_char),
+    {
+        use std::ffi::CString;
+
+        let argv = [
+            CString::new(std::env::args_os().next().unwrap().into_vec()).unwrap(),
+            CString::new("--exact").unwrap(),
+            CString::new("__reexec_no_such_test").unwrap(),
+        ];
+        let envp = std::env::vars_os()
+            .map(|(key, val)| {
+                let mut buf = key.into_vec();
+                buf.push(b'=');
+                buf.extend_from_slice(val.as_bytes());
+                CString::new(buf).unwrap()
+            })
+            .collect::<Vec<_>>();
+
+        let argv_ptrs = argv
+            .iter()
+            .map(|arg| arg.as_ptr())
+            .chain(std::iter::once(std::ptr::null()))
+            .collect::<Vec<_>>();
+        let envp_ptrs = envp
+            .iter()
+            .map(|var| var.as_ptr())
+            .chain(std::iter::once(std::ptr::null()))
+            .collect::<Vec<_>>();
+
+        check_child(|| unsafe {
+            // Only async-signal-safe operations from here on
+            f(argv_ptrs.as_ptr(), envp_ptrs.as_ptr());
+            libc::_exit(127);
+        });
+    }
+
+    /// Fork, and call `f` in the child with stdout and stderr redirected to `/dev/null`. The
+    /// parent checks that `f` returns without panicking.
+    #[cfg(unix)]
+    pub(crate) fn check_child<F: FnOnce()>(f: F) {
+        unsafe {
+            let devnull = libc::open(
+                b"/dev/null\0".as_ptr() as *const _,
+                libc::O_WRONLY | libc::O_CLOEXEC,
+            );
+            assert!(devnull >= 0);
+
+            let pid = libc::fork();
+            assert!(pid >= 0);
+            if pid == 0 {
+                libc::dup2(devnull, 1);
+                libc::dup2(devnull, 2);
+                let res = std::panic::catch_unwind(std::panic::AssertUnwindSafe(f));
+                libc::_exit(if res.is_ok() { 0 } else { 1 });
+            }
+            libc::close(devnull);
+
+            let mut status = 0;
+            assert_eq!(libc::waitpid(pid, &mut status, 0), pid);
+            assert!(libc::WIFEXITED(status));
+            assert_eq!(libc::WEXITSTATUS(status), 0);
+        }
+    }
+
     #[cfg(unix)]
     pub(crate) fn check_path_bytes(path: &[u8]) {
         check_path(OsStr::from_bytes(path));
@@ -154,4 +325,28 @@ mod tests {
     fn test_get_exe_path() {
         check_path(get_exe_path().unwrap().as_ref().as_ref());
     }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_get_path_into() {
+        let mut buf = [0xff; libc::PATH_MAX as usize + 1];
+
+        let path = get_reexec_path_into(&mut buf).unwrap();
+        assert_eq!(
+            path.to_bytes(),
+            get_reexec_path().unwrap().as_os_str().as_bytes()
+        );
+
+        let path = get_exe_path_into(&mut buf).unwrap();
+        assert_eq!(
+            path.to_bytes(),
+            get_exe_path().unwrap().as_os_str().as_bytes()
+        );
+
+        let err = get_exe_path_into(&mut buf[..1]).unwrap_err();
+        assert!(err
+            .attempts()
+            .iter()
+            .all(|attempt| attempt.raw_os_error() == libc::ENAMETOOLONG));
+    }
 }
@@ -0,0 +1,658 @@
+use std::ffi::OsString;
+use std::fmt;
+use std::io;
+use std::sync::atomic::{AtomicU8, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// The environment variable that the lineage is passed to the new program in.
+pub(crate) const LINEAGE_VAR: &str = "REEXEC_LINEAGE";
+
+const LINEAGE_FORMAT: &str = "2";
+
+/// The maximum number of re-exec times that are passed on to the next generation.
+const MAX_RECENT: usize = 64;
+
+/// The width of the (zero-padded) PID field, so it can be filled in at exec time.
+const PID_WIDTH: usize = 10;
+
+/// The width of the time of the re-exec that is being performed (`<secs>.<nanos>`, zero-padded),
+/// so it can be filled in at exec time.
+const NOW_WIDTH: usize = 30;
+
+/// The lineage of the current process, or `None` if it hasn't been loaded yet.
+static LINEAGE: Mutex<Option<Lineage>> = Mutex::new(None);
+
+// With the "launch-context" feature, load the lineage before main() runs, so it isn't affected by
+// changes the program makes to its environment. Otherwise, this happens the first time it's needed.
+#[cfg(all(
+    feature = "launch-context",
+    any(
+        target_os = "linux",
+        target_os = "android",
+        target_os = "freebsd",
+        target_os = "netbsd",
+        target_os = "openbsd",
+        target_os = "dragonfly",
+        target_os = "illumos",
+        target_os = "solaris",
+    ),
+))]
+#[used]
+#[link_section = ".init_array"]
+static INIT_LINEAGE: extern "C" fn() = init_lineage;
+
+#[cfg(all(feature = "launch-context", target_os = "macos"))]
+#[used]
+#[link_section = "__DATA,__mod_init_func"]
+static INIT_LINEAGE: extern "C" fn() = init_lineage;
+
+#[cfg(feature = "launch-context")]
+extern "C" fn init_lineage() {
+    with_lineage(|_| ());
+}
+
+/// Information about how the current process came to be running.
+///
+/// The lineage is passed from each program to the next when it's re-executed with
+/// [`Reexec::exec()`](crate::Reexec::exec) (or a [`PreparedReexec`](crate::PreparedReexec)), in
+/// the `REEXEC_LINEAGE` environment variable. The variable is read (with the `launch-context`
+/// feature, before `main()` runs; otherwise, the first time the lineage is needed) but left in
+/// the environment; it's always replaced when the program is re-executed. Get the lineage with
+/// [`lineage()`].
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Lineage {
+    generation: u64,
+    first_start: SystemTime,
+    /// The times of the most recent re-execs, as read from `CLOCK_MONOTONIC` (which isn't reset
+    /// by `execve()`), oldest first.
+    recent: Vec<Duration>,
+    previous_build_id: Option<Vec<u8>>,
+    reason: Option<String>,
+}
+
+impl Lineage {
+    /// Get the number of times the program has been re-executed.
+    ///
+    /// This is 0 if the program wasn't re-executed.
+    #[inline]
+    pub fn generation(&self) -> u64 {
+        self.generation
+    }
+
+    /// Get the (approximate) time the first generation of the program was started.
+    #[inline]
+    pub fn first_start(&self) -> SystemTime {
+        self.first_start
+    }
+
+    /// Get the GNU build ID of the previous generation of the program, if it had one.
+    ///
+    /// Comparing this with [`ExeInfo::build_id()`](crate::ExeInfo::build_id) shows whether the
+    /// program was upgraded.
+    #[inline]
+    pub fn previous_build_id(&self) -> Option<&[u8]> {
+        self.previous_build_id.as_deref()
+    }
+
+    /// Get the reason the program was re-executed, as passed to
+    /// [`Reexec::reason()`](crate::Reexec::reason).
+    #[inline]
+    pub fn reason(&self) -> Option<&str> {
+        self.reason.as_deref()
+    }
+
+    fn first() -> Self {
+        Self {
+            generation: 0,
+            first_start: SystemTime::now(),
+            recent: Vec::new(),
+            previous_build_id: None,
+            reason: None,
+        }
+    }
+
+    #[cfg(test)]
+    pub(crate) fn recent(&self) -> &[Duration] {
+        &self.recent
+    }
+
+    /// Count the re-execs in the last `window`.
+    fn count_recent(&self, now: Duration, window: Duration) -> usize {
+        self.recent
+            .iter()
+            .filter(|&&time| time >= now.saturating_sub(window))
+            .count()
+    }
+
+    /// Parse the value of `REEXEC_LINEAGE`. `None` is returned if it's malformed or wasn't meant
+    /// for this process.
+    pub(crate) fn parse(val: &str, pid: u32) -> Option<Self> {
+        let mut parts = val.splitn(7, ';');
+        if parts.next()? != LINEAGE_FORMAT {
+            return None;
+        }
+
+        if parts.next()?.parse::<u32>().ok()? != pid {
+            return None;
+        }
+
+        let generation = parts.next()?.parse().ok()?;
+        let first_start = UNIX_EPOCH.checked_add(parse_duration(parts.next()?)?)?;
+
+        let recent = match parts.next()? {
+            "" => Vec::new(),
+            recent => recent
+                .split(',')
+                .map(parse_duration)
+                .collect::<Option<_>>()?,
+        };
+
+        let previous_build_id = match parts.next()? {
+            "" => None,
+            hex => Some(decode_hex(hex)?),
+        };
+
+        let reason = match parts.next()? {
+            "" => None,
+            reason => Some(reason.to_string()),
+        };
+
+        Some(Self {
+            generation,
+            first_start,
+            recent,
+            previous_build_id,
+            reason,
+        })
+    }
+
+    /// Format the lineage that should be passed to the next generation, when it's re-executed
+    /// for the given reason.
+    fn format_next(
+        &self,
+        build_id: Option<&[u8]>,
+        reason: Option<&str>,
+        pid: u32,
+        now: Duration,
+    ) -> String {
+        let first_start = self
+            .first_start
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default();
+
+        let skip = (self.recent.len() + 1).saturating_sub(MAX_RECENT);
+        let recent = self
+            .recent
+            .iter()
+            .skip(skip)
+            .map(|&time| format_duration(time))
+            .chain(std::iter::once(format!(
+                "{:020}.{:09}",
+                now.as_secs(),
+                now.subsec_nanos()
+            )))
+            .collect::<Vec<_>>()
+            .join(",");
+
+        format!(
+            "{};{:010};{};{};{};{};{}",
+            LINEAGE_FORMAT,
+            pid,
+            self.generation + 1,
+            format_duration(first_start),
+            recent,
+            build_id.map(encode_hex).unwrap_or_default(),
+            reason.unwrap_or(""),
+        )
+    }
+}
+
+fn format_duration(dur: Duration) -> String {
+    format!("{}.{}", dur.as_secs(), dur.subsec_nanos())
+}
+
+fn parse_duration(s: &str) -> Option<Duration> {
+    let mut parts = s.splitn(2, '.');
+    let secs = parts.next()?.parse().ok()?;
+    let nanos = parts.next()?.parse().ok()?;
+    if nanos >= 1_000_000_000 {
+        return None;
+    }
+    Some(Duration::new(secs, nanos))
+}
+
+/// Write `n` into `buf` in decimal, zero-padded to fill it.
+///
+/// The bytes are written atomically, since the buffer may be shared with another thread that's
+/// doing the same thing. This function is async-signal-safe.
+fn write_padded(buf: &[AtomicU8], mut n: u64) {
+    for byte in buf.iter().rev() {
+        byte.store(b'0' + (n % 10) as u8, Ordering::Relaxed);
+        n /= 10;
+    }
+}
+
+/// The positions of the fields in a `REEXEC_LINEAGE` value (as returned by [`next_env()`]) that
+/// depend on when and from which process the re-exec is actually performed.
+///
+/// This allows a [`PreparedReexec`](crate::PreparedReexec) to fill them in at exec time, rather
+/// than when it's prepared.
+#[derive(Copy, Clone, Debug)]
+pub(crate) struct Slots {
+    pid: usize,
+    now: usize,
+}
+
+impl Slots {
+    /// Find the slots in the given value.
+    pub(crate) fn find(val: &[u8]) -> Option<Self> {
+        let pid = LINEAGE_FORMAT.len() + 1;
+
+        // The time of this re-exec is the last entry in the list of recent re-execs (the fifth
+        // field)
+        let end = val
+            .iter()
+            .enumerate()
+            .filter(|&(_, &ch)| ch == b';')
+            .map(|(i, _)| i)
+            .nth(4)?;
+        let now = end.checked_sub(NOW_WIDTH)?;
+
+        if now < pid + PID_WIDTH {
+            return None;
+        }
+        Some(Self { pid, now })
+    }
+
+    /// Fill in the current PID and time in the value (which must be the one that the slots were
+    /// found in).
+    ///
+    /// This function is async-signal-safe.
+    pub(crate) fn fill(&self, val: &[AtomicU8]) {
+        write_padded(
+            &val[self.pid..self.pid + PID_WIDTH],
+            unsafe { libc::getpid() } as u64,
+        );
+
+        let now = monotonic_now();
+        write_padded(&val[self.now..self.now + 20], now.as_secs());
+        write_padded(
+            &val[self.now + 21..self.now + NOW_WIDTH],
+            now.subsec_nanos() as u64,
+        );
+    }
+}
+
+/// Get the current time from `CLOCK_MONOTONIC`.
+fn monotonic_now() -> Duration {
+    let mut ts = libc::timespec {
+        tv_sec: 0,
+        tv_nsec: 0,
+    };
+    unsafe {
+        libc::clock_gettime(libc::CLOCK_MONOTONIC, &mut ts);
+    }
+    Duration::new(ts.tv_sec as u64, ts.tv_nsec as u32)
+}
+
+/// What to do when a [`LoopGuard`]'s limit is reached.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum LoopAction {
+    /// Don't re-execute the program, and return an error (see [`LoopDetected`]).
+    Fail,
+    /// Exit immediately with the given exit code (using [`std::process::exit()`]).
+    Exit(i32),
+    /// Don't re-execute the program, and keep running it.
+    ///
+    /// [`Reexec::exec()`](crate::Reexec::exec) still has to return an error; use
+    /// [`LoopDetected::from_error()`] to tell it apart from other errors. Unlike with
+    /// [`Self::Fail`], [`restart_on_signal()`](crate::restart_on_signal) silently ignores it.
+    Continue,
+}
+
+/// Protection against programs that re-execute themselves in a loop (for example, because of a bad
+/// deploy that triggers a re-exec on startup).
+///
+/// Set this with [`Reexec::loop_guard()`](crate::Reexec::loop_guard). The times of recent
+/// re-execs are passed from each generation to the next along with the rest of the
+/// [`Lineage`], so the limit applies across re-execs.
+#[derive(Clone, Debug)]
+pub struct LoopGuard {
+    max_reexecs: usize,
+    window: Duration,
+    backoff: Option<(Duration, Duration)>,
+    action: LoopAction,
+}
+
+impl LoopGuard {
+    /// Create a guard that allows at most `max_reexecs` re-execs within any period of length
+    /// `window`. By default, there is no backoff, and the action is [`LoopAction::Fail`].
+    pub fn new(max_reexecs: usize, window: Duration) -> Self {
+        Self {
+            max_reexecs,
+            window,
+            backoff: None,
+            action: LoopAction::Fail,
+        }
+    }
+
+    /// Wait before re-executing the program if it was re-executed recently.
+    ///
+    /// The delay starts at `initial` if the program was re-executed once within the window, and
+    /// doubles with each additional re-exec, up to `max`.
+    pub fn backoff(&mut self, initial: Duration, max: Duration) -> &mut Self {
+        self.backoff = Some((initial, max));
+        self
+    }
+
+    /// Set what to do when the limit is reached.
+    pub fn action(&mut self, action: LoopAction) -> &mut Self {
+        self.action = action;
+        self
+    }
+
+    /// Compute the delay before re-executing, given the number of recent re-execs.
+    fn delay(&self, recent: usize) -> Duration {
+        match self.backoff {
+            Some((initial, max)) if recent > 0 => {
+                let shift = (recent - 1).min(31) as u32;
+                initial.checked_mul(1 << shift).unwrap_or(max).min(max)
+            }
+            _ => Duration::from_secs(0),
+        }
+    }
+
+    /// Check whether the program can be re-executed now, sleeping for the backoff delay if so.
+    pub(crate) fn check(&self) -> io::Result<()> {
+        let now = monotonic_now();
+        let recent = with_lineage(|lineage| lineage.count_recent(now, self.window));
+
+        if recent >= self.max_reexecs {
+            let detected = LoopDetected {
+                reexecs: recent,
+                window: self.window,
+                action: self.action,
+            };
+            return match self.action {
+                LoopAction::Exit(code) => std::process::exit(code),
+                _ => Err(io::Error::other(detected)),
+            };
+        }
+
+        let delay = self.delay(recent);
+        if delay > Duration::from_secs(0) {
+            std::thread::sleep(delay);
+        }
+        Ok(())
+    }
+}
+
+/// The error returned by [`Reexec::exec()`](crate::Reexec::exec) when a [`LoopGuard`]'s limit is
+/// reached.
+#[derive(Clone, Debug)]
+pub struct LoopDetected {
+    reexecs: usize,
+    window: Duration,
+    action: LoopAction,
+}
+
+impl LoopDetected {
+    /// Get the `LoopDetected` error wrapped by the given `io::Error`, if there is one.
+    pub fn from_error(err: &io::Error) -> Option<&Self> {
+        err.get_ref().and_then(|err| err.downcast_ref())
+    }
+
+    /// Get the number of times the program was re-executed within the window.
+    #[inline]
+    pub fn reexecs(&self) -> usize {
+        self.reexecs
+    }
+
+    /// Get the action the guard was configured with ([`LoopAction::Fail`] or
+    /// [`LoopAction::Continue`]).
+    #[inline]
+    pub fn action(&self) -> LoopAction {
+        self.action
+    }
+}
+
+impl fmt::Display for LoopDetected {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "re-exec loop detected: re-executed {} times in the last {:?}",
+            self.reexecs, self.window
+        )
+    }
+}
+
+impl std::error::Error for LoopDetected {}
+
+fn encode_hex(data: &[u8]) -> String {
+    data.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+fn decode_hex(hex: &str) -> Option<Vec<u8>> {
+    if hex.len() & 1 != 0 || !hex.is_ascii() {
+        return None;
+    }
+
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&hex[i..i + 2], 16).ok())
+        .collect()
+}
+
+fn load() -> Lineage {
+    std::env::var(LINEAGE_VAR)
+        .ok()
+        .and_then(|val| Lineage::parse(&val, std::process::id()))
+        .unwrap_or_else(Lineage::first)
+}
+
+fn with_lineage<T, F: FnOnce(&Lineage) -> T>(f: F) -> T {
+    let mut lineage = LINEAGE.lock().unwrap_or_else(|e| e.into_inner());
+    f(lineage.get_or_insert_with(load))
+}
+
+/// Get the lineage of the current process.
+///
+/// See [`Lineage`].
+pub fn lineage() -> Lineage {
+    with_lineage(|lineage| lineage.clone())
+}
+
+/// Get the value of `REEXEC_LINEAGE` to pass to the next generation.
+pub(crate) fn next_env(reason: Option<&str>) -> OsString {
+    let build_id = crate::exe_info()
+        .ok()
+        .and_then(|info| info.build_id().map(|id| id.to_vec()));
+
+    with_lineage(|lineage| {
+        lineage.format_next(
+            build_id.as_deref(),
+            reason,
+            std::process::id(),
+            monotonic_now(),
+        )
+    })
+    .into()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::Reexec;
+
+    #[test]
+    fn test_parse_format() {
+        let first = Lineage::first();
+        assert_eq!(first.generation(), 0);
+        assert_eq!(first.previous_build_id(), None);
+        assert_eq!(first.reason(), None);
+
+        let next = first.format_next(Some(&[0xab, 0x01]), Some("a;b"), 10, Duration::new(5, 1));
+        let lineage = Lineage::parse(&next, 10).unwrap();
+        assert_eq!(lineage.generation(), 1);
+        assert_eq!(lineage.recent, vec![Duration::new(5, 1)]);
+        assert_eq!(lineage.first_start(), first.first_start());
+        assert_eq!(lineage.previous_build_id(), Some(&[0xab, 0x01][..]));
+        assert_eq!(lineage.reason(), Some("a;b"));
+
+        let next = lineage.format_next(None, None, 10, Duration::new(6, 0));
+        let lineage = Lineage::parse(&next, 10).unwrap();
+        assert_eq!(lineage.generation(), 2);
+        assert_eq!(
+            lineage.recent,
+            vec![Duration::new(5, 1), Duration::new(6, 0)]
+        );
+        assert_eq!(lineage.first_start(), first.first_start());
+        assert_eq!(lineage.previous_build_id(), None);
+        assert_eq!(lineage.reason(), None);
+
+        // Wrong PID
+        assert_eq!(Lineage::parse(&next, 11), None);
+
+        for &val in [
+            "",
+            "3;10;1;0.0;;;",
+            "2;10;x;0.0;;;",
+            "2;10;1;0;;;",
+            "2;10;1;0.0;;abc;",
+            "2;10;1;0.0;1;;",
+            "2;10;1;0.0;;",
+        ]
+        .iter()
+        {
+            assert_eq!(Lineage::parse(val, 10), None, "{:?}", val);
+        }
+    }
+
+    #[test]
+    fn test_slots() {
+        let before = monotonic_now();
+
+        let next = Lineage::first().format_next(None, Some("x;y"), 1, Duration::new(5, 1));
+        let slots = Slots::find(next.as_bytes()).unwrap();
+        let val = next.bytes().map(AtomicU8::new).collect::<Vec<_>>();
+        slots.fill(&val);
+
+        let next = String::from_utf8(val.into_iter().map(AtomicU8::into_inner).collect()).unwrap();
+        let lineage = Lineage::parse(&next, std::process::id()).unwrap();
+        assert_eq!(lineage.generation(), 1);
+        assert_eq!(lineage.reason(), Some("x;y"));
+        assert_eq!(lineage.recent.len(), 1);
+        assert!(lineage.recent[0] >= before);
+        assert!(lineage.recent[0] <= monotonic_now());
+
+        assert!(Slots::find(b"2;10;1;0.0;1.0;;").is_none());
+    }
+
+    #[test]
+    fn test_recent_limit() {
+        let mut lineage = Lineage::first();
+        for i in 0..(MAX_RECENT as u64 + 5) {
+            let next = lineage.format_next(None, None, 10, Duration::from_secs(i));
+            lineage = Lineage::parse(&next, 10).unwrap();
+        }
+        assert_eq!(lineage.recent.len(), MAX_RECENT);
+        assert_eq!(
+            lineage.recent.last(),
+            Some(&Duration::from_secs(MAX_RECENT as u64 + 4))
+        );
+
+        let now = Duration::from_secs(MAX_RECENT as u64 + 4);
+        assert_eq!(lineage.count_recent(now, Duration::from_secs(0)), 1);
+        assert_eq!(lineage.count_recent(now, Duration::from_secs(9)), 10);
+    }
+
+    #[test]
+    fn test_backoff() {
+        let mut guard = LoopGuard::new(10, Duration::from_secs(60));
+        assert_eq!(guard.delay(3), Duration::from_secs(0));
+
+        guard.backoff(Duration::from_millis(100), Duration::from_secs(1));
+        assert_eq!(guard.delay(0), Duration::from_secs(0));
+        assert_eq!(guard.delay(1), Duration::from_millis(100));
+        assert_eq!(guard.delay(2), Duration::from_millis(200));
+        assert_eq!(guard.delay(4), Duration::from_millis(800));
+        assert_eq!(guard.delay(5), Duration::from_secs(1));
+        assert_eq!(guard.delay(100), Duration::from_secs(1));
+    }
+
+    #[test]
+    fn test_loop_guard_child() {
+        let stage = match std::env::var("REEXEC_TEST_LOOP_GUARD") {
+            Ok(stage) => stage,
+            Err(_) => return,
+        };
+
+        let mut guard = LoopGuard::new(2, Duration::from_secs(600));
+        guard.action(match stage.as_str() {
+            "exit" => LoopAction::Exit(42),
+            _ => LoopAction::Continue,
+        });
+
+        let err = Reexec::new().loop_guard(guard).exec();
+        // The first two re-execs succeed
+        let detected = LoopDetected::from_error(&err).unwrap();
+        assert_eq!(lineage().generation(), 2);
+        assert_eq!(detected.reexecs(), 2);
+        assert_eq!(detected.action(), LoopAction::Continue);
+    }
+
+    #[test]
+    fn test_loop_guard() {
+        for &(stage, code) in [("continue", 0), ("exit", 42)].iter() {
+            let status = std::process::Command::new(std::env::current_exe().unwrap())
+                .arg("--exact")
+                .arg("lineage::tests::test_loop_guard_child")
+                .env("REEXEC_TEST_LOOP_GUARD", stage)
+                .stdout(std::process::Stdio::null())
+                .status()
+                .unwrap();
+            assert_eq!(status.code(), Some(code));
+        }
+    }
+
+    #[test]
+    fn test_lineage_child() {
+        let stage = match std::env::var("REEXEC_TEST_LINEAGE") {
+            Ok(stage) => stage,
+            Err(_) => return,
+        };
+
+        let lineage = lineage();
+        assert_eq!(std::env::var_os(LINEAGE_VAR).is_some(), stage == "check");
+        if stage == "exec" {
+            assert_eq!(lineage.generation(), 0);
+            let err = Reexec::new()
+                .env("REEXEC_TEST_LINEAGE", "check")
+                .reason("upgrade")
+                .exec();
+            panic!("{}", err);
+        } else {
+            assert_eq!(lineage.generation(), 1);
+            assert_eq!(lineage.reason(), Some("upgrade"));
+            assert_eq!(
+                lineage.previous_build_id(),
+                crate::exe_info().unwrap().build_id()
+            );
+            assert!(lineage.first_start() <= SystemTime::now());
+        }
+    }
+
+    #[test]
+    fn test_lineage() {
+        let status = std::process::Command::new(std::env::current_exe().unwrap())
+            .arg("--exact")
+            .arg("lineage::tests::test_lineage_child")
+            .env("REEXEC_TEST_LINEAGE", "exec")
+            .stdout(std::process::Stdio::null())
+            .status()
+            .unwrap();
+        assert!(status.success());
+    }
+}
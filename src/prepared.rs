@@ -0,0 +1,458 @@
+use std::ffi::CString;
+use std::io;
+use std::mem::size_of;
+use std::os::unix::prelude::*;
+use std::sync::atomic::{AtomicBool, AtomicU8, Ordering};
+
+use crate::builder::nul_error;
+use crate::lineage::{Slots, LINEAGE_VAR};
+use crate::{Reexec, ReexecError, Strategy};
+
+/// The size of the buffer used to hold candidate paths.
+const PATH_BUF_LEN: usize = libc::PATH_MAX as usize + 1;
+
+/// A [`Reexec`] whose `argv` and `envp` arrays have been built ahead of time.
+///
+/// This is created with [`Reexec::prepare()`]. All of the memory that [`Self::exec()`] needs
+/// (the `argv` and `envp` arrays, the strings they point to, and a buffer for candidate paths) is
+/// allocated up front in a single anonymous `mmap()`ed region, so `exec()` is async-signal-safe.
+/// It can be called from a signal handler, or in the child after `fork()`ing a multithreaded
+/// process.
+///
+/// The arguments and environment are fixed when the `PreparedReexec` is built. If they change
+/// (for example, the environment is modified), call [`Self::update()`], which reuses the existing
+/// region if it's large enough. The exception is the [`Lineage`](crate::Lineage) passed to the
+/// new program: the PID and the time of the re-exec are filled in when [`Self::exec()`] is
+/// called, so a `PreparedReexec` can be built long before it's used (or used in a child process
+/// after `fork()`ing). The rest of the lineage (including the reason and the build ID) is fixed.
+///
+/// [`Reexec::pass_fd()`], [`Reexec::child()`], and [`Reexec::loop_guard()`] are not supported;
+/// building a `PreparedReexec` from a builder that uses them fails with
+/// [`io::ErrorKind::InvalidInput`].
+#[derive(Debug)]
+pub struct PreparedReexec {
+    map: *mut u8,
+    map_len: usize,
+    argv: *const *const libc::c_char,
+    envp: *const *const libc::c_char,
+    path_buf: *mut u8,
+    path_buf_busy: AtomicBool,
+    /// The offset and length of the `REEXEC_LINEAGE` value in the region, and the positions of
+    /// the fields in it that are filled in at exec time.
+    lineage: Option<(usize, usize, Slots)>,
+    strategies: Option<Box<[Strategy]>>,
+    preserve_signal_state: bool,
+    default_signals: Box<[i32]>,
+    close_fds_above: Option<RawFd>,
+    keep_fds: Box<[RawFd]>,
+    current_dir: Option<CString>,
+    umask: Option<libc::mode_t>,
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    recover_deleted: bool,
+}
+
+// The region is only written to by update() (which takes &mut self), except for the path buffer,
+// which is guarded by path_buf_busy, and the lineage slots, which are only written to atomically
+unsafe impl Send for PreparedReexec {}
+unsafe impl Sync for PreparedReexec {}
+
+impl PreparedReexec {
+    pub(crate) fn new(reexec: &Reexec) -> io::Result<Self> {
+        let mut prepared = Self {
+            map: std::ptr::null_mut(),
+            map_len: 0,
+            argv: std::ptr::null(),
+            envp: std::ptr::null(),
+            path_buf: std::ptr::null_mut(),
+            path_buf_busy: AtomicBool::new(false),
+            lineage: None,
+            strategies: None,
+            preserve_signal_state: false,
+            default_signals: Box::new([]),
+            close_fds_above: None,
+            keep_fds: Box::new([]),
+            current_dir: None,
+            umask: None,
+            #[cfg(any(target_os = "linux", target_os = "android"))]
+            recover_deleted: false,
+        };
+        prepared.update(reexec)?;
+        Ok(prepared)
+    }
+
+    /// Rebuild the `argv` and `envp` arrays from the given builder.
+    ///
+    /// This is equivalent to replacing `self` with `reexec.prepare()`, except that the existing
+    /// memory region is reused if it's large enough. If an error occurs, `self` is left
+    /// unchanged.
+    pub fn update(&mut self, reexec: &Reexec) -> io::Result<()> {
+        if !reexec.pass_fds.is_empty() || !reexec.children.is_empty() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "file descriptors and child processes can't be passed with a PreparedReexec",
+            ));
+        } else if reexec.loop_guard.is_some() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "loop guards can't be used with a PreparedReexec",
+            ));
+        }
+
+        let argv = reexec.build_argv();
+        let env = reexec
+            .build_exec_env(&[])
+            .into_iter()
+            .map(|(key, val)| {
+                let mut var = Vec::with_capacity(key.len() + val.len() + 1);
+                var.extend_from_slice(key.as_bytes());
+                var.push(b'=');
+                var.extend_from_slice(val.as_bytes());
+                var
+            })
+            .collect::<Vec<_>>();
+
+        if argv.iter().any(|arg| arg.as_bytes().contains(&0))
+            || env.iter().any(|var| var.contains(&0))
+        {
+            return Err(nul_error());
+        }
+
+        let current_dir = match reexec.current_dir {
+            Some(ref dir) => {
+                Some(CString::new(dir.as_os_str().as_bytes()).map_err(|_| nul_error())?)
+            }
+            None => None,
+        };
+
+        let lineage_prefix = format!("{}=", LINEAGE_VAR);
+        let lineage_slots = env.iter().enumerate().find_map(|(i, var)| {
+            let val = var.strip_prefix(lineage_prefix.as_bytes())?;
+            Some((argv.len() + 1 + i, Slots::find(val)?))
+        });
+
+        // Layout: argv pointers, envp pointers, the path buffer, then the strings
+        let nptrs = argv.len() + env.len() + 2;
+        let strings_len = argv.iter().map(|arg| arg.len() + 1).sum::<usize>()
+            + env.iter().map(|var| var.len() + 1).sum::<usize>();
+        let len = nptrs * size_of::<*const libc::c_char>() + PATH_BUF_LEN + strings_len;
+
+        if len > self.map_len {
+            let page_size = unsafe { libc::sysconf(libc::_SC_PAGESIZE) } as usize;
+            let map_len = (len + page_size - 1) & !(page_size - 1);
+
+            let map = unsafe {
+                libc::mmap(
+                    std::ptr::null_mut(),
+                    map_len,
+                    libc::PROT_READ | libc::PROT_WRITE,
+                    libc::MAP_PRIVATE | libc::MAP_ANONYMOUS,
+                    -1,
+                    0,
+                )
+            };
+            if map == libc::MAP_FAILED {
+                return Err(io::Error::last_os_error());
+            }
+
+            self.unmap();
+            self.map = map as *mut u8;
+            self.map_len = map_len;
+        }
+
+        let mut lineage = None;
+        unsafe {
+            let ptrs = self.map as *mut *const libc::c_char;
+            let mut strings = self
+                .map
+                .add(nptrs * size_of::<*const libc::c_char>() + PATH_BUF_LEN);
+
+            let items = argv
+                .iter()
+                .map(|arg| arg.as_bytes())
+                .chain(std::iter::once(&[][..]))
+                .chain(env.iter().map(|var| var.as_slice()));
+            for (i, item) in items.enumerate() {
+                // The empty item separates argv and envp; it's replaced by a NULL pointer
+                if i == argv.len() {
+                    *ptrs.add(i) = std::ptr::null();
+                    continue;
+                }
+
+                std::ptr::copy_nonoverlapping(item.as_ptr(), strings, item.len());
+                *strings.add(item.len()) = 0;
+                *ptrs.add(i) = strings as *const libc::c_char;
+                if let Some((index, slots)) = lineage_slots {
+                    if i == index {
+                        let offset = strings as usize - self.map as usize + lineage_prefix.len();
+                        lineage = Some((offset, item.len() - lineage_prefix.len(), slots));
+                    }
+                }
+                strings = strings.add(item.len() + 1);
+            }
+            *ptrs.add(nptrs - 1) = std::ptr::null();
+
+            self.argv = ptrs;
+            self.envp = ptrs.add(argv.len() + 1);
+            self.path_buf = self.map.add(nptrs * size_of::<*const libc::c_char>());
+        }
+        self.lineage = lineage;
+
+        self.strategies = reexec
+            .strategies
+            .as_ref()
+            .map(|strategies| strategies.clone().into_boxed_slice());
+        self.preserve_signal_state = reexec.preserve_signal_state;
+        self.default_signals = reexec.default_signals.clone().into_boxed_slice();
+        self.close_fds_above = reexec.close_fds_above;
+        self.keep_fds = reexec.sorted_keep_fds().into_boxed_slice();
+        self.current_dir = current_dir;
+        self.umask = reexec.umask.map(|mask| mask as libc::mode_t);
+        #[cfg(any(target_os = "linux", target_os = "android"))]
+        {
+            self.recover_deleted = reexec.recover_deleted;
+        }
+
+        Ok(())
+    }
+
+    /// Get the prepared `argv` array (terminated by a NULL pointer).
+    #[inline]
+    pub fn argv(&self) -> *const *const libc::c_char {
+        self.argv
+    }
+
+    /// Get the prepared `envp` array (terminated by a NULL pointer).
+    #[inline]
+    pub fn envp(&self) -> *const *const libc::c_char {
+        self.envp
+    }
+
+    /// Re-execute the current program.
+    ///
+    /// This tries the same strategies as [`Reexec::exec()`], in the same order, and honors
+    /// [`Reexec::preserve_signal_state()`], [`Reexec::close_fds_above()`],
+    /// [`Reexec::current_dir()`], [`Reexec::umask()`], and (on Linux)
+    /// [`Reexec::recover_deleted()`]. It will only return if an error occurs.
+    ///
+    /// This function is async-signal-safe (as long as any custom strategies are). For that
+    /// reason, it returns a [`ReexecError`] rather than an `io::Error`, since converting it into
+    /// an `io::Error` allocates memory.
+    pub fn exec(&self) -> ReexecError {
+        self.exec_with(|| self.exec_impl())
+    }
+
+    /// Re-execute the program that the given [`ExeHandle`](crate::ExeHandle) refers to.
+    ///
+    /// See [`ExeHandle::reexecve()`](crate::ExeHandle::reexecve) and [`Self::exec()`]. If the
+    /// kernel doesn't support `execveat()`, this falls back on the strategies that were set with
+    /// [`Reexec::strategies()`].
+    ///
+    /// This function is async-signal-safe.
+    #[cfg_attr(docsrs, doc(cfg(any(target_os = "linux", target_os = "android"))))]
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    pub fn exec_handle(&self, handle: &crate::ExeHandle) -> ReexecError {
+        let strategies = match self.strategies {
+            Some(ref strategies) => strategies,
+            None => crate::strategies(),
+        };
+
+        self.exec_with(|| unsafe {
+            handle.reexecve_via(handle.as_raw_fd(), strategies, self.argv, self.envp)
+        })
+    }
+
+    /// Set up the process state and call `f()` to actually re-execute the program, then restore
+    /// the state if it fails.
+    fn exec_with<F: FnOnce() -> ReexecError>(&self, f: F) -> ReexecError {
+        self.fill_lineage();
+
+        if let Some(min_fd) = self.close_fds_above {
+            if let Err(eno) = crate::imp::fds::cloexec_above(min_fd, &self.keep_fds) {
+                return ReexecError::new(eno);
+            }
+        }
+
+        let procstate = match unsafe {
+            crate::imp::procstate::apply(self.current_dir.as_deref(), self.umask)
+        } {
+            Ok(procstate) => procstate,
+            Err(eno) => return ReexecError::new(eno),
+        };
+
+        let err = if self.preserve_signal_state {
+            f()
+        } else {
+            unsafe {
+                let saved = crate::imp::sigstate::normalize(&self.default_signals);
+                let err = f();
+                crate::imp::sigstate::restore(&saved, &self.default_signals);
+                err
+            }
+        };
+
+        unsafe {
+            crate::imp::procstate::restore(procstate);
+        }
+        err
+    }
+
+    /// Fill in the PID and the time of the re-exec in the lineage passed to the new program.
+    fn fill_lineage(&self) {
+        if let Some((offset, len, slots)) = self.lineage {
+            slots.fill(unsafe {
+                std::slice::from_raw_parts(self.map.add(offset) as *const AtomicU8, len)
+            });
+        }
+    }
+
+    fn exec_impl(&self) -> ReexecError {
+        #[cfg(any(target_os = "linux", target_os = "android"))]
+        if self.recover_deleted && crate::is_exe_deleted() {
+            return unsafe { crate::reexecve_running_image(self.argv, self.envp) };
+        }
+
+        let strategies = match self.strategies {
+            Some(ref strategies) => strategies,
+            None => crate::strategies(),
+        };
+
+        // If another thread (or a signal handler that interrupted this thread) is already using the
+        // path buffer, fall back on one on the stack
+        if self.path_buf_busy.swap(true, Ordering::Acquire) {
+            let mut buf = [0u8; PATH_BUF_LEN];
+            return unsafe { crate::imp::reexecve_buf(strategies, self.argv, self.envp, &mut buf) };
+        }
+
+        let err = unsafe {
+            crate::imp::reexecve_buf(
+                strategies,
+                self.argv,
+                self.envp,
+                std::slice::from_raw_parts_mut(self.path_buf, PATH_BUF_LEN),
+            )
+        };
+        self.path_buf_busy.store(false, Ordering::Release);
+        err
+    }
+
+    fn unmap(&mut self) {
+        if !self.map.is_null() {
+            unsafe {
+                libc::munmap(self.map as *mut _, self.map_len);
+            }
+            self.map = std::ptr::null_mut();
+            self.map_len = 0;
+        }
+    }
+}
+
+impl Drop for PreparedReexec {
+    #[inline]
+    fn drop(&mut self) {
+        self.unmap();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::ffi::{CStr, OsString};
+
+    unsafe fn collect(mut ptrs: *const *const libc::c_char) -> Vec<OsString> {
+        let mut items = Vec::new();
+        while !(*ptrs).is_null() {
+            items.push(OsString::from_vec(CStr::from_ptr(*ptrs).to_bytes().into()));
+            ptrs = ptrs.add(1);
+        }
+        items
+    }
+
+    /// Like `collect()`, but skip `REEXEC_LINEAGE`, which is always present.
+    unsafe fn collect_env(ptrs: *const *const libc::c_char) -> Vec<OsString> {
+        let mut env = collect(ptrs);
+        let len = env.len();
+        env.retain(|var| !var.as_bytes().starts_with(b"REEXEC_LINEAGE="));
+        assert_eq!(env.len(), len - 1);
+        env
+    }
+
+    #[test]
+    fn test_prepare() {
+        let mut reexec = Reexec::new();
+        reexec.env_clear().env("A", "1").arg0("x").arg("a");
+
+        let mut prepared = reexec.prepare().unwrap();
+        assert_eq!(unsafe { collect(prepared.argv()) }, vec!["x", "a"]);
+        assert_eq!(unsafe { collect_env(prepared.envp()) }, vec!["A=1"]);
+
+        // Shrinking reuses the same region
+        let map = prepared.map;
+        reexec.env_clear();
+        prepared.update(&reexec).unwrap();
+        assert_eq!(prepared.map, map);
+        assert_eq!(unsafe { collect(prepared.argv()) }, vec!["x", "a"]);
+        assert_eq!(
+            unsafe { collect_env(prepared.envp()) },
+            Vec::<OsString>::new()
+        );
+
+        // Growing past the end of the region remaps it
+        let long = "a".repeat(prepared.map_len);
+        reexec.env("B", &long);
+        prepared.update(&reexec).unwrap();
+        assert_eq!(
+            unsafe { collect_env(prepared.envp()) },
+            vec![OsString::from(format!("B={}", long))]
+        );
+
+        // Errors leave the prepared arrays untouched
+        let err = prepared.update(Reexec::new().arg("a\0b")).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+        assert_eq!(unsafe { collect(prepared.argv()) }, vec!["x", "a"]);
+
+        let err = prepared
+            .update(Reexec::new().pass_fd("stdin", &0))
+            .unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+    }
+
+    #[test]
+    fn test_lineage_filled() {
+        let prepared = Reexec::new().reason("test").prepare().unwrap();
+
+        let get_lineage = || {
+            let env = unsafe { collect(prepared.envp()) };
+            let var = env
+                .iter()
+                .find_map(|var| var.as_bytes().strip_prefix(b"REEXEC_LINEAGE="))
+                .unwrap();
+            crate::lineage::Lineage::parse(std::str::from_utf8(var).unwrap(), std::process::id())
+                .unwrap()
+        };
+
+        let before = get_lineage();
+        std::thread::sleep(std::time::Duration::from_millis(10));
+        prepared.fill_lineage();
+        let after = get_lineage();
+
+        // Only the time of the re-exec changes
+        assert_eq!(before.reason(), Some("test"));
+        assert_eq!(after.generation(), before.generation());
+        assert_eq!(after.reason(), before.reason());
+        assert!(after.recent().last() > before.recent().last());
+    }
+
+    #[test]
+    fn test_exec() {
+        let prepared = Reexec::new()
+            .arg("--exact")
+            .arg("__reexec_no_such_test")
+            .prepare()
+            .unwrap();
+
+        crate::tests::check_reexec_child(|_, _| {
+            prepared.exec();
+        });
+    }
+}
@@ -0,0 +1,476 @@
+//! Save the way the program was launched to disk, so it can be reproduced later.
+//!
+//! [`write()`] saves a [`Record`] of the current program's executable (its path, device ID and
+//! inode number, and build ID) and its [`LaunchContext`] (the original
+//! arguments, environment, working directory, umask, and resource limits). For example, a
+//! service could write a record at startup, so that if it crashes, the exact invocation can be
+//! reproduced with [`replay()`].
+//!
+//! Records are stored in a versioned binary format. [`read()`] fails with
+//! [`io::ErrorKind::InvalidData`] if the file isn't a record, or was written in a format that
+//! this version of the library doesn't understand.
+
+use std::ffi::{CString, OsStr, OsString};
+use std::fs;
+use std::io;
+use std::os::unix::prelude::*;
+use std::path::{Path, PathBuf};
+use std::process::{Child, Command};
+
+use crate::LaunchContext;
+
+const RECORD_MAGIC: &[u8; 8] = b"REEXECRC";
+const RECORD_FORMAT: u32 = 1;
+
+fn invalid_data(msg: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+/// A record of how a program was launched.
+///
+/// See the [module-level documentation](self).
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Record {
+    exe: PathBuf,
+    dev: u64,
+    ino: u64,
+    build_id: Option<Vec<u8>>,
+    context: LaunchContext,
+}
+
+impl Record {
+    /// Create a record of the current program.
+    ///
+    /// The executable path comes from [`get_exe_path()`](crate::get_exe_path), the device ID,
+    /// inode number, and build ID from [`exe_info()`](crate::exe_info), and the rest from
+    /// [`launch_context()`](crate::launch_context).
+    pub fn current() -> io::Result<Self> {
+        let exe = crate::get_exe_path()?.into_owned();
+        let info = crate::exe_info()?;
+
+        Ok(Self {
+            exe,
+            dev: info.dev(),
+            ino: info.ino(),
+            build_id: info.build_id().map(|id| id.to_vec()),
+            context: crate::launch_context(),
+        })
+    }
+
+    /// Get the path to the executable.
+    #[inline]
+    pub fn exe(&self) -> &Path {
+        &self.exe
+    }
+
+    /// Get the device ID of the executable.
+    #[inline]
+    pub fn dev(&self) -> u64 {
+        self.dev
+    }
+
+    /// Get the inode number of the executable.
+    #[inline]
+    pub fn ino(&self) -> u64 {
+        self.ino
+    }
+
+    /// Get the GNU build ID of the executable, if it had one.
+    #[inline]
+    pub fn build_id(&self) -> Option<&[u8]> {
+        self.build_id.as_deref()
+    }
+
+    /// Get the context the program was launched in.
+    #[inline]
+    pub fn context(&self) -> &LaunchContext {
+        &self.context
+    }
+
+    /// Check that the executable at the recorded path is the one that was recorded.
+    ///
+    /// If a build ID was recorded, the executable must have the same build ID. Otherwise, it must
+    /// have the same device ID and inode number (which only works on the same machine).
+    ///
+    /// On success, the file that was checked is returned.
+    fn verify_exe(&self) -> io::Result<fs::File> {
+        let file = fs::File::open(&self.exe)?;
+
+        let matches = match self.build_id {
+            Some(ref build_id) => {
+                crate::imp::file_build_id(&file)?.as_deref() == Some(build_id.as_slice())
+            }
+            None => {
+                let meta = file.metadata()?;
+                meta.dev() == self.dev && meta.ino() == self.ino
+            }
+        };
+
+        if matches {
+            Ok(file)
+        } else {
+            Err(invalid_data(format!(
+                "{} doesn't match the recorded executable",
+                self.exe.display()
+            )))
+        }
+    }
+
+    /// Launch the recorded executable under the recorded context.
+    ///
+    /// The new process gets the recorded arguments (including `argv[0]`) and environment, and
+    /// the recorded working directory, umask, and resource limits. (Resource limits that can't be
+    /// set, for example because raising a hard limit requires privileges, are left alone.) Like
+    /// [`Reexec::from_launch_context()`](crate::Reexec::from_launch_context), variables that
+    /// describe file descriptors and child processes passed to the original program are left out
+    /// of the environment.
+    ///
+    /// This refuses to launch the executable (failing with [`io::ErrorKind::InvalidData`]) if it
+    /// no longer matches the record: if a build ID was recorded, the build ID of the file at the
+    /// recorded path must match it; otherwise, its device ID and inode number must match.
+    ///
+    /// On Linux, the file that was checked is executed through `/proc/self/fd`, so replacing the
+    /// file at the recorded path after the check has no effect. Elsewhere (or if `/proc` isn't
+    /// available), the path is checked again right before `execve()`; if it no longer refers to
+    /// the same file, spawning fails with `ESTALE`.
+    pub fn replay(&self) -> io::Result<Child> {
+        let file = self.verify_exe()?;
+        let meta = file.metadata()?;
+        let (dev, ino) = (meta.dev(), meta.ino());
+
+        // Only look up the path again if the verified file can't be executed directly
+        #[cfg(any(target_os = "linux", target_os = "android"))]
+        let fd_path = {
+            let path = format!("/proc/self/fd/{}", file.as_raw_fd());
+            let cpath = CString::new(path.as_str()).unwrap();
+            if unsafe { libc::access(cpath.as_ptr(), libc::X_OK) } == 0 {
+                Some(PathBuf::from(path))
+            } else {
+                None
+            }
+        };
+        #[cfg(not(any(target_os = "linux", target_os = "android")))]
+        let fd_path: Option<PathBuf> = None;
+
+        let recheck = match fd_path {
+            Some(_) => None,
+            None => Some(
+                CString::new(self.exe.as_os_str().as_bytes())
+                    .map_err(|_| crate::builder::nul_error())?,
+            ),
+        };
+
+        let mut cmd = Command::new(fd_path.as_deref().unwrap_or(&self.exe));
+
+        let mut args = self.context.args().iter();
+        if let Some(arg0) = args.next() {
+            cmd.arg0(arg0);
+        }
+        cmd.args(args).env_clear().envs(
+            self.context
+                .env()
+                .iter()
+                .filter(|(key, _)| !crate::launch::is_handoff_var(key))
+                .map(|(key, val)| (key, val)),
+        );
+
+        if let Some(cwd) = self.context.cwd() {
+            cmd.current_dir(cwd);
+        }
+
+        let umask = self.context.umask() as libc::mode_t;
+        let rlimits = self.context.rlimits.clone();
+        unsafe {
+            cmd.pre_exec(move || {
+                // The verified file is close-on-exec, but it's still open when execve() looks up
+                // /proc/self/fd/<fd>
+                let _ = &file;
+
+                if let Some(ref path) = recheck {
+                    let mut st = std::mem::MaybeUninit::<libc::stat>::uninit();
+                    if libc::stat(path.as_ptr(), st.as_mut_ptr()) != 0 {
+                        return Err(io::Error::last_os_error());
+                    }
+                    let st = st.assume_init();
+                    #[allow(clippy::unnecessary_cast)]
+                    if st.st_dev as u64 != dev || st.st_ino as u64 != ino {
+                        return Err(io::Error::from_raw_os_error(libc::ESTALE));
+                    }
+                }
+
+                libc::umask(umask);
+                for &(resource, soft, hard) in rlimits.iter() {
+                    let rlim = libc::rlimit {
+                        rlim_cur: crate::launch::rlim_from_u64(soft),
+                        rlim_max: crate::launch::rlim_from_u64(hard),
+                    };
+                    libc::setrlimit(resource as _, &rlim);
+                }
+                Ok(())
+            });
+        }
+
+        cmd.spawn()
+    }
+
+    fn encode(&self) -> Vec<u8> {
+        let mut enc = Encoder(Vec::new());
+        enc.0.extend_from_slice(RECORD_MAGIC);
+        enc.u32(RECORD_FORMAT);
+
+        enc.bytes(self.exe.as_os_str().as_bytes());
+        enc.u64(self.dev);
+        enc.u64(self.ino);
+        enc.opt_bytes(self.build_id.as_deref());
+
+        let ctx = &self.context;
+        enc.u32(ctx.args.len() as u32);
+        for arg in ctx.args.iter() {
+            enc.bytes(arg.as_bytes());
+        }
+        enc.u32(ctx.env.len() as u32);
+        for (key, val) in ctx.env.iter() {
+            enc.bytes(key.as_bytes());
+            enc.bytes(val.as_bytes());
+        }
+        enc.opt_bytes(ctx.cwd.as_ref().map(|cwd| cwd.as_os_str().as_bytes()));
+        enc.u32(ctx.umask);
+        enc.u32(ctx.rlimits.len() as u32);
+        for &(resource, soft, hard) in ctx.rlimits.iter() {
+            enc.u32(resource as u32);
+            enc.u64(soft);
+            enc.u64(hard);
+        }
+        enc.0.push(ctx.late as u8);
+
+        enc.0
+    }
+
+    fn decode(buf: &[u8]) -> io::Result<Self> {
+        if buf.len() < 12 || &buf[..8] != RECORD_MAGIC {
+            return Err(invalid_data("not a reexec launch record".into()));
+        }
+
+        let mut dec = Decoder(&buf[8..]);
+        let format = dec.u32()?;
+        if format != RECORD_FORMAT {
+            return Err(invalid_data(format!(
+                "unsupported launch record format {} (expected {})",
+                format, RECORD_FORMAT
+            )));
+        }
+
+        let exe = PathBuf::from(dec.os_string()?);
+        let dev = dec.u64()?;
+        let ino = dec.u64()?;
+        let build_id = dec.opt_bytes()?.map(|id| id.to_vec());
+
+        let args = (0..dec.u32()?)
+            .map(|_| dec.os_string())
+            .collect::<io::Result<Vec<_>>>()?;
+        let env = (0..dec.u32()?)
+            .map(|_| Ok((dec.os_string()?, dec.os_string()?)))
+            .collect::<io::Result<Vec<_>>>()?;
+        let cwd = dec
+            .opt_bytes()?
+            .map(|cwd| PathBuf::from(OsStr::from_bytes(cwd)));
+        let umask = dec.u32()?;
+        let rlimits = (0..dec.u32()?)
+            .map(|_| Ok((dec.u32()? as i32, dec.u64()?, dec.u64()?)))
+            .collect::<io::Result<Vec<_>>>()?;
+        let late = dec.take(1)?[0] != 0;
+
+        if !dec.0.is_empty() {
+            return Err(invalid_data("trailing data in launch record".into()));
+        }
+
+        Ok(Self {
+            exe,
+            dev,
+            ino,
+            build_id,
+            context: LaunchContext {
+                args,
+                env,
+                cwd,
+                umask,
+                rlimits,
+                late,
+            },
+        })
+    }
+}
+
+struct Encoder(Vec<u8>);
+
+impl Encoder {
+    fn u32(&mut self, val: u32) {
+        self.0.extend_from_slice(&val.to_le_bytes());
+    }
+
+    fn u64(&mut self, val: u64) {
+        self.0.extend_from_slice(&val.to_le_bytes());
+    }
+
+    fn bytes(&mut self, data: &[u8]) {
+        self.u32(data.len() as u32);
+        self.0.extend_from_slice(data);
+    }
+
+    fn opt_bytes(&mut self, data: Option<&[u8]>) {
+        match data {
+            Some(data) => {
+                self.0.push(1);
+                self.bytes(data);
+            }
+            None => self.0.push(0),
+        }
+    }
+}
+
+struct Decoder<'a>(&'a [u8]);
+
+impl<'a> Decoder<'a> {
+    fn take(&mut self, len: usize) -> io::Result<&'a [u8]> {
+        if self.0.len() < len {
+            return Err(invalid_data("truncated launch record".into()));
+        }
+        let (data, rest) = self.0.split_at(len);
+        self.0 = rest;
+        Ok(data)
+    }
+
+    fn u32(&mut self) -> io::Result<u32> {
+        let mut bytes = [0; 4];
+        bytes.copy_from_slice(self.take(4)?);
+        Ok(u32::from_le_bytes(bytes))
+    }
+
+    fn u64(&mut self) -> io::Result<u64> {
+        let mut bytes = [0; 8];
+        bytes.copy_from_slice(self.take(8)?);
+        Ok(u64::from_le_bytes(bytes))
+    }
+
+    fn bytes(&mut self) -> io::Result<&'a [u8]> {
+        let len = self.u32()? as usize;
+        self.take(len)
+    }
+
+    fn os_string(&mut self) -> io::Result<OsString> {
+        Ok(OsStr::from_bytes(self.bytes()?).into())
+    }
+
+    fn opt_bytes(&mut self) -> io::Result<Option<&'a [u8]>> {
+        match self.take(1)?[0] {
+            0 => Ok(None),
+            _ => self.bytes().map(Some),
+        }
+    }
+}
+
+/// Write a [`Record`] of the current program to the given file (see [`Record::current()`]).
+///
+/// For the most accurate record, the launch context should be captured early (see
+/// [`init()`](crate::init)).
+pub fn write<P: AsRef<Path>>(path: P) -> io::Result<()> {
+    fs::write(path, Record::current()?.encode())
+}
+
+/// Read a [`Record`] from the given file.
+pub fn read<P: AsRef<Path>>(path: P) -> io::Result<Record> {
+    Record::decode(&fs::read(path)?)
+}
+
+/// Read a [`Record`] from the given file and [replay](Record::replay) it.
+pub fn replay<P: AsRef<Path>>(path: P) -> io::Result<Child> {
+    read(path)?.replay()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn temp_path(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("reexec-test-{}-{}", name, std::process::id()))
+    }
+
+    #[test]
+    fn test_write_read() {
+        crate::init();
+
+        let path = temp_path("record");
+        write(&path).unwrap();
+        let record = read(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+
+        assert_eq!(record, Record::current().unwrap());
+        assert_eq!(record.exe(), std::env::current_exe().unwrap());
+
+        let data = record.encode();
+        for len in 0..data.len() {
+            let err = Record::decode(&data[..len]).unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        }
+
+        let mut data = data;
+        data[8] = 2;
+        let err = Record::decode(&data).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn test_replay_child() {
+        if std::env::var_os("REEXEC_TEST_RECORD").is_none() {
+            return;
+        }
+
+        assert_eq!(std::env::current_dir().unwrap(), Path::new("/"));
+        assert_eq!(crate::launch_context().umask(), 0o027);
+
+        let mut rlim = libc::rlimit {
+            rlim_cur: 0,
+            rlim_max: 0,
+        };
+        assert_eq!(unsafe { libc::getrlimit(libc::RLIMIT_CORE, &mut rlim) }, 0);
+        assert_eq!(rlim.rlim_cur, 0);
+    }
+
+    #[test]
+    fn test_replay() {
+        crate::init();
+
+        let mut record = Record::current().unwrap();
+        record.context.args = vec![
+            std::env::args_os().next().unwrap(),
+            "--exact".into(),
+            "record::tests::test_replay_child".into(),
+        ];
+        record.context.env = std::env::vars_os()
+            .chain(std::iter::once(("REEXEC_TEST_RECORD".into(), "1".into())))
+            .collect();
+        record.context.cwd = Some("/".into());
+        record.context.umask = 0o027;
+        #[allow(clippy::unnecessary_cast)]
+        let core = libc::RLIMIT_CORE as i32;
+        for rlimit in record.context.rlimits.iter_mut() {
+            if rlimit.0 == core {
+                rlimit.1 = 0;
+            }
+        }
+
+        let path = temp_path("replay");
+        fs::write(&path, record.encode()).unwrap();
+        let mut child = replay(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+        assert!(child.wait().unwrap().success());
+
+        // A different executable is refused
+        match record.build_id {
+            Some(ref mut build_id) => build_id[0] ^= 1,
+            None => record.ino = record.ino.wrapping_add(1),
+        }
+        let err = record.replay().unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    }
+}
@@ -0,0 +1,337 @@
+use std::fmt;
+use std::io;
+use std::os::unix::prelude::*;
+use std::sync::atomic::{AtomicI32, Ordering};
+
+use crate::imp::errno;
+use crate::{errno_ptr, Reexec};
+
+/// The write end of the self-pipe, or -1 if `restart_on_signal()` hasn't been called.
+static PIPE_WR: AtomicI32 = AtomicI32::new(-1);
+
+/// Options for [`restart_on_signal()`].
+#[derive(Default)]
+pub struct RestartOptions {
+    reexec: Reexec,
+    graceful: Option<Box<dyn FnMut(i32) + Send>>,
+    on_error: Option<Box<dyn FnMut(io::Error) + Send>>,
+}
+
+impl RestartOptions {
+    /// Create a new set of options that will re-execute the program with its original arguments
+    /// and current environment.
+    #[inline]
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Set the [`Reexec`] builder used to re-execute the program.
+    ///
+    /// The `argv` and `envp` arrays are built when a signal is received, so changes made to the
+    /// environment in the meantime are reflected in the new program.
+    pub fn reexec(&mut self, reexec: Reexec) -> &mut Self {
+        self.reexec = reexec;
+        self
+    }
+
+    /// Set a callback that is run before the program is re-executed.
+    ///
+    /// This is called with the number of the signal that was received. It runs on the watcher
+    /// thread (not in a signal handler), so it can do anything a normal thread can: for example,
+    /// stop accepting connections and wait for in-flight requests to finish.
+    pub fn graceful<F: FnMut(i32) + Send + 'static>(&mut self, f: F) -> &mut Self {
+        self.graceful = Some(Box::new(f));
+        self
+    }
+
+    /// Set a callback that is run if re-executing the program fails.
+    ///
+    /// If this isn't set, the error is silently discarded. Either way, the watcher thread then
+    /// goes back to waiting for signals.
+    pub fn on_error<F: FnMut(io::Error) + Send + 'static>(&mut self, f: F) -> &mut Self {
+        self.on_error = Some(Box::new(f));
+        self
+    }
+}
+
+impl fmt::Debug for RestartOptions {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("RestartOptions")
+            .field("reexec", &self.reexec)
+            .field("graceful", &self.graceful.is_some())
+            .field("on_error", &self.on_error.is_some())
+            .finish()
+    }
+}
+
+extern "C" fn handle_signal(sig: libc::c_int) {
+    // Preserve errno for the interrupted code
+    let saved_errno = errno();
+
+    let fd = PIPE_WR.load(Ordering::Relaxed);
+    if fd >= 0 {
+        // If the pipe is full, a restart is already pending, so it's fine to drop this
+        let byte = sig as u8;
+        unsafe {
+            libc::write(fd, &byte as *const u8 as *const _, 1);
+        }
+    }
+
+    unsafe {
+        *errno_ptr() = saved_errno;
+    }
+}
+
+/// Create a pipe with both ends marked close-on-exec, and the write end non-blocking.
+fn make_pipe() -> io::Result<(RawFd, RawFd)> {
+    let mut fds = [0; 2];
+
+    #[cfg(any(
+        target_os = "linux",
+        target_os = "android",
+        target_os = "freebsd",
+        target_os = "netbsd",
+        target_os = "openbsd",
+        target_os = "dragonfly",
+        target_os = "illumos",
+        target_os = "solaris",
+        target_os = "redox",
+    ))]
+    if unsafe { libc::pipe2(fds.as_mut_ptr(), libc::O_CLOEXEC) } < 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    #[cfg(not(any(
+        target_os = "linux",
+        target_os = "android",
+        target_os = "freebsd",
+        target_os = "netbsd",
+        target_os = "openbsd",
+        target_os = "dragonfly",
+        target_os = "illumos",
+        target_os = "solaris",
+        target_os = "redox",
+    )))]
+    unsafe {
+        if libc::pipe(fds.as_mut_ptr()) < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        for &fd in fds.iter() {
+            libc::fcntl(fd, libc::F_SETFD, libc::FD_CLOEXEC);
+        }
+    }
+
+    unsafe {
+        let flags = libc::fcntl(fds[1], libc::F_GETFL);
+        libc::fcntl(fds[1], libc::F_SETFL, flags | libc::O_NONBLOCK);
+    }
+
+    Ok((fds[0], fds[1]))
+}
+
+fn watch(rd: RawFd, mut options: RestartOptions) {
+    loop {
+        let mut byte = 0u8;
+        let n = unsafe { libc::read(rd, &mut byte as *mut u8 as *mut _, 1) };
+        if n < 0 {
+            if errno() == libc::EINTR {
+                continue;
+            }
+            return;
+        } else if n == 0 {
+            return;
+        }
+
+        if let Some(ref mut graceful) = options.graceful {
+            graceful(byte as i32);
+        }
+
+        let err = options.reexec.exec();
+        if let Some(detected) = crate::LoopDetected::from_error(&err) {
+            if detected.action() == crate::LoopAction::Continue {
+                continue;
+            }
+        }
+
+        if let Some(ref mut on_error) = options.on_error {
+            on_error(err);
+        }
+    }
+}
+
+/// Re-execute the current program whenever one of the given signals is received.
+///
+/// This installs a handler for each signal that notifies a background "watcher" thread (using
+/// the self-pipe trick). The watcher thread runs the [graceful](RestartOptions::graceful)
+/// callback, if one was set, and then re-executes the program with [`Reexec::exec()`]. By default,
+/// the program is re-executed with its original arguments.
+///
+/// Since the actual work is done on a normal thread, none of it needs to be async-signal-safe.
+///
+/// This can only be called once per process; later calls fail with
+/// [`io::ErrorKind::AlreadyExists`]. Signal numbers must be valid, less than 256, and not
+/// `SIGKILL` or `SIGSTOP`; otherwise this fails with `EINVAL`. If this fails, no handlers are left
+/// installed, and it can be called again.
+pub fn restart_on_signal(signals: &[i32], options: RestartOptions) -> io::Result<()> {
+    if signals
+        .iter()
+        .any(|&sig| !(1..256).contains(&sig) || sig == libc::SIGKILL || sig == libc::SIGSTOP)
+    {
+        return Err(io::Error::from_raw_os_error(libc::EINVAL));
+    }
+
+    // Save the old actions (which also checks that the signals are valid), so they can be
+    // restored if anything fails
+    let mut old_actions = Vec::with_capacity(signals.len());
+    for &sig in signals {
+        unsafe {
+            let mut old: libc::sigaction = std::mem::zeroed();
+            if libc::sigaction(sig, std::ptr::null(), &mut old) < 0 {
+                return Err(io::Error::last_os_error());
+            }
+            old_actions.push(old);
+        }
+    }
+
+    let restore = |n: usize| {
+        for (&sig, old) in signals[..n].iter().zip(old_actions.iter()) {
+            unsafe {
+                libc::sigaction(sig, old, std::ptr::null_mut());
+            }
+        }
+    };
+
+    let (rd, wr) = make_pipe()?;
+    if PIPE_WR
+        .compare_exchange(-1, wr, Ordering::AcqRel, Ordering::Acquire)
+        .is_err()
+    {
+        unsafe {
+            libc::close(rd);
+            libc::close(wr);
+        }
+        return Err(io::Error::new(
+            io::ErrorKind::AlreadyExists,
+            "restart_on_signal() has already been called",
+        ));
+    }
+
+    let undo = |n: usize, e: io::Error| {
+        restore(n);
+        PIPE_WR.store(-1, Ordering::Release);
+        unsafe {
+            libc::close(rd);
+            libc::close(wr);
+        }
+        Err(e)
+    };
+
+    for (i, &sig) in signals.iter().enumerate() {
+        unsafe {
+            let mut act: libc::sigaction = std::mem::zeroed();
+            act.sa_sigaction = handle_signal as extern "C" fn(libc::c_int) as libc::sighandler_t;
+            act.sa_flags = libc::SA_RESTART;
+            libc::sigemptyset(&mut act.sa_mask);
+
+            if libc::sigaction(sig, &act, std::ptr::null_mut()) < 0 {
+                return undo(i, io::Error::last_os_error());
+            }
+        }
+    }
+
+    // If a signal arrives before the watcher thread starts, it's buffered in the pipe
+    if let Err(e) = std::thread::Builder::new()
+        .name("reexec-restart".into())
+        .spawn(move || watch(rd, options))
+    {
+        return undo(signals.len(), e);
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::io::Read;
+    use std::process::{Command, Stdio};
+
+    #[test]
+    fn test_restart_child() {
+        if std::env::var_os("REEXEC_TEST_RESTART").is_none() {
+            return;
+        }
+
+        let mut reexec = Reexec::new();
+        reexec
+            .args(vec!["--exact", "__reexec_no_such_test"])
+            .env_remove("REEXEC_TEST_RESTART");
+
+        let mut options = RestartOptions::new();
+        options
+            .reexec(reexec)
+            .graceful(|sig| println!("graceful {}", sig));
+        restart_on_signal(&[libc::SIGUSR2], options).unwrap();
+
+        let err = restart_on_signal(&[libc::SIGUSR2], RestartOptions::new()).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::AlreadyExists);
+
+        unsafe {
+            libc::raise(libc::SIGUSR2);
+        }
+
+        // The watcher thread should re-execute the program before this finishes
+        std::thread::sleep(std::time::Duration::from_secs(10));
+        panic!("program was not re-executed");
+    }
+
+    #[test]
+    fn test_restart_on_signal() {
+        let mut child = Command::new(std::env::current_exe().unwrap())
+            .arg("--exact")
+            .arg("restart::tests::test_restart_child")
+            .arg("--nocapture")
+            .env("REEXEC_TEST_RESTART", "1")
+            .stdout(Stdio::piped())
+            .stderr(Stdio::null())
+            .spawn()
+            .unwrap();
+
+        let mut output = String::new();
+        child
+            .stdout
+            .take()
+            .unwrap()
+            .read_to_string(&mut output)
+            .unwrap();
+        assert!(child.wait().unwrap().success());
+        assert!(output.contains(&format!("graceful {}", libc::SIGUSR2)));
+    }
+
+    #[test]
+    fn test_invalid_signal() {
+        for signals in [
+            &[0][..],
+            &[256],
+            &[libc::SIGSTOP],
+            &[libc::SIGUSR1, libc::SIGKILL],
+        ]
+        .iter()
+        {
+            let err = restart_on_signal(signals, RestartOptions::new()).unwrap_err();
+            assert_eq!(err.raw_os_error(), Some(libc::EINVAL));
+        }
+
+        // Nothing was installed
+        assert_eq!(PIPE_WR.load(Ordering::Acquire), -1);
+        unsafe {
+            let mut old: libc::sigaction = std::mem::zeroed();
+            assert_eq!(
+                libc::sigaction(libc::SIGUSR1, std::ptr::null(), &mut old),
+                0
+            );
+            assert_eq!(old.sa_sigaction, libc::SIG_DFL);
+        }
+    }
+}
@@ -0,0 +1,350 @@
+use std::ffi::OsStr;
+use std::fmt;
+use std::io;
+use std::os::unix::prelude::*;
+use std::process::{Child, Command, Stdio};
+
+use crate::{PreparedReexec, Reexec};
+
+/// Options for [`SpawnOptions::spawn()`].
+///
+/// The new process runs the current program, located with the same strategies as
+/// [`Reexec::exec()`]. Its arguments and environment come from a [`Reexec`] builder.
+#[derive(Default)]
+pub struct SpawnOptions {
+    reexec: Reexec,
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    exe_handle: Option<crate::ExeHandle>,
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    parent_death_signal: Option<i32>,
+    new_session: bool,
+    stdin: Option<Stdio>,
+    stdout: Option<Stdio>,
+    stderr: Option<Stdio>,
+}
+
+impl SpawnOptions {
+    /// Create a new set of options that will spawn a copy of the program with its original
+    /// arguments and the current environment.
+    #[inline]
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Set the [`Reexec`] builder used to build the arguments and environment of the new
+    /// process.
+    ///
+    /// Features of `Reexec` that aren't supported by [`PreparedReexec`] (such as
+    /// [`Reexec::pass_fd()`]) can't be used, and will cause [`Self::spawn()`] to fail.
+    pub fn reexec(&mut self, reexec: Reexec) -> &mut Self {
+        self.reexec = reexec;
+        self
+    }
+
+    /// Execute the program that the given [`ExeHandle`](crate::ExeHandle) refers to, instead of
+    /// locating the executable with the usual strategies.
+    #[cfg_attr(docsrs, doc(cfg(any(target_os = "linux", target_os = "android"))))]
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    pub fn exe_handle(&mut self, handle: crate::ExeHandle) -> &mut Self {
+        self.exe_handle = Some(handle);
+        self
+    }
+
+    /// Have the new process receive the given signal when this thread exits (using
+    /// `prctl(PR_SET_PDEATHSIG)`).
+    ///
+    /// Note that the signal is sent when the *thread* that spawned the process exits, not the
+    /// whole process. If this process has already exited by the time the new process starts, the
+    /// signal is sent immediately.
+    #[cfg_attr(docsrs, doc(cfg(any(target_os = "linux", target_os = "android"))))]
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    pub fn parent_death_signal(&mut self, sig: i32) -> &mut Self {
+        self.parent_death_signal = Some(sig);
+        self
+    }
+
+    /// Set whether to start the new process in a new session (using `setsid()`), detaching it
+    /// from this process's controlling terminal.
+    pub fn new_session(&mut self, new_session: bool) -> &mut Self {
+        self.new_session = new_session;
+        self
+    }
+
+    /// Set the new process's standard input (see [`Command::stdin()`]).
+    pub fn stdin<T: Into<Stdio>>(&mut self, cfg: T) -> &mut Self {
+        self.stdin = Some(cfg.into());
+        self
+    }
+
+    /// Set the new process's standard output (see [`Command::stdout()`]).
+    pub fn stdout<T: Into<Stdio>>(&mut self, cfg: T) -> &mut Self {
+        self.stdout = Some(cfg.into());
+        self
+    }
+
+    /// Set the new process's standard error (see [`Command::stderr()`]).
+    pub fn stderr<T: Into<Stdio>>(&mut self, cfg: T) -> &mut Self {
+        self.stderr = Some(cfg.into());
+        self
+    }
+
+    /// Spawn a new process running the current program.
+    ///
+    /// This is implemented with [`Command`] and a `pre_exec()` hook that re-executes the program
+    /// with a [`PreparedReexec`] (so the child never uses any path that was resolved before it
+    /// was forked). If re-executing the program fails in the child, the error is reported back
+    /// through the standard library's close-on-exec pipe and returned from this function.
+    ///
+    /// The new process is not a re-exec of this one, so no [`Lineage`](crate::Lineage) is passed
+    /// to it.
+    ///
+    /// The `Stdio` handles passed to [`Self::stdin()`] and friends, and the handle passed to
+    /// `exe_handle()`, are consumed by this method.
+    pub fn spawn(&mut self) -> io::Result<Child> {
+        let mut reexec = self.reexec.clone();
+        reexec.omit_lineage = true;
+        let prepared = reexec.prepare()?;
+
+        // The program is never actually executed by Command; pre_exec() re-executes the program
+        // (or fails) first
+        let argv0 = unsafe { std::ffi::CStr::from_ptr(*prepared.argv()) };
+        let mut cmd = Command::new(OsStr::from_bytes(argv0.to_bytes()));
+
+        if let Some(stdin) = self.stdin.take() {
+            cmd.stdin(stdin);
+        }
+        if let Some(stdout) = self.stdout.take() {
+            cmd.stdout(stdout);
+        }
+        if let Some(stderr) = self.stderr.take() {
+            cmd.stderr(stderr);
+        }
+
+        let hook = ExecHook {
+            prepared,
+            #[cfg(any(target_os = "linux", target_os = "android"))]
+            exe_handle: self.exe_handle.take(),
+            #[cfg(any(target_os = "linux", target_os = "android"))]
+            parent_death_signal: self.parent_death_signal,
+            #[cfg(any(target_os = "linux", target_os = "android"))]
+            parent: unsafe { libc::getpid() },
+            new_session: self.new_session,
+        };
+
+        unsafe {
+            cmd.pre_exec(move || Err(hook.exec()));
+        }
+        cmd.spawn()
+    }
+}
+
+impl fmt::Debug for SpawnOptions {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let mut d = f.debug_struct("SpawnOptions");
+        d.field("reexec", &self.reexec);
+        #[cfg(any(target_os = "linux", target_os = "android"))]
+        d.field("exe_handle", &self.exe_handle)
+            .field("parent_death_signal", &self.parent_death_signal);
+        d.field("new_session", &self.new_session).finish()
+    }
+}
+
+/// Everything the `pre_exec()` hook needs, prepared ahead of time so that it's async-signal-safe.
+struct ExecHook {
+    prepared: PreparedReexec,
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    exe_handle: Option<crate::ExeHandle>,
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    parent_death_signal: Option<i32>,
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    parent: libc::pid_t,
+    new_session: bool,
+}
+
+impl ExecHook {
+    /// Set up the child and re-execute the program. This only returns if an error occurs.
+    ///
+    /// This is called after `fork()`, so it must be async-signal-safe.
+    fn exec(&self) -> io::Error {
+        if self.new_session && unsafe { libc::setsid() } < 0 {
+            return io::Error::last_os_error();
+        }
+
+        #[cfg(any(target_os = "linux", target_os = "android"))]
+        if let Some(sig) = self.parent_death_signal {
+            unsafe {
+                if libc::prctl(libc::PR_SET_PDEATHSIG, sig as libc::c_ulong) < 0 {
+                    return io::Error::last_os_error();
+                }
+                // If the parent already died, the signal won't be sent
+                if libc::getppid() != self.parent {
+                    libc::raise(sig);
+                }
+            }
+        }
+
+        #[cfg(any(target_os = "linux", target_os = "android"))]
+        if let Some(ref handle) = self.exe_handle {
+            return io::Error::from_raw_os_error(self.prepared.exec_handle(handle).raw_os_error());
+        }
+
+        // Converting the ReexecError directly would allocate
+        io::Error::from_raw_os_error(self.prepared.exec().raw_os_error())
+    }
+}
+
+/// Spawn a new process running the current program with the given arguments (not including
+/// `argv[0]`) and additional environment variables.
+///
+/// This is a shortcut for building a [`Reexec`] and passing it to [`SpawnOptions::spawn()`]. Like
+/// [`Reexec::exec()`], the executable is located with the configured strategies in the child, so
+/// it works even if the executable can't be found with [`std::env::current_exe()`].
+pub fn spawn_self<I, S, E, K, V>(args: I, env: E) -> io::Result<Child>
+where
+    I: IntoIterator<Item = S>,
+    S: AsRef<OsStr>,
+    E: IntoIterator<Item = (K, V)>,
+    K: AsRef<OsStr>,
+    V: AsRef<OsStr>,
+{
+    let mut reexec = Reexec::new();
+    reexec.args(args).envs(env);
+    SpawnOptions::new().reexec(reexec).spawn()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::path::Path;
+
+    #[test]
+    fn test_spawn_self() {
+        let status = spawn_self(
+            vec!["--exact", "__reexec_no_such_test"],
+            vec![("REEXEC_TEST_SPAWN_ENV", "1")],
+        )
+        .unwrap()
+        .wait()
+        .unwrap();
+        assert!(status.success());
+    }
+
+    #[test]
+    fn test_spawn_child() {
+        if std::env::var_os("REEXEC_TEST_SPAWN").is_none() {
+            return;
+        }
+
+        assert_eq!(unsafe { libc::getsid(0) }, unsafe { libc::getpid() });
+        println!("ready");
+
+        // Wait to be killed
+        std::thread::sleep(std::time::Duration::from_secs(30));
+        panic!("not killed");
+    }
+
+    #[test]
+    fn test_spawn_options() {
+        use std::io::{BufRead, BufReader};
+        use std::os::unix::process::ExitStatusExt;
+
+        // The parent death signal is sent when the spawning thread exits, so spawn it from
+        // another thread and wait for the child to report that it's started
+        let mut child = std::thread::spawn(|| {
+            let mut reexec = Reexec::new();
+            reexec
+                .args(vec![
+                    "--exact",
+                    "spawn::tests::test_spawn_child",
+                    "--nocapture",
+                ])
+                .env("REEXEC_TEST_SPAWN", "1");
+
+            let mut options = SpawnOptions::new();
+            options
+                .reexec(reexec)
+                .new_session(true)
+                .stdout(Stdio::piped())
+                .stderr(Stdio::null());
+            #[cfg(any(target_os = "linux", target_os = "android"))]
+            options.parent_death_signal(libc::SIGTERM);
+
+            let mut child = options.spawn().unwrap();
+            let mut stdout = BufReader::new(child.stdout.take().unwrap());
+            let mut line = String::new();
+            while !line.trim_end().ends_with("ready") {
+                line.clear();
+                assert_ne!(stdout.read_line(&mut line).unwrap(), 0);
+            }
+            child
+        })
+        .join()
+        .unwrap();
+
+        #[cfg(not(any(target_os = "linux", target_os = "android")))]
+        child.kill().unwrap();
+
+        let status = child.wait().unwrap();
+        #[cfg(any(target_os = "linux", target_os = "android"))]
+        assert_eq!(status.signal(), Some(libc::SIGTERM));
+        #[cfg(not(any(target_os = "linux", target_os = "android")))]
+        assert_eq!(status.signal(), Some(libc::SIGKILL));
+    }
+
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    #[test]
+    fn test_spawn_exe_handle() {
+        let mut reexec = Reexec::new();
+        reexec.args(vec!["--exact", "__reexec_no_such_test"]);
+
+        let status = SpawnOptions::new()
+            .reexec(reexec)
+            .exe_handle(crate::ExeHandle::open().unwrap())
+            .stdout(Stdio::null())
+            .spawn()
+            .unwrap()
+            .wait()
+            .unwrap();
+        assert!(status.success());
+    }
+
+    #[test]
+    fn test_spawn_cwd_child() {
+        if std::env::var_os("REEXEC_TEST_SPAWN_CWD").is_none() {
+            return;
+        }
+
+        assert_eq!(std::env::current_dir().unwrap(), Path::new("/"));
+        assert!(std::env::var_os(crate::lineage::LINEAGE_VAR).is_none());
+    }
+
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    #[test]
+    fn test_spawn_exe_handle_cwd() {
+        let mut reexec = Reexec::new();
+        reexec
+            .args(vec!["--exact", "spawn::tests::test_spawn_cwd_child"])
+            .env("REEXEC_TEST_SPAWN_CWD", "1")
+            .current_dir("/");
+
+        let status = SpawnOptions::new()
+            .reexec(reexec)
+            .exe_handle(crate::ExeHandle::open().unwrap())
+            .stdout(Stdio::null())
+            .spawn()
+            .unwrap()
+            .wait()
+            .unwrap();
+        assert!(status.success());
+    }
+
+    #[test]
+    fn test_spawn_error() {
+        let mut reexec = Reexec::new();
+        reexec.strategies(vec![]);
+
+        let err = SpawnOptions::new().reexec(reexec).spawn().unwrap_err();
+        assert_eq!(err.raw_os_error(), Some(libc::ENOENT));
+    }
+}
@@ -0,0 +1,321 @@
+use std::fmt;
+use std::hash::{Hash, Hasher};
+use std::sync::atomic::{AtomicPtr, Ordering};
+use std::sync::Mutex;
+
+/// A user-defined method of locating the current program's executable.
+///
+/// These can be used with [`Strategy::Custom`].
+#[cfg_attr(docsrs, doc(cfg(unix)))]
+#[cfg(unix)]
+pub trait CustomStrategy: Sync {
+    /// Get a short human-readable description of this strategy.
+    fn name(&self) -> &'static str;
+
+    /// Try to get a path to the current program's executable.
+    ///
+    /// On success, the path should be written to `buf`, followed by a NUL byte, and the length of
+    /// the path (excluding the NUL byte) should be returned. On failure, an `errno` value should
+    /// be returned.
+    ///
+    /// If this strategy is going to be used with [`reexecve()`](crate::reexecve), this method must
+    /// be async-signal-safe.
+    fn resolve(&self, buf: &mut [u8]) -> Result<usize, i32>;
+
+    /// Used to compare custom strategies; this should not be overridden.
+    #[doc(hidden)]
+    #[inline]
+    fn custom_type_id(&self) -> std::any::TypeId
+    where
+        Self: 'static,
+    {
+        std::any::TypeId::of::<Self>()
+    }
+}
+
+/// A method of locating the current program's executable.
+///
+/// Not all strategies are available on all platforms; see [`Strategy::DEFAULT`] for the ones that
+/// are supported on the current platform. The list of strategies that are actually tried can be
+/// changed with [`set_strategies()`].
+#[derive(Copy, Clone)]
+#[non_exhaustive]
+pub enum Strategy {
+    /// A special file under `/proc` (for example, `/proc/self/exe` on Linux).
+    ///
+    /// On some platforms, this may refer to the original executable even if it has been deleted
+    /// or renamed.
+    Procfs,
+    /// Like [`Strategy::Procfs`], but first verifies that `/proc` can be trusted.
+    ///
+    /// This checks that the filesystem mounted at `/proc` is really procfs (`PROC_SUPER_MAGIC`),
+    /// and that `/proc/self` refers to the current process. Inside containers, `/proc` may be a
+    /// bind mount, a fake filesystem, or another PID namespace's procfs; in that case, this
+    /// strategy fails with `EXDEV`.
+    ///
+    /// This is only supported on Linux and Android, and it isn't used by default. To also protect
+    /// against `/proc/self/exe` being over-mounted, see
+    /// [`ExeHandle::open_private_procfs()`](crate::ExeHandle::open_private_procfs).
+    VerifiedProcfs,
+    /// An OS-specific kernel interface that reports the executable's path.
+    ///
+    /// This is `sysctl(KERN_PROC_PATHNAME)` on FreeBSD/DragonFlyBSD/NetBSD, `proc_pidpath()` on
+    /// macOS, `sys:exe` on Redox, and `QueryFullProcessImageNameW()` on Windows.
+    ProcInfo,
+    /// The path the program was started with, as recorded by the kernel.
+    ///
+    /// This is `getauxval(AT_EXECFN)` on Linux and `getexecname()` on Solaris/illumos.
+    ///
+    /// On Linux, if the program was started with a relative path, it's resolved against the
+    /// working directory the program was started in, which is recorded by [`init()`](crate::init)
+    /// (or, with the `launch-context` feature, before `main()` runs); if neither happened, a
+    /// relative path is never used. The result is only used if its device ID and inode number still match the running image.
+    /// If `/proc` isn't available at startup, the running image's identity can't be read
+    /// directly; instead, the file that the path referred to at startup is used, and only if its
+    /// GNU build ID matches the running image's. (Executables linked without a build ID can't be
+    /// found this way.)
+    AuxExecFn,
+    /// The path the program was started with, as retrieved by `elf_aux_info(AT_EXECPATH)` on
+    /// FreeBSD.
+    AuxExecPath,
+    /// `argv[0]`, after verifying that it refers to the current executable (OpenBSD).
+    Argv0,
+    /// A user-defined strategy.
+    ///
+    /// Two custom strategies are considered equal if they refer to the same object, viewed as
+    /// the same type. (Different zero-sized types may be placed at the same address, so they are
+    /// told apart by their vtables.)
+    #[cfg_attr(docsrs, doc(cfg(unix)))]
+    #[cfg(unix)]
+    Custom(&'static dyn CustomStrategy),
+}
+
+impl Strategy {
+    /// The built-in strategies supported on the current platform, in the order they are tried by
+    /// default.
+    pub const DEFAULT: &'static [Strategy] = crate::imp::DEFAULT_STRATEGIES;
+
+    /// Check whether this strategy is supported on the current platform.
+    ///
+    /// Custom strategies are always considered supported.
+    #[inline]
+    pub fn is_supported(self) -> bool {
+        #[cfg(unix)]
+        if let Self::Custom(_) = self {
+            return true;
+        }
+
+        if let Self::VerifiedProcfs = self {
+            return cfg!(any(target_os = "linux", target_os = "android"));
+        }
+
+        Self::DEFAULT.contains(&self)
+    }
+
+    /// Get a short human-readable description of the mechanism this strategy uses on the current
+    /// platform.
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::Procfs => "procfs",
+            Self::VerifiedProcfs => "procfs (verified)",
+            Self::ProcInfo => {
+                if cfg!(any(target_os = "macos", target_os = "ios")) {
+                    "proc_pidpath()"
+                } else if cfg!(target_os = "redox") {
+                    "sys:exe"
+                } else if cfg!(windows) {
+                    "QueryFullProcessImageNameW()"
+                } else {
+                    "sysctl(KERN_PROC_PATHNAME)"
+                }
+            }
+            Self::AuxExecFn => {
+                if cfg!(any(target_os = "solaris", target_os = "illumos")) {
+                    "getexecname()"
+                } else {
+                    "AT_EXECFN"
+                }
+            }
+            Self::AuxExecPath => "AT_EXECPATH",
+            Self::Argv0 => "argv[0]",
+            #[cfg(unix)]
+            Self::Custom(custom) => custom.name(),
+        }
+    }
+
+    /// A value that identifies this strategy, for comparisons and hashing.
+    ///
+    /// For custom strategies, this only includes the address of the object. Zero-sized objects
+    /// may share an address, so [`PartialEq`] also compares the types.
+    #[inline]
+    fn key(self) -> (u8, usize) {
+        match self {
+            Self::Procfs => (0, 0),
+            Self::ProcInfo => (1, 0),
+            Self::AuxExecFn => (2, 0),
+            Self::AuxExecPath => (3, 0),
+            Self::Argv0 => (4, 0),
+            Self::VerifiedProcfs => (6, 0),
+            #[cfg(unix)]
+            Self::Custom(custom) => (5, custom as *const dyn CustomStrategy as *const u8 as usize),
+        }
+    }
+}
+
+impl PartialEq for Strategy {
+    #[inline]
+    fn eq(&self, other: &Self) -> bool {
+        match (self, other) {
+            #[cfg(unix)]
+            (Self::Custom(a), Self::Custom(b)) => {
+                self.key() == other.key() && a.custom_type_id() == b.custom_type_id()
+            }
+            _ => self.key() == other.key(),
+        }
+    }
+}
+
+impl Eq for Strategy {}
+
+impl Hash for Strategy {
+    #[inline]
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.key().hash(state);
+    }
+}
+
+impl fmt::Debug for Strategy {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::Procfs => f.write_str("Procfs"),
+            Self::VerifiedProcfs => f.write_str("VerifiedProcfs"),
+            Self::ProcInfo => f.write_str("ProcInfo"),
+            Self::AuxExecFn => f.write_str("AuxExecFn"),
+            Self::AuxExecPath => f.write_str("AuxExecPath"),
+            Self::Argv0 => f.write_str("Argv0"),
+            #[cfg(unix)]
+            Self::Custom(custom) => f.debug_tuple("Custom").field(&custom.name()).finish(),
+        }
+    }
+}
+
+impl fmt::Display for Strategy {
+    #[inline]
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+static STRATEGIES: AtomicPtr<&'static [Strategy]> = AtomicPtr::new(std::ptr::null_mut());
+
+/// Every list that has been passed to [`set_strategies()`], so each one is only allocated once.
+static REGISTERED: Mutex<Vec<&'static &'static [Strategy]>> = Mutex::new(Vec::new());
+
+/// Get the list of strategies that are currently used to locate the executable, in order.
+///
+/// This is [`Strategy::DEFAULT`] unless it has been changed with [`set_strategies()`].
+///
+/// This function is async-signal-safe.
+#[inline]
+pub fn strategies() -> &'static [Strategy] {
+    let ptr = STRATEGIES.load(Ordering::Acquire);
+    if ptr.is_null() {
+        Strategy::DEFAULT
+    } else {
+        unsafe { *ptr }
+    }
+}
+
+/// Change the list of strategies that are used to locate the executable.
+///
+/// The strategies are tried in the order given, by [`reexecve()`](crate::reexecve),
+/// [`get_reexec_path()`](crate::get_reexec_path), [`get_exe_path()`](crate::get_exe_path),
+/// [`diagnose()`](crate::diagnose), and [`Reexec`](crate::Reexec) (unless overridden with
+/// [`Reexec::strategies()`](crate::Reexec::strategies)). Strategies that aren't supported on the
+/// current platform fail with `ENOSYS`.
+///
+/// This is intended to be called early in the program's startup. Since it cannot be known
+/// whether another thread (or a signal handler) is still using the previous list, a small amount
+/// of memory is allocated (and never freed) the first time each distinct list is passed; calling
+/// this again with a list that was used before does not allocate.
+pub fn set_strategies(strategies: &'static [Strategy]) {
+    let mut registered = REGISTERED.lock().unwrap_or_else(|e| e.into_inner());
+
+    let entry = match registered
+        .iter()
+        .find(|&&entry| std::ptr::eq(*entry, strategies))
+    {
+        Some(&entry) => entry,
+        None => {
+            let entry: &'static &'static [Strategy] = Box::leak(Box::new(strategies));
+            registered.push(entry);
+            entry
+        }
+    };
+
+    STRATEGIES.store(entry as *const _ as *mut _, Ordering::Release);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_default_supported() {
+        for &strategy in Strategy::DEFAULT {
+            assert!(strategy.is_supported());
+            assert!(!strategy.name().is_empty());
+            assert_eq!(strategy.to_string(), strategy.name());
+        }
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_custom_eq() {
+        use std::collections::HashSet;
+
+        struct A;
+        struct B;
+
+        impl CustomStrategy for A {
+            fn name(&self) -> &'static str {
+                "a"
+            }
+
+            fn resolve(&self, _buf: &mut [u8]) -> Result<usize, i32> {
+                Err(libc::ENOENT)
+            }
+        }
+
+        impl CustomStrategy for B {
+            fn name(&self) -> &'static str {
+                "b"
+            }
+
+            fn resolve(&self, _buf: &mut [u8]) -> Result<usize, i32> {
+                Err(libc::EACCES)
+            }
+        }
+
+        let a = Strategy::Custom(&A);
+        let b = Strategy::Custom(&B);
+        assert_eq!(a, a);
+        assert_ne!(a, b);
+        assert_ne!(a, Strategy::Procfs);
+
+        let set: HashSet<Strategy> = [a, b, a].iter().copied().collect();
+        assert_eq!(set.len(), 2);
+    }
+
+    #[test]
+    fn test_set_strategies_reuse() {
+        set_strategies(Strategy::DEFAULT);
+        let ptr = STRATEGIES.load(Ordering::Acquire);
+        let count = REGISTERED.lock().unwrap().len();
+
+        set_strategies(Strategy::DEFAULT);
+        assert_eq!(STRATEGIES.load(Ordering::Acquire), ptr);
+        assert_eq!(REGISTERED.lock().unwrap().len(), count);
+        assert!(std::ptr::eq(strategies(), Strategy::DEFAULT));
+    }
+}
@@ -0,0 +1,372 @@
+use std::convert::TryInto;
+use std::io;
+
+const PT_NOTE: u32 = 4;
+#[cfg(any(
+    target_os = "linux",
+    all(target_os = "android", target_pointer_width = "64"),
+))]
+const PT_PHDR: u32 = 6;
+const NT_GNU_BUILD_ID: u32 = 3;
+
+/// Don't try to read absurdly large note segments
+const MAX_NOTE_SIZE: u64 = 64 * 1024;
+
+fn malformed() -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, "malformed ELF file")
+}
+
+/// The fields of a program header that we care about.
+#[cfg_attr(
+    not(any(
+        target_os = "linux",
+        all(target_os = "android", target_pointer_width = "64"),
+    )),
+    allow(dead_code)
+)]
+struct Phdr {
+    p_type: u32,
+    offset: u64,
+    vaddr: u64,
+    filesz: u64,
+    memsz: u64,
+    align: u64,
+}
+
+struct Reader<F> {
+    read_at: F,
+    is_64: bool,
+    is_le: bool,
+}
+
+impl<F: Fn(u64, &mut [u8]) -> io::Result<()>> Reader<F> {
+    fn u16(&self, data: &[u8]) -> u16 {
+        let data = data[..2].try_into().unwrap();
+        if self.is_le {
+            u16::from_le_bytes(data)
+        } else {
+            u16::from_be_bytes(data)
+        }
+    }
+
+    fn u32(&self, data: &[u8]) -> u32 {
+        let data = data[..4].try_into().unwrap();
+        if self.is_le {
+            u32::from_le_bytes(data)
+        } else {
+            u32::from_be_bytes(data)
+        }
+    }
+
+    fn u64(&self, data: &[u8]) -> u64 {
+        let data = data[..8].try_into().unwrap();
+        if self.is_le {
+            u64::from_le_bytes(data)
+        } else {
+            u64::from_be_bytes(data)
+        }
+    }
+
+    fn phdr(&self, phdr: &[u8]) -> Phdr {
+        if self.is_64 {
+            Phdr {
+                p_type: self.u32(phdr),
+                offset: self.u64(&phdr[8..]),
+                vaddr: self.u64(&phdr[16..]),
+                filesz: self.u64(&phdr[32..]),
+                memsz: self.u64(&phdr[40..]),
+                align: self.u64(&phdr[48..]),
+            }
+        } else {
+            Phdr {
+                p_type: self.u32(phdr),
+                offset: self.u32(&phdr[4..]) as u64,
+                vaddr: self.u32(&phdr[8..]) as u64,
+                filesz: self.u32(&phdr[16..]) as u64,
+                memsz: self.u32(&phdr[20..]) as u64,
+                align: self.u32(&phdr[28..]) as u64,
+            }
+        }
+    }
+
+    /// Search a note segment for the build ID
+    fn search_notes<'a>(&self, notes: &'a [u8], align: u64) -> io::Result<Option<&'a [u8]>> {
+        let align = if align == 8 { 8 } else { 4 };
+        let pad = |n: usize| {
+            n.checked_add(align - 1)
+                .map(|n| n & !(align - 1))
+                .ok_or_else(malformed)
+        };
+
+        let mut notes = notes;
+        while notes.len() >= 12 {
+            let namesz = self.u32(&notes[0..]) as usize;
+            let descsz = self.u32(&notes[4..]) as usize;
+            let ntype = self.u32(&notes[8..]);
+
+            let name_start: usize = 12;
+            let desc_start = name_start.checked_add(pad(namesz)?).ok_or_else(malformed)?;
+            let desc_end = desc_start.checked_add(descsz).ok_or_else(malformed)?;
+            if desc_end > notes.len() {
+                return Ok(None);
+            }
+
+            if ntype == NT_GNU_BUILD_ID && &notes[name_start..name_start + namesz] == b"GNU\0" {
+                return Ok(Some(&notes[desc_start..desc_end]));
+            }
+
+            notes = &notes[std::cmp::min(pad(desc_end)?, notes.len())..];
+        }
+
+        Ok(None)
+    }
+
+    fn build_id(&self) -> io::Result<Option<Vec<u8>>> {
+        let mut ehdr = [0u8; 64];
+        (self.read_at)(0, &mut ehdr[..if self.is_64 { 64 } else { 52 }])?;
+
+        let (phoff, phentsize, phnum) = if self.is_64 {
+            (
+                self.u64(&ehdr[32..]),
+                self.u16(&ehdr[54..]),
+                self.u16(&ehdr[56..]),
+            )
+        } else {
+            (
+                self.u32(&ehdr[28..]) as u64,
+                self.u16(&ehdr[42..]),
+                self.u16(&ehdr[44..]),
+            )
+        };
+
+        let min_phentsize = if self.is_64 { 56 } else { 32 };
+        if (phentsize as usize) < min_phentsize {
+            return Ok(None);
+        }
+
+        let mut phdr = vec![0u8; phentsize as usize];
+        for i in 0..phnum as u64 {
+            let off = i
+                .checked_mul(phentsize as u64)
+                .and_then(|off| off.checked_add(phoff))
+                .ok_or_else(malformed)?;
+            (self.read_at)(off, &mut phdr)?;
+            let phdr = self.phdr(&phdr);
+            if phdr.p_type != PT_NOTE || phdr.filesz > MAX_NOTE_SIZE {
+                continue;
+            }
+
+            let mut notes = vec![0u8; phdr.filesz as usize];
+            (self.read_at)(phdr.offset, &mut notes)?;
+            if let Some(build_id) = self.search_notes(&notes, phdr.align)? {
+                return Ok(Some(build_id.to_vec()));
+            }
+        }
+
+        Ok(None)
+    }
+}
+
+/// Find the GNU build ID (`NT_GNU_BUILD_ID`) of an ELF file.
+///
+/// `read_at(offset, buf)` should fill `buf` with the data at the given offset in the file. If the
+/// file isn't an ELF file, or it doesn't have a build ID, `Ok(None)` is returned.
+pub fn read_build_id<F>(read_at: F) -> io::Result<Option<Vec<u8>>>
+where
+    F: Fn(u64, &mut [u8]) -> io::Result<()>,
+{
+    let mut ident = [0u8; 16];
+    match read_at(0, &mut ident) {
+        Ok(()) => (),
+        Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+        Err(e) => return Err(e),
+    }
+
+    if &ident[..4] != b"\x7fELF" {
+        return Ok(None);
+    }
+
+    let is_64 = match ident[4] {
+        1 => false,
+        2 => true,
+        _ => return Ok(None),
+    };
+    let is_le = match ident[5] {
+        1 => true,
+        2 => false,
+        _ => return Ok(None),
+    };
+
+    match (Reader {
+        read_at,
+        is_64,
+        is_le,
+    })
+    .build_id()
+    {
+        Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => Ok(None),
+        res => res,
+    }
+}
+
+/// Find the GNU build ID of the running image, by reading the note segments that were loaded into
+/// memory along with it.
+///
+/// Unlike reading the build ID from `/proc/self/exe`, this doesn't depend on any filesystem. It
+/// doesn't allocate memory.
+#[cfg(any(
+    target_os = "linux",
+    all(target_os = "android", target_pointer_width = "64"),
+))]
+pub fn running_build_id() -> Option<&'static [u8]> {
+    let (phdrs, phent, phnum) = unsafe {
+        (
+            libc::getauxval(libc::AT_PHDR) as usize,
+            libc::getauxval(libc::AT_PHENT) as usize,
+            libc::getauxval(libc::AT_PHNUM) as usize,
+        )
+    };
+    let min_phent = if cfg!(target_pointer_width = "64") {
+        56
+    } else {
+        32
+    };
+    if phdrs == 0 || phent < min_phent {
+        return None;
+    }
+
+    let reader = Reader {
+        read_at: |_, _: &mut [u8]| Ok(()),
+        is_64: cfg!(target_pointer_width = "64"),
+        is_le: cfg!(target_endian = "little"),
+    };
+    let phdr = |i: usize| {
+        reader.phdr(unsafe { std::slice::from_raw_parts((phdrs + i * phent) as *const u8, phent) })
+    };
+
+    // The difference between the addresses in the program headers and where they were actually
+    // loaded (for position-independent executables)
+    let bias = (0..phnum)
+        .map(phdr)
+        .find(|phdr| phdr.p_type == PT_PHDR)
+        .map(|phdr| phdrs.wrapping_sub(phdr.vaddr as usize))?;
+
+    (0..phnum)
+        .map(phdr)
+        .filter(|phdr| phdr.p_type == PT_NOTE)
+        .find_map(|phdr| {
+            let notes = unsafe {
+                std::slice::from_raw_parts(
+                    bias.wrapping_add(phdr.vaddr as usize) as *const u8,
+                    phdr.memsz as usize,
+                )
+            };
+            reader.search_notes(notes, phdr.align).ok().flatten()
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn read_slice(data: &[u8]) -> impl Fn(u64, &mut [u8]) -> io::Result<()> + '_ {
+        move |offset, buf| {
+            let offset = offset as usize;
+            if offset > data.len() || buf.len() > data.len() - offset {
+                return Err(io::ErrorKind::UnexpectedEof.into());
+            }
+            buf.copy_from_slice(&data[offset..offset + buf.len()]);
+            Ok(())
+        }
+    }
+
+    /// Build a minimal 64-bit little-endian ELF file with a single PT_NOTE segment containing
+    /// the given notes
+    fn build_elf64(notes: &[u8]) -> Vec<u8> {
+        let mut data = vec![0u8; 64 + 56];
+        data[..4].copy_from_slice(b"\x7fELF");
+        data[4] = 2;
+        data[5] = 1;
+        data[32..40].copy_from_slice(&64u64.to_le_bytes());
+        data[54..56].copy_from_slice(&56u16.to_le_bytes());
+        data[56..58].copy_from_slice(&1u16.to_le_bytes());
+
+        let phdr = &mut data[64..];
+        phdr[..4].copy_from_slice(&PT_NOTE.to_le_bytes());
+        phdr[8..16].copy_from_slice(&120u64.to_le_bytes());
+        phdr[32..40].copy_from_slice(&(notes.len() as u64).to_le_bytes());
+        phdr[48..56].copy_from_slice(&4u64.to_le_bytes());
+
+        data.extend_from_slice(notes);
+        data
+    }
+
+    fn note(name: &[u8], ntype: u32, desc: &[u8]) -> Vec<u8> {
+        let mut data = Vec::new();
+        data.extend_from_slice(&(name.len() as u32).to_le_bytes());
+        data.extend_from_slice(&(desc.len() as u32).to_le_bytes());
+        data.extend_from_slice(&ntype.to_le_bytes());
+        data.extend_from_slice(name);
+        while data.len() % 4 != 0 {
+            data.push(0);
+        }
+        data.extend_from_slice(desc);
+        while data.len() % 4 != 0 {
+            data.push(0);
+        }
+        data
+    }
+
+    #[test]
+    fn test_read_build_id() {
+        let mut notes = note(b"GNU\0", 1, &[0; 16]);
+        notes.extend(note(b"XYZ\0", NT_GNU_BUILD_ID, b"abc"));
+        notes.extend(note(b"GNU\0", NT_GNU_BUILD_ID, b"\x01\x02\x03\x04\x05"));
+        let elf = build_elf64(&notes);
+        assert_eq!(
+            read_build_id(read_slice(&elf)).unwrap(),
+            Some(vec![1, 2, 3, 4, 5])
+        );
+
+        let elf = build_elf64(&note(b"GNU\0", 1, &[0; 16]));
+        assert_eq!(read_build_id(read_slice(&elf)).unwrap(), None);
+
+        // Truncated/non-ELF files
+        assert_eq!(read_build_id(read_slice(&elf[..100])).unwrap(), None);
+        assert_eq!(read_build_id(read_slice(b"#!/bin/sh\n")).unwrap(), None);
+        assert_eq!(read_build_id(read_slice(b"")).unwrap(), None);
+    }
+
+    #[cfg(any(
+        target_os = "linux",
+        all(target_os = "android", target_pointer_width = "64"),
+    ))]
+    #[test]
+    fn test_running_build_id() {
+        let file = std::fs::File::open("/proc/self/exe").unwrap();
+        assert_eq!(
+            running_build_id().map(|id| id.to_vec()),
+            crate::imp::file_build_id(&file).unwrap()
+        );
+    }
+
+    #[test]
+    fn test_read_build_id_overflow() {
+        // Program headers that run past the end of the address space
+        let mut elf = build_elf64(&[]);
+        elf[32..40].copy_from_slice(&(u64::MAX - 10).to_le_bytes());
+        elf[56..58].copy_from_slice(&2u16.to_le_bytes());
+        let ehdr = &elf[..64];
+
+        let err = read_build_id(|offset, buf: &mut [u8]| {
+            if offset == 0 {
+                buf.copy_from_slice(&ehdr[..buf.len()]);
+            } else {
+                buf.iter_mut().for_each(|b| *b = 0);
+            }
+            Ok(())
+        })
+        .unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    }
+}
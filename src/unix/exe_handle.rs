@@ -0,0 +1,356 @@
+use std::ffi::CStr;
+use std::io;
+use std::os::unix::prelude::*;
+
+use super::{errno, resolve, sys, Mode};
+use crate::{ReexecError, Strategy};
+
+/// A handle to the current program's executable, opened ahead of time.
+///
+/// This is created with [`ExeHandle::open()`], ideally early in the program's startup. Re-executing
+/// the program through the handle (with [`ExeHandle::reexecve()`] or [`Reexec::exec_handle()`])
+/// uses `execveat(fd, "", argv, envp, AT_EMPTY_PATH)`, which doesn't need to look up any paths. As
+/// a result, it isn't affected by later changes to the filesystem (for example, `/proc` being
+/// unmounted, or the executable being replaced).
+///
+/// [`Reexec::exec_handle()`]: crate::Reexec::exec_handle
+#[derive(Debug)]
+pub struct ExeHandle {
+    fd: RawFd,
+    dev: u64,
+    ino: u64,
+    sealed: bool,
+}
+
+impl ExeHandle {
+    /// Open the current program's executable.
+    ///
+    /// The strategies in [`strategies()`](crate::strategies) are tried in order, and the first
+    /// path that can be opened (with `O_PATH | O_CLOEXEC`) is used.
+    pub fn open() -> io::Result<Self> {
+        Self::open_with(crate::strategies())
+    }
+
+    /// Open the current program's executable using the given list of strategies.
+    pub fn open_with(strategies: &[Strategy]) -> io::Result<Self> {
+        let mut err = ReexecError::new(libc::ENOENT);
+        let mut buf = [0u8; libc::PATH_MAX as usize + 1];
+
+        for &strategy in strategies {
+            let eno = match resolve(strategy, &mut buf, Mode::Exec) {
+                Ok(path) => {
+                    let fd = unsafe {
+                        libc::open(path.as_cstr().as_ptr(), libc::O_PATH | libc::O_CLOEXEC)
+                    };
+                    if fd >= 0 {
+                        // This takes ownership of the file descriptor, so it will be closed if
+                        // this fails
+                        return unsafe { Self::from_fd(fd, false) };
+                    }
+                    errno()
+                }
+                Err(eno) => eno,
+            };
+
+            err.push(strategy, eno);
+        }
+
+        Err(err.into())
+    }
+
+    /// Open the current program's executable through a private instance of procfs.
+    ///
+    /// This creates a new procfs instance with `fsopen("proc")` and `fsmount()` that isn't
+    /// attached anywhere in the filesystem, and opens `self/exe` relative to it. Unlike
+    /// [`Strategy::VerifiedProcfs`], this can't be redirected by anything mounted over `/proc` or
+    /// `/proc/self/exe`.
+    ///
+    /// This requires Linux 5.2 or later, and permission to mount filesystems (`CAP_SYS_ADMIN` in
+    /// the user namespace that owns the current mount namespace). Callers will usually want to
+    /// fall back on [`ExeHandle::open()`] if this fails.
+    pub fn open_private_procfs() -> io::Result<Self> {
+        let fsfd =
+            unsafe { libc::syscall(libc::SYS_fsopen, b"proc\0".as_ptr(), sys::FSOPEN_CLOEXEC) }
+                as RawFd;
+        if fsfd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        let mntfd = unsafe {
+            if libc::syscall(
+                libc::SYS_fsconfig,
+                fsfd,
+                sys::FSCONFIG_CMD_CREATE,
+                std::ptr::null::<libc::c_char>(),
+                std::ptr::null::<libc::c_void>(),
+                0,
+            ) < 0
+            {
+                -1
+            } else {
+                libc::syscall(libc::SYS_fsmount, fsfd, sys::FSMOUNT_CLOEXEC, 0) as RawFd
+            }
+        };
+        let err = io::Error::last_os_error();
+        unsafe {
+            libc::close(fsfd);
+        }
+        if mntfd < 0 {
+            return Err(err);
+        }
+
+        let fd = unsafe {
+            libc::openat(
+                mntfd,
+                b"self/exe\0".as_ptr() as *const _,
+                libc::O_PATH | libc::O_CLOEXEC,
+            )
+        };
+        let err = io::Error::last_os_error();
+        unsafe {
+            libc::close(mntfd);
+        }
+        if fd < 0 {
+            return Err(err);
+        }
+
+        unsafe { Self::from_fd(fd, false) }
+    }
+
+    /// Create a handle from an open file descriptor, taking ownership of it.
+    ///
+    /// If `sealed` is true, the file descriptor refers to a sealed copy of the executable, and
+    /// `reexecve()` will never fall back on the normal strategies.
+    #[allow(clippy::unnecessary_cast)]
+    pub(crate) unsafe fn from_fd(fd: RawFd, sealed: bool) -> io::Result<Self> {
+        let mut st = std::mem::MaybeUninit::uninit();
+        if libc::fstat(fd, st.as_mut_ptr()) < 0 {
+            let err = io::Error::last_os_error();
+            libc::close(fd);
+            return Err(err);
+        }
+        let st = st.assume_init();
+
+        if st.st_mode as libc::mode_t & libc::S_IFMT != libc::S_IFREG {
+            libc::close(fd);
+            return Err(io::Error::from_raw_os_error(libc::EACCES));
+        }
+
+        Ok(Self {
+            fd,
+            dev: st.st_dev as u64,
+            ino: st.st_ino as u64,
+            sealed,
+        })
+    }
+
+    /// Check whether this handle refers to a sealed copy of the executable (see
+    /// [`snapshot_to_memfd()`](crate::snapshot_to_memfd)).
+    #[inline]
+    pub fn is_sealed(&self) -> bool {
+        self.sealed
+    }
+
+    /// Get the device ID of the executable file that this handle refers to.
+    #[inline]
+    pub fn dev(&self) -> u64 {
+        self.dev
+    }
+
+    /// Get the inode number of the executable file that this handle refers to.
+    #[inline]
+    pub fn ino(&self) -> u64 {
+        self.ino
+    }
+
+    /// Get the `/proc/self/fd/<fd>` path of this handle's file descriptor, formatted into `buf`.
+    #[inline]
+    pub(crate) fn proc_path<'a>(&self, buf: &'a mut [u8; 32]) -> &'a CStr {
+        fd_path(self.fd, buf)
+    }
+
+    /// Re-execute the program that this handle refers to with the specified `argv` and `envp`.
+    ///
+    /// This uses `execveat(AT_EMPTY_PATH)`. If the kernel doesn't support `execveat()`, this
+    /// falls back on [`reexecve()`](crate::reexecve) with the strategies in
+    /// [`strategies()`](crate::strategies) (or, for [sealed](Self::is_sealed) handles,
+    /// on executing the file descriptor through `/proc/self/fd`, like `fexecve()`).
+    ///
+    /// This function is async-signal-safe.
+    ///
+    /// # Safety
+    ///
+    /// See [`reexecve()`](crate::reexecve).
+    pub unsafe fn reexecve(
+        &self,
+        argv: *const *const libc::c_char,
+        envp: *const *const libc::c_char,
+    ) -> ReexecError {
+        self.reexecve_via(self.fd, crate::strategies(), argv, envp)
+    }
+
+    /// Like `reexecve()`, but execute `fd` (which must be a duplicate of this handle's file
+    /// descriptor) instead, and fall back on the given `strategies`. Used when the original file
+    /// descriptor has been clobbered by `place_fds()`.
+    pub(crate) unsafe fn reexecve_via(
+        &self,
+        fd: RawFd,
+        strategies: &[Strategy],
+        argv: *const *const libc::c_char,
+        envp: *const *const libc::c_char,
+    ) -> ReexecError {
+        if self.sealed {
+            return execveat_sealed(fd, argv, envp);
+        }
+
+        let eno = execveat_empty(fd, argv, envp);
+        if eno == libc::ENOSYS {
+            super::reexecve(strategies, argv, envp)
+        } else {
+            ReexecError::new(eno)
+        }
+    }
+}
+
+/// Call `execveat(fd, "", argv, envp, AT_EMPTY_PATH)` and return the resulting error.
+unsafe fn execveat_empty(
+    fd: RawFd,
+    argv: *const *const libc::c_char,
+    envp: *const *const libc::c_char,
+) -> i32 {
+    libc::syscall(
+        libc::SYS_execveat,
+        fd,
+        b"\0".as_ptr(),
+        argv,
+        envp,
+        libc::AT_EMPTY_PATH,
+    );
+    errno()
+}
+
+/// Format `/proc/self/fd/<fd>` into the given buffer.
+fn fd_path(fd: RawFd, buf: &mut [u8; 32]) -> &CStr {
+    const PREFIX: &[u8] = b"/proc/self/fd/";
+
+    let mut digits = [0u8; 10];
+    let mut ndigits = 0;
+    let mut n = fd as u32;
+    loop {
+        digits[ndigits] = b'0' + (n % 10) as u8;
+        ndigits += 1;
+        n /= 10;
+        if n == 0 {
+            break;
+        }
+    }
+
+    buf[..PREFIX.len()].copy_from_slice(PREFIX);
+    for (i, &digit) in digits[..ndigits].iter().rev().enumerate() {
+        buf[PREFIX.len() + i] = digit;
+    }
+    let len = PREFIX.len() + ndigits;
+    buf[len] = 0;
+
+    unsafe { CStr::from_bytes_with_nul_unchecked(&buf[..=len]) }
+}
+
+/// Execute the (sealed) file referred to by `fd`, without ever falling back on the normal
+/// strategies.
+///
+/// This function is async-signal-safe.
+pub unsafe fn execveat_sealed(
+    fd: RawFd,
+    argv: *const *const libc::c_char,
+    envp: *const *const libc::c_char,
+) -> ReexecError {
+    let mut eno = execveat_empty(fd, argv, envp);
+
+    if eno == libc::ENOSYS {
+        let mut buf = [0; 32];
+        libc::execve(fd_path(fd, &mut buf).as_ptr(), argv, envp);
+        eno = errno();
+    }
+
+    ReexecError::new(eno)
+}
+
+impl AsRawFd for ExeHandle {
+    #[inline]
+    fn as_raw_fd(&self) -> RawFd {
+        self.fd
+    }
+}
+
+impl IntoRawFd for ExeHandle {
+    #[inline]
+    fn into_raw_fd(self) -> RawFd {
+        let fd = self.fd;
+        std::mem::forget(self);
+        fd
+    }
+}
+
+impl Drop for ExeHandle {
+    #[inline]
+    fn drop(&mut self) {
+        unsafe {
+            libc::close(self.fd);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_open() {
+        let handle = ExeHandle::open().unwrap();
+        let meta = std::fs::metadata(std::env::current_exe().unwrap()).unwrap();
+        assert_eq!(handle.dev(), meta.dev());
+        assert_eq!(handle.ino(), meta.ino());
+
+        let flags = unsafe { libc::fcntl(handle.as_raw_fd(), libc::F_GETFD) };
+        assert_ne!(flags & libc::FD_CLOEXEC, 0);
+
+        let err = ExeHandle::open_with(&[]).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::NotFound);
+    }
+
+    #[test]
+    fn test_open_private_procfs() {
+        let handle = match ExeHandle::open_private_procfs() {
+            Ok(handle) => handle,
+            // Unsupported kernel or insufficient privileges
+            Err(e) if matches!(e.raw_os_error(), Some(libc::ENOSYS) | Some(libc::EPERM)) => return,
+            Err(e) => panic!("{}", e),
+        };
+
+        let meta = std::fs::metadata(std::env::current_exe().unwrap()).unwrap();
+        assert_eq!(handle.dev(), meta.dev());
+        assert_eq!(handle.ino(), meta.ino());
+    }
+
+    #[test]
+    fn test_fd_path() {
+        let mut buf = [0; 32];
+        for &(fd, path) in &[
+            (0, "/proc/self/fd/0"),
+            (7, "/proc/self/fd/7"),
+            (10, "/proc/self/fd/10"),
+            (1234, "/proc/self/fd/1234"),
+            (i32::MAX, "/proc/self/fd/2147483647"),
+        ] {
+            assert_eq!(fd_path(fd, &mut buf).to_str().unwrap(), path);
+        }
+    }
+
+    #[test]
+    fn test_reexecve() {
+        let handle = ExeHandle::open().unwrap();
+        crate::tests::check_reexec_child(|argv, envp| unsafe {
+            handle.reexecve(argv, envp);
+        });
+    }
+}
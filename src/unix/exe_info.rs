@@ -0,0 +1,160 @@
+use std::fs;
+use std::io;
+use std::os::unix::prelude::*;
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+
+use super::elf;
+
+/// Identifying information about the image of the currently running program.
+///
+/// This is returned by [`exe_info()`].
+#[derive(Clone, Debug)]
+pub struct ExeInfo {
+    dev: u64,
+    ino: u64,
+    size: u64,
+    mtime: Option<SystemTime>,
+    path: Option<PathBuf>,
+    build_id: Option<Vec<u8>>,
+}
+
+impl ExeInfo {
+    /// Get the device ID of the running image.
+    #[inline]
+    pub fn dev(&self) -> u64 {
+        self.dev
+    }
+
+    /// Get the inode number of the running image.
+    #[inline]
+    pub fn ino(&self) -> u64 {
+        self.ino
+    }
+
+    /// Get the size of the running image, in bytes.
+    #[inline]
+    pub fn size(&self) -> u64 {
+        self.size
+    }
+
+    /// Get the modification time of the running image (if the platform supports it).
+    #[inline]
+    pub fn mtime(&self) -> Option<SystemTime> {
+        self.mtime
+    }
+
+    /// Get the path to the executable, as returned by [`get_exe_path()`](crate::get_exe_path).
+    ///
+    /// This is `None` if the path could not be determined (for example, because the executable
+    /// has been deleted).
+    #[inline]
+    pub fn path(&self) -> Option<&Path> {
+        self.path.as_deref()
+    }
+
+    /// Get the GNU build ID (`NT_GNU_BUILD_ID`) of the running image.
+    ///
+    /// This is `None` if the executable isn't an ELF file, or if it was linked without a build
+    /// ID.
+    #[inline]
+    pub fn build_id(&self) -> Option<&[u8]> {
+        self.build_id.as_deref()
+    }
+
+    /// Check whether the executable has been replaced on disk since the program started.
+    ///
+    /// This looks up the executable with [`get_exe_path()`](crate::get_exe_path) and compares
+    /// the device ID, inode number, size, and modification time of the file it finds against the
+    /// running image. If the executable has been deleted, this returns `Ok(true)`.
+    ///
+    /// Note that an executable that has been modified in place (rather than replaced) will also
+    /// be detected, unless the modification preserved both its size and its modification time.
+    pub fn is_replaced_on_disk(&self) -> io::Result<bool> {
+        let path = match crate::get_exe_path() {
+            Ok(path) => path,
+            Err(err) if err.is_deleted() => return Ok(true),
+            Err(err) => return Err(err.into()),
+        };
+
+        let meta = match fs::metadata(&path) {
+            Ok(meta) => meta,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(true),
+            Err(e) => return Err(e),
+        };
+
+        Ok(meta.dev() != self.dev
+            || meta.ino() != self.ino
+            || meta.len() != self.size
+            || meta.modified().ok() != self.mtime)
+    }
+}
+
+/// Open the image of the currently running program.
+fn open_running_image() -> io::Result<fs::File> {
+    // On Linux, /proc/self/exe refers to the running image even if the executable has been
+    // deleted or replaced. Elsewhere, get_reexec_path() is the best we can do (and on some
+    // platforms, like OpenBSD, it has already verified that the file is the running image).
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    return fs::File::open("/proc/self/exe");
+
+    #[cfg(not(any(target_os = "linux", target_os = "android")))]
+    return fs::File::open(crate::get_reexec_path()?);
+}
+
+/// Find the GNU build ID of the given (ELF) file, if it has one.
+pub fn file_build_id(file: &fs::File) -> io::Result<Option<Vec<u8>>> {
+    elf::read_build_id(|offset, buf| file.read_exact_at(buf, offset))
+}
+
+/// Get identifying information about the image of the currently running program.
+///
+/// On Linux and Android, the device ID, inode number, size, and modification time are retrieved
+/// by `fstat()`ing `/proc/self/exe`, so they always describe the running image. On other
+/// platforms, they describe the file found by [`get_reexec_path()`](crate::get_reexec_path).
+///
+/// The resulting [`ExeInfo`] can later be used to check whether the executable has been replaced
+/// (for example, by an upgrade) with [`ExeInfo::is_replaced_on_disk()`].
+pub fn exe_info() -> io::Result<ExeInfo> {
+    let file = open_running_image()?;
+    let meta = file.metadata()?;
+
+    let build_id = file_build_id(&file)?;
+
+    Ok(ExeInfo {
+        dev: meta.dev(),
+        ino: meta.ino(),
+        size: meta.len(),
+        mtime: meta.modified().ok(),
+        path: crate::get_exe_path().ok().map(|path| path.into_owned()),
+        build_id,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_exe_info() {
+        let info = exe_info().unwrap();
+
+        let exe = std::env::current_exe().unwrap();
+        let meta = fs::metadata(&exe).unwrap();
+        assert_eq!(info.dev(), meta.dev());
+        assert_eq!(info.ino(), meta.ino());
+        assert_eq!(info.size(), meta.len());
+        assert_eq!(info.mtime(), meta.modified().ok());
+        assert_eq!(info.path(), Some(exe.as_path()));
+
+        if let Some(build_id) = info.build_id() {
+            assert!(!build_id.is_empty());
+        }
+
+        assert!(!info.is_replaced_on_disk().unwrap());
+
+        let mut info = info;
+        info.ino = info.ino.wrapping_add(1);
+        assert!(info.is_replaced_on_disk().unwrap());
+    }
+}
@@ -0,0 +1,471 @@
+use std::os::unix::prelude::*;
+
+use super::errno;
+#[cfg(any(target_os = "linux", target_os = "android"))]
+use super::sys;
+
+/// Set `FD_CLOEXEC` on a single file descriptor. EBADF is ignored.
+///
+/// This function is async-signal-safe.
+fn set_cloexec(fd: RawFd) -> Result<(), i32> {
+    unsafe {
+        let flags = libc::fcntl(fd, libc::F_GETFD);
+        if flags < 0 {
+            return match errno() {
+                libc::EBADF => Ok(()),
+                eno => Err(eno),
+            };
+        }
+
+        if flags & libc::FD_CLOEXEC == 0
+            && libc::fcntl(fd, libc::F_SETFD, flags | libc::FD_CLOEXEC) < 0
+        {
+            return Err(errno());
+        }
+    }
+
+    Ok(())
+}
+
+/// Iterate over the ranges of file descriptors above `min_fd` that are not in `keep` (which must
+/// be sorted). The last range extends to `RawFd::MAX`.
+fn for_each_range<F>(min_fd: RawFd, keep: &[RawFd], mut f: F) -> Result<(), i32>
+where
+    F: FnMut(RawFd, RawFd) -> Result<(), i32>,
+{
+    let mut start = min_fd.saturating_add(1).max(0);
+
+    for &fd in keep {
+        if fd < start {
+            continue;
+        }
+        if fd > start {
+            f(start, fd - 1)?;
+        }
+        if fd == RawFd::MAX {
+            return Ok(());
+        }
+        start = fd + 1;
+    }
+
+    f(start, RawFd::MAX)
+}
+
+/// Set `FD_CLOEXEC` on every file descriptor above `min_fd` except those in `keep`, using
+/// `close_range(CLOSE_RANGE_CLOEXEC)`.
+#[cfg(any(target_os = "linux", target_os = "android"))]
+fn cloexec_close_range(min_fd: RawFd, keep: &[RawFd]) -> Result<(), i32> {
+    for_each_range(min_fd, keep, |first, last| {
+        if unsafe {
+            libc::syscall(
+                libc::SYS_close_range,
+                first as libc::c_uint,
+                last as libc::c_uint,
+                sys::CLOSE_RANGE_CLOEXEC,
+            )
+        } < 0
+        {
+            Err(errno())
+        } else {
+            Ok(())
+        }
+    })
+}
+
+/// Set `FD_CLOEXEC` on every file descriptor above `min_fd` except those in `keep`, by walking
+/// `/proc/self/fd` (with `getdents64()`, which, unlike `readdir()`, doesn't allocate).
+#[cfg(any(target_os = "linux", target_os = "android"))]
+fn cloexec_procfs(min_fd: RawFd, keep: &[RawFd]) -> Result<(), i32> {
+    let dirfd = unsafe {
+        libc::open(
+            b"/proc/self/fd\0".as_ptr() as *const _,
+            libc::O_RDONLY | libc::O_DIRECTORY | libc::O_CLOEXEC,
+        )
+    };
+    if dirfd < 0 {
+        return Err(errno());
+    }
+
+    let res = (|| {
+        let mut buf = [0u64; 256];
+        loop {
+            let n = unsafe {
+                libc::syscall(
+                    libc::SYS_getdents64,
+                    dirfd,
+                    buf.as_mut_ptr(),
+                    std::mem::size_of_val(&buf),
+                )
+            };
+            if n < 0 {
+                return Err(errno());
+            } else if n == 0 {
+                return Ok(());
+            }
+
+            let data = unsafe { std::slice::from_raw_parts(buf.as_ptr() as *const u8, n as usize) };
+            let mut off = 0;
+            while off < data.len() {
+                // struct linux_dirent64: d_ino (8), d_off (8), d_reclen (2), d_type (1), d_name
+                let reclen = u16::from_ne_bytes([data[off + 16], data[off + 17]]) as usize;
+                let name = &data[off + 19..off + reclen];
+                off += reclen;
+
+                if let Some(fd) = parse_fd(name) {
+                    if fd > min_fd && fd != dirfd && keep.binary_search(&fd).is_err() {
+                        set_cloexec(fd)?;
+                    }
+                }
+            }
+        }
+    })();
+
+    unsafe {
+        libc::close(dirfd);
+    }
+    res
+}
+
+/// Parse a NUL-terminated file descriptor number.
+#[cfg(any(target_os = "linux", target_os = "android"))]
+fn parse_fd(name: &[u8]) -> Option<RawFd> {
+    let mut fd: RawFd = 0;
+    let mut ndigits = 0;
+
+    for &ch in name {
+        match ch {
+            b'0'..=b'9' => {
+                fd = fd.checked_mul(10)?.checked_add((ch - b'0') as RawFd)?;
+                ndigits += 1;
+            }
+            0 => break,
+            _ => return None,
+        }
+    }
+
+    if ndigits > 0 {
+        Some(fd)
+    } else {
+        None
+    }
+}
+
+/// Set `FD_CLOEXEC` on every file descriptor above `min_fd` (up to the `RLIMIT_NOFILE` limit)
+/// except those in `keep`, one at a time.
+fn cloexec_brute_force(min_fd: RawFd, keep: &[RawFd]) -> Result<(), i32> {
+    let mut rlim = std::mem::MaybeUninit::uninit();
+    let max_fd = if unsafe { libc::getrlimit(libc::RLIMIT_NOFILE, rlim.as_mut_ptr()) } == 0 {
+        let rlim = unsafe { rlim.assume_init() };
+        std::cmp::min(rlim.rlim_cur, 65536) as RawFd
+    } else {
+        1024
+    };
+
+    for_each_range(min_fd, keep, |first, last| {
+        for fd in first..=std::cmp::min(last, max_fd - 1) {
+            set_cloexec(fd)?;
+        }
+        Ok(())
+    })
+}
+
+/// Set `FD_CLOEXEC` on every file descriptor above `min_fd` except those in `keep` (which must be
+/// sorted), so they won't be inherited across `execve()`.
+///
+/// This uses `close_range(CLOSE_RANGE_CLOEXEC)` if the kernel supports it, falling back on walking
+/// `/proc/self/fd`, and finally on trying every file descriptor up to the `RLIMIT_NOFILE` limit.
+///
+/// This function is async-signal-safe.
+pub fn cloexec_above(min_fd: RawFd, keep: &[RawFd]) -> Result<(), i32> {
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    {
+        match cloexec_close_range(min_fd, keep) {
+            Err(libc::ENOSYS) | Err(libc::EINVAL) => (),
+            res => return res,
+        }
+
+        match cloexec_procfs(min_fd, keep) {
+            Err(libc::ENOENT) | Err(libc::EACCES) => (),
+            res => return res,
+        }
+    }
+
+    cloexec_brute_force(min_fd, keep)
+}
+
+/// The first file descriptor used for passing file descriptors with `LISTEN_FDS`.
+pub const LISTEN_FDS_START: RawFd = 3;
+
+/// File descriptors that have been moved into place by `place_fds()`.
+pub struct PlacedFds {
+    /// For each target file descriptor, a close-on-exec copy of whatever was open there before
+    /// (or -1), and whether that file descriptor had `FD_CLOEXEC` set.
+    saved: Vec<(RawFd, bool)>,
+}
+
+impl PlacedFds {
+    /// Translate a file descriptor that was open before `place_fds()` was called into one that
+    /// still refers to the same file.
+    pub fn translate(&self, fd: RawFd) -> RawFd {
+        match fd.checked_sub(LISTEN_FDS_START) {
+            Some(i) if (i as usize) < self.saved.len() && self.saved[i as usize].0 >= 0 => {
+                self.saved[i as usize].0
+            }
+            _ => fd,
+        }
+    }
+
+    /// Put back whatever was open at the target file descriptors before `place_fds()` was called.
+    pub fn restore(self) {
+        for (i, &(saved, cloexec)) in self.saved.iter().enumerate() {
+            let target = LISTEN_FDS_START + i as RawFd;
+            unsafe {
+                if saved >= 0 {
+                    libc::dup2(saved, target);
+                    if cloexec {
+                        libc::fcntl(target, libc::F_SETFD, libc::FD_CLOEXEC);
+                    }
+                    libc::close(saved);
+                } else {
+                    libc::close(target);
+                }
+            }
+        }
+    }
+}
+
+/// Duplicate the given file descriptors into consecutive file descriptors starting at
+/// `LISTEN_FDS_START`, without `FD_CLOEXEC` set, so they'll be inherited by the new program.
+///
+/// Anything that was already open at those file descriptors is saved first, so it can be put back
+/// with `PlacedFds::restore()` (and it can be accessed in the meantime with
+/// `PlacedFds::translate()`).
+pub fn place_fds(sources: &[RawFd]) -> Result<PlacedFds, i32> {
+    let end = LISTEN_FDS_START + sources.len() as RawFd;
+    let mut placed = PlacedFds {
+        saved: Vec::with_capacity(sources.len()),
+    };
+
+    for target in LISTEN_FDS_START..end {
+        let flags = unsafe { libc::fcntl(target, libc::F_GETFD) };
+        let saved = if flags < 0 {
+            -1
+        } else {
+            let saved = unsafe { libc::fcntl(target, libc::F_DUPFD_CLOEXEC, end) };
+            if saved < 0 {
+                let eno = errno();
+                placed.restore_saved();
+                return Err(eno);
+            }
+            saved
+        };
+        placed
+            .saved
+            .push((saved, flags >= 0 && flags & libc::FD_CLOEXEC != 0));
+    }
+
+    for (i, &src) in sources.iter().enumerate() {
+        let src = placed.translate(src);
+        let target = LISTEN_FDS_START + i as RawFd;
+
+        let res = if src == target {
+            // dup2() would do nothing, so clear FD_CLOEXEC manually
+            unsafe { libc::fcntl(target, libc::F_SETFD, 0) }
+        } else {
+            unsafe { libc::dup2(src, target) }
+        };
+        if res < 0 {
+            let eno = errno();
+            placed.restore();
+            return Err(eno);
+        }
+    }
+
+    Ok(placed)
+}
+
+impl PlacedFds {
+    /// Close the saved copies without touching the target file descriptors (used if saving them
+    /// failed partway through).
+    fn restore_saved(&self) {
+        for &(saved, _) in self.saved.iter() {
+            if saved >= 0 {
+                unsafe {
+                    libc::close(saved);
+                }
+            }
+        }
+    }
+}
+
+/// Get a list of the file descriptors that would be inherited across `execve()` (i.e. those
+/// without `FD_CLOEXEC`), along with the files they refer to.
+///
+/// This reads `/proc/self/fd`. The targets are the results of `readlink()`ing the entries there;
+/// for example, a socket will show up as `socket:[12345]`.
+///
+/// This is intended for testing and debugging, to check that no file descriptors leak into the
+/// re-executed program unintentionally.
+#[cfg(any(target_os = "linux", target_os = "android"))]
+pub fn leaked_fds() -> std::io::Result<Vec<(RawFd, std::path::PathBuf)>> {
+    let mut fds = Vec::new();
+
+    for entry in std::fs::read_dir("/proc/self/fd")? {
+        let entry = entry?;
+        let fd = match parse_fd(entry.file_name().as_bytes()) {
+            Some(fd) => fd,
+            None => continue,
+        };
+
+        // The directory file descriptor used by read_dir() will have FD_CLOEXEC set, so it'll be
+        // skipped here
+        let flags = unsafe { libc::fcntl(fd, libc::F_GETFD) };
+        if flags < 0 || flags & libc::FD_CLOEXEC != 0 {
+            continue;
+        }
+
+        match std::fs::read_link(entry.path()) {
+            Ok(target) => fds.push((fd, target)),
+            // Closed by another thread
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => (),
+            Err(e) => return Err(e),
+        }
+    }
+
+    fds.sort_unstable_by_key(|&(fd, _)| fd);
+    Ok(fds)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ranges(min_fd: RawFd, keep: &[RawFd]) -> Vec<(RawFd, RawFd)> {
+        let mut ranges = Vec::new();
+        for_each_range(min_fd, keep, |first, last| {
+            ranges.push((first, last));
+            Ok(())
+        })
+        .unwrap();
+        ranges
+    }
+
+    #[test]
+    fn test_for_each_range() {
+        assert_eq!(ranges(2, &[]), vec![(3, RawFd::MAX)]);
+        assert_eq!(ranges(-1, &[]), vec![(0, RawFd::MAX)]);
+        assert_eq!(ranges(2, &[0, 3]), vec![(4, RawFd::MAX)]);
+        assert_eq!(
+            ranges(2, &[5, 6, 9]),
+            vec![(3, 4), (7, 8), (10, RawFd::MAX)]
+        );
+        assert_eq!(ranges(2, &[RawFd::MAX]), vec![(3, RawFd::MAX - 1)]);
+    }
+
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    #[test]
+    fn test_parse_fd() {
+        assert_eq!(parse_fd(b"0\0"), Some(0));
+        assert_eq!(parse_fd(b"123\0"), Some(123));
+        assert_eq!(parse_fd(b"123"), Some(123));
+        assert_eq!(parse_fd(b".\0"), None);
+        assert_eq!(parse_fd(b"\0"), None);
+        assert_eq!(parse_fd(b"99999999999\0"), None);
+    }
+
+    fn check_cloexec_above<F: Fn(RawFd, &[RawFd]) -> Result<(), i32>>(f: F) {
+        // Use high file descriptor numbers so other tests running in parallel aren't affected
+        let base = unsafe { libc::fcntl(0, libc::F_DUPFD, 900) };
+        assert!(base >= 0);
+        let fds = [
+            base,
+            unsafe { libc::fcntl(0, libc::F_DUPFD, base + 1) },
+            unsafe { libc::fcntl(0, libc::F_DUPFD, base + 2) },
+        ];
+
+        f(fds[0] - 1, &[fds[1]]).unwrap();
+
+        let cloexec = |fd| unsafe { libc::fcntl(fd, libc::F_GETFD) } & libc::FD_CLOEXEC != 0;
+        assert!(cloexec(fds[0]));
+        assert!(!cloexec(fds[1]));
+        assert!(cloexec(fds[2]));
+
+        for &fd in fds.iter() {
+            unsafe {
+                libc::close(fd);
+            }
+        }
+    }
+
+    #[test]
+    fn test_cloexec_above() {
+        check_cloexec_above(cloexec_above);
+        check_cloexec_above(cloexec_brute_force);
+        #[cfg(any(target_os = "linux", target_os = "android"))]
+        check_cloexec_above(cloexec_procfs);
+    }
+
+    #[test]
+    fn test_place_fds() {
+        #[allow(clippy::unnecessary_cast)]
+        unsafe fn ino(fd: RawFd) -> Option<u64> {
+            let mut st = std::mem::MaybeUninit::<libc::stat>::uninit();
+            if libc::fstat(fd, st.as_mut_ptr()) == 0 {
+                Some(st.assume_init().st_ino as u64)
+            } else {
+                None
+            }
+        }
+
+        unsafe fn cloexec(fd: RawFd) -> bool {
+            libc::fcntl(fd, libc::F_GETFD) & libc::FD_CLOEXEC != 0
+        }
+
+        // This has to run in a child process, since it changes file descriptors 3 and up
+        crate::tests::check_child(|| unsafe {
+            let mut pipe = [0; 2];
+            assert_eq!(libc::pipe(pipe.as_mut_ptr()), 0);
+            libc::fcntl(pipe[1], libc::F_SETFD, libc::FD_CLOEXEC);
+            let dev_null = libc::open(b"/dev/null\0".as_ptr() as *const _, libc::O_RDONLY);
+            assert!(dev_null >= 0);
+
+            let orig = [(ino(3), cloexec(3)), (ino(4), cloexec(4))];
+
+            let placed = place_fds(&[dev_null, pipe[0]]).unwrap();
+
+            assert_eq!(ino(3), ino(placed.translate(dev_null)));
+            assert_eq!(ino(4), ino(placed.translate(pipe[0])));
+            assert!(!cloexec(3));
+            assert!(!cloexec(4));
+
+            placed.restore();
+
+            assert_eq!([(ino(3), cloexec(3)), (ino(4), cloexec(4))], orig);
+            assert!(cloexec(pipe[1]));
+        });
+    }
+
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    #[test]
+    fn test_leaked_fds() {
+        let fd = unsafe { libc::fcntl(0, libc::F_DUPFD, 500) };
+        assert!(fd >= 0);
+
+        let leaked = leaked_fds().unwrap();
+        let (_, target) = leaked
+            .iter()
+            .find(|&&(leaked_fd, _)| leaked_fd == fd)
+            .unwrap();
+        assert_eq!(*target, std::fs::read_link("/proc/self/fd/0").unwrap());
+
+        set_cloexec(fd).unwrap();
+        assert!(!leaked_fds()
+            .unwrap()
+            .iter()
+            .any(|&(leaked_fd, _)| leaked_fd == fd));
+
+        unsafe {
+            libc::close(fd);
+        }
+    }
+}
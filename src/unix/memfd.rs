@@ -0,0 +1,306 @@
+use std::io;
+use std::os::unix::prelude::*;
+
+use super::{errno, resolve, ExeHandle, Mode};
+use crate::{ReexecError, Strategy};
+
+pub const SEALS: libc::c_int =
+    libc::F_SEAL_WRITE | libc::F_SEAL_GROW | libc::F_SEAL_SHRINK | libc::F_SEAL_SEAL;
+
+/// Create a new memfd with sealing allowed. `name` must be NUL-terminated, and `exec` controls
+/// whether the memfd needs to be executable.
+///
+/// This function is async-signal-safe.
+pub fn memfd_create(name: &[u8], exec: bool) -> Result<RawFd, i32> {
+    debug_assert_eq!(name.last(), Some(&0));
+
+    // Newer kernels may be configured to create non-executable memfds unless MFD_EXEC is passed;
+    // older kernels will fail with EINVAL if they don't recognize it
+    let all_flags = [
+        libc::MFD_CLOEXEC | libc::MFD_ALLOW_SEALING | libc::MFD_EXEC,
+        libc::MFD_CLOEXEC | libc::MFD_ALLOW_SEALING,
+    ];
+    let flags = if exec {
+        &all_flags[..]
+    } else {
+        &all_flags[1..]
+    };
+
+    for &flags in flags {
+        let fd = unsafe { libc::syscall(libc::SYS_memfd_create, name.as_ptr(), flags) };
+        if fd >= 0 {
+            return Ok(fd as RawFd);
+        }
+
+        let eno = errno();
+        if eno != libc::EINVAL {
+            return Err(eno);
+        }
+    }
+
+    Err(libc::EINVAL)
+}
+
+/// Copy everything from `src` (starting at its current offset) into `dest`.
+///
+/// This function is async-signal-safe.
+fn copy_all(src: RawFd, dest: RawFd) -> Result<(), i32> {
+    // Try sendfile() first; it avoids copying the data through userspace
+    loop {
+        match unsafe { libc::sendfile(dest, src, std::ptr::null_mut(), 1 << 30) } {
+            0 => return Ok(()),
+            n if n > 0 => (),
+            _ => match errno() {
+                libc::EINTR => (),
+                libc::EINVAL | libc::ENOSYS => break,
+                eno => return Err(eno),
+            },
+        }
+    }
+
+    let mut buf = [0u8; 8192];
+    loop {
+        let n = unsafe { libc::read(src, buf.as_mut_ptr() as *mut _, buf.len()) };
+        if n == 0 {
+            return Ok(());
+        } else if n < 0 {
+            match errno() {
+                libc::EINTR => continue,
+                eno => return Err(eno),
+            }
+        }
+
+        let mut data = &buf[..n as usize];
+        while !data.is_empty() {
+            let n = unsafe { libc::write(dest, data.as_ptr() as *const _, data.len()) };
+            if n < 0 {
+                match errno() {
+                    libc::EINTR => continue,
+                    eno => return Err(eno),
+                }
+            }
+            data = &data[n as usize..];
+        }
+    }
+}
+
+/// Copy the contents of `src` into a new memfd, and seal it against modification.
+///
+/// This function is async-signal-safe.
+pub fn copy_to_memfd(src: RawFd) -> Result<RawFd, i32> {
+    let fd = memfd_create(b"reexec\0", true)?;
+
+    let res = copy_all(src, fd).and_then(|()| {
+        if unsafe { libc::fcntl(fd, libc::F_ADD_SEALS, SEALS) } < 0 {
+            Err(errno())
+        } else {
+            Ok(())
+        }
+    });
+
+    match res {
+        Ok(()) => Ok(fd),
+        Err(eno) => {
+            unsafe {
+                libc::close(fd);
+            }
+            Err(eno)
+        }
+    }
+}
+
+/// Copy the executable found by the first working strategy into a sealed memfd.
+///
+/// This function is async-signal-safe.
+fn snapshot(strategies: &[Strategy]) -> Result<RawFd, ReexecError> {
+    let mut err = ReexecError::new(libc::ENOENT);
+    let mut buf = [0u8; libc::PATH_MAX as usize + 1];
+
+    for &strategy in strategies {
+        let eno = match resolve(strategy, &mut buf, Mode::Exec) {
+            Ok(path) => {
+                let src = unsafe {
+                    libc::open(path.as_cstr().as_ptr(), libc::O_RDONLY | libc::O_CLOEXEC)
+                };
+                if src < 0 {
+                    errno()
+                } else {
+                    let res = copy_to_memfd(src);
+                    unsafe {
+                        libc::close(src);
+                    }
+                    match res {
+                        Ok(fd) => return Ok(fd),
+                        Err(eno) => eno,
+                    }
+                }
+            }
+            Err(eno) => eno,
+        };
+
+        if eno == libc::EACCES {
+            err.set_errno(libc::EACCES);
+        }
+        err.push(strategy, eno);
+    }
+
+    Err(err)
+}
+
+/// Copy the current program's executable into a sealed memfd.
+///
+/// The executable is located in the same way as [`get_reexec_path()`](crate::get_reexec_path),
+/// and copied into a file created with `memfd_create(MFD_CLOEXEC | MFD_ALLOW_SEALING)`. The file
+/// is then sealed with `F_SEAL_WRITE | F_SEAL_GROW | F_SEAL_SHRINK | F_SEAL_SEAL`, so neither this
+/// process nor the re-executed program can modify it.
+///
+/// This is useful when re-executing into a less trusted environment (for example, a container),
+/// since it avoids exposing the executable on the host to the new program (see CVE-2019-5736).
+///
+/// The returned handle always executes the sealed copy; it never falls back on looking up the
+/// executable's path.
+pub fn snapshot_to_memfd() -> io::Result<ExeHandle> {
+    let fd = snapshot(crate::strategies())?;
+    unsafe { ExeHandle::from_fd(fd, true) }
+}
+
+/// Copy the image of the currently running program into a sealed memfd.
+///
+/// Unlike [`snapshot_to_memfd()`], this always reads the executable through `/proc/self/exe`, so
+/// the copy is of the exact version of the program that is running, even if the executable has
+/// since been deleted or replaced (see [`is_exe_deleted()`](crate::is_exe_deleted)). This allows
+/// deliberately re-executing the old version of the program.
+pub fn snapshot_running_image() -> io::Result<ExeHandle> {
+    let fd = snapshot_running_image_fd().map_err(io::Error::from_raw_os_error)?;
+    unsafe { ExeHandle::from_fd(fd, true) }
+}
+
+/// Copy `/proc/self/exe` into a sealed memfd.
+///
+/// This function is async-signal-safe.
+fn snapshot_running_image_fd() -> Result<RawFd, i32> {
+    let src = unsafe {
+        libc::open(
+            b"/proc/self/exe\0".as_ptr() as *const _,
+            libc::O_RDONLY | libc::O_CLOEXEC,
+        )
+    };
+    if src < 0 {
+        return Err(errno());
+    }
+
+    let res = copy_to_memfd(src);
+    unsafe {
+        libc::close(src);
+    }
+    res
+}
+
+/// Re-execute the current program from a sealed copy of its executable.
+///
+/// This creates a sealed copy of the executable (as with [`snapshot_to_memfd()`]) and executes it
+/// with the specified `argv` and `envp`.
+///
+/// This function is async-signal-safe.
+///
+/// # Safety
+///
+/// See [`reexecve()`](crate::reexecve).
+pub unsafe fn reexecve_sealed(
+    argv: *const *const libc::c_char,
+    envp: *const *const libc::c_char,
+) -> ReexecError {
+    let fd = match snapshot(crate::strategies()) {
+        Ok(fd) => fd,
+        Err(err) => return err,
+    };
+
+    // We can't use ExeHandle::from_fd() because it isn't async-signal-safe (it may allocate an
+    // io::Error)
+    let err = super::exe_handle::execveat_sealed(fd, argv, envp);
+    libc::close(fd);
+    err
+}
+
+/// Re-execute the image of the currently running program from a sealed copy.
+///
+/// This is an async-signal-safe equivalent of [`snapshot_running_image()`] followed by
+/// [`ExeHandle::reexecve()`].
+///
+/// # Safety
+///
+/// See [`reexecve()`](crate::reexecve).
+pub unsafe fn reexecve_running_image(
+    argv: *const *const libc::c_char,
+    envp: *const *const libc::c_char,
+) -> ReexecError {
+    let fd = match snapshot_running_image_fd() {
+        Ok(fd) => fd,
+        Err(eno) => return ReexecError::new(eno),
+    };
+
+    let err = super::exe_handle::execveat_sealed(fd, argv, envp);
+    libc::close(fd);
+    err
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::fs;
+    use std::io::{Read, Seek, SeekFrom, Write};
+
+    #[test]
+    fn test_snapshot_to_memfd() {
+        let handle = snapshot_to_memfd().unwrap();
+        assert!(handle.is_sealed());
+
+        let seals = unsafe { libc::fcntl(handle.as_raw_fd(), libc::F_GET_SEALS) };
+        assert_eq!(seals & SEALS, SEALS);
+
+        // The contents should match the original file
+        let mut f = unsafe { fs::File::from_raw_fd(libc::dup(handle.as_raw_fd())) };
+        let mut data = Vec::new();
+        f.seek(SeekFrom::Start(0)).unwrap();
+        f.read_to_end(&mut data).unwrap();
+        assert_eq!(data, fs::read(std::env::current_exe().unwrap()).unwrap());
+
+        // And it shouldn't be writable
+        assert_eq!(
+            f.write(b"abc").unwrap_err().raw_os_error(),
+            Some(libc::EPERM)
+        );
+    }
+
+    #[test]
+    fn test_snapshot_running_image() {
+        let handle = snapshot_running_image().unwrap();
+        assert!(handle.is_sealed());
+
+        let mut f = unsafe { fs::File::from_raw_fd(libc::dup(handle.as_raw_fd())) };
+        let mut data = Vec::new();
+        f.seek(SeekFrom::Start(0)).unwrap();
+        f.read_to_end(&mut data).unwrap();
+        assert_eq!(data, fs::read(std::env::current_exe().unwrap()).unwrap());
+    }
+
+    #[test]
+    fn test_reexecve_sealed() {
+        crate::tests::check_reexec_child(|argv, envp| unsafe {
+            reexecve_sealed(argv, envp);
+        });
+
+        let handle = snapshot_to_memfd().unwrap();
+        crate::tests::check_reexec_child(|argv, envp| unsafe {
+            handle.reexecve(argv, envp);
+        });
+    }
+
+    #[test]
+    fn test_reexecve_running_image() {
+        crate::tests::check_reexec_child(|argv, envp| unsafe {
+            reexecve_running_image(argv, envp);
+        });
+    }
+}
@@ -1,12 +1,42 @@
 use std::borrow::Cow;
-use std::ffi::{OsStr, OsString};
+use std::ffi::{CStr, OsStr, OsString};
 use std::os::unix::prelude::*;
 use std::path::Path;
 
+mod elf;
+#[cfg(any(target_os = "linux", target_os = "android"))]
+mod exe_handle;
+mod exe_info;
+pub mod fds;
+#[cfg(any(target_os = "linux", target_os = "android"))]
+pub mod memfd;
+#[cfg(any(target_os = "linux", target_os = "android"))]
+pub mod pidfd;
+pub mod procstate;
 mod reexec_path;
+pub mod sigstate;
 mod sys;
 
-use crate::errno_ptr;
+#[cfg(any(target_os = "linux", target_os = "android"))]
+pub use exe_handle::ExeHandle;
+pub use exe_info::{exe_info, file_build_id, ExeInfo};
+#[cfg(any(target_os = "linux", target_os = "android"))]
+pub use fds::leaked_fds;
+#[cfg(any(target_os = "linux", target_os = "android"))]
+pub use memfd::{
+    reexecve_running_image, reexecve_sealed, snapshot_running_image, snapshot_to_memfd,
+};
+pub use reexec_path::capture_startup_execfn;
+#[cfg(any(target_os = "linux", target_os = "android"))]
+pub use reexec_path::is_exe_deleted;
+pub use reexec_path::DEFAULT_STRATEGIES;
+
+use crate::{errno_ptr, Probe, ReexecError, Strategy};
+
+#[inline]
+pub fn errno() -> i32 {
+    unsafe { *errno_ptr() }
+}
 
 #[inline]
 unsafe fn eaccess(path: *const libc::c_char, amode: libc::c_int) -> libc::c_int {
@@ -16,20 +46,159 @@ unsafe fn eaccess(path: *const libc::c_char, amode: libc::c_int) -> libc::c_int
     return libc::access(path, amode);
 }
 
-pub unsafe fn reexecve(argv: *const *const libc::c_char, envp: *const *const libc::c_char) -> i32 {
-    let mut eno = libc::ENOENT;
-    let eno_ptr = errno_ptr();
+/// What a resolved path is going to be used for.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum Mode {
+    /// The path will be passed directly to `execve()`.
+    Exec,
+    /// The path will be returned to the caller, so it must be absolute.
+    Path,
+    /// Like `Path`, but it must be the actual path to the executable (not a special file under
+    /// `/proc`).
+    Exe,
+}
 
-    macro_rules! try_exec {
-        ($prog:expr $(,)?) => {
-            libc::execve($prog as *const libc::c_char, argv, envp);
+/// A path produced by one of the strategies.
+pub enum Candidate<'a> {
+    Static(&'static CStr),
+    Buffered(&'a CStr),
+}
 
-            eno = *eno_ptr;
-            if !matches!(eno, libc::ENOENT | libc::EACCES) {
-                return eno;
+impl Candidate<'_> {
+    #[inline]
+    pub fn as_cstr(&self) -> &CStr {
+        match *self {
+            Self::Static(path) => path,
+            Self::Buffered(path) => path,
+        }
+    }
+
+    #[inline]
+    pub fn into_path(self) -> Cow<'static, Path> {
+        match self {
+            Self::Static(path) => Cow::Borrowed(OsStr::from_bytes(path.to_bytes()).as_ref()),
+            Self::Buffered(path) => Cow::Owned(OsString::from_vec(path.to_bytes().into()).into()),
+        }
+    }
+}
+
+#[inline]
+unsafe fn buffered_cstr(buf: &[u8], n: Option<usize>) -> &CStr {
+    let n = n.unwrap_or_else(|| libc::strlen(buf.as_ptr() as *const _));
+    CStr::from_bytes_with_nul_unchecked(&buf[..=n])
+}
+
+/// Try to get a path to the current program with the given strategy.
+///
+/// This does not check that the path is executable. It is async-signal-safe (as long as any
+/// custom strategies are).
+pub fn resolve(strategy: Strategy, buf: &mut [u8], mode: Mode) -> Result<Candidate<'_>, i32> {
+    unsafe {
+        match strategy {
+            Strategy::Procfs => {
+                if mode == Mode::Exe {
+                    let n = reexec_path::get_procfs_readlink(buf)?;
+                    Ok(Candidate::Buffered(buffered_cstr(buf, Some(n))))
+                } else {
+                    let path = reexec_path::get_procfs_reexec()?;
+                    Ok(Candidate::Static(CStr::from_bytes_with_nul_unchecked(path)))
+                }
             }
-        };
+
+            Strategy::VerifiedProcfs => {
+                #[cfg(any(target_os = "linux", target_os = "android"))]
+                {
+                    reexec_path::verify_procfs()?;
+                    resolve(Strategy::Procfs, buf, mode)
+                }
+                #[cfg(not(any(target_os = "linux", target_os = "android")))]
+                Err(libc::ENOSYS)
+            }
+
+            Strategy::ProcInfo => {
+                let n = reexec_path::get_procinfo(buf)?;
+                Ok(Candidate::Buffered(buffered_cstr(buf, n)))
+            }
+
+            Strategy::AuxExecFn => Ok(Candidate::Static(CStr::from_ptr(
+                reexec_path::get_initial_static()?,
+            ))),
+
+            Strategy::AuxExecPath => {
+                let n = reexec_path::get_initial_buffered(buf)?;
+                Ok(Candidate::Buffered(buffered_cstr(buf, n)))
+            }
+
+            #[cfg(target_os = "openbsd")]
+            Strategy::Argv0 => {
+                let (n, dev, ino) = reexec_path::get_openbsd(buf)?;
+
+                if mode != Mode::Exec && buf[0] != b'/' {
+                    // The working directory might change between now and when we call execve();
+                    // canonicalize the path and check that it's still the right file
+                    let mut rbuf = [0u8; libc::PATH_MAX as usize + 1];
+                    if libc::realpath(buf.as_ptr() as *const _, rbuf.as_mut_ptr() as *mut _)
+                        .is_null()
+                    {
+                        return Err(errno());
+                    }
+
+                    let mut st = std::mem::MaybeUninit::uninit();
+                    if libc::stat(rbuf.as_ptr() as *const _, st.as_mut_ptr()) != 0 {
+                        return Err(errno());
+                    }
+                    let st = st.assume_init();
+                    if st.st_dev != dev || st.st_ino != ino {
+                        return Err(libc::ENOENT);
+                    }
+
+                    let n = libc::strlen(rbuf.as_ptr() as *const _);
+                    if n >= buf.len() {
+                        return Err(libc::ENAMETOOLONG);
+                    }
+                    buf[..=n].copy_from_slice(&rbuf[..=n]);
+                    return Ok(Candidate::Buffered(buffered_cstr(buf, Some(n))));
+                }
+
+                Ok(Candidate::Buffered(buffered_cstr(buf, Some(n))))
+            }
+
+            #[cfg(not(target_os = "openbsd"))]
+            Strategy::Argv0 => Err(libc::ENOSYS),
+
+            Strategy::Custom(custom) => {
+                let n = custom.resolve(buf)?;
+                // Make sure the path was properly NUL-terminated (and doesn't contain any other NUL
+                // bytes)
+                if n >= buf.len() || buf[n] != 0 || buf[..n].contains(&0) {
+                    return Err(libc::EINVAL);
+                } else if n == 0 || (mode != Mode::Exec && buf[0] != b'/') {
+                    return Err(libc::ENOENT);
+                }
+                Ok(Candidate::Buffered(buffered_cstr(buf, Some(n))))
+            }
+        }
     }
+}
+
+#[inline]
+pub unsafe fn reexecve(
+    strategies: &[Strategy],
+    argv: *const *const libc::c_char,
+    envp: *const *const libc::c_char,
+) -> ReexecError {
+    let mut buf = [0u8; libc::PATH_MAX as usize + 1];
+    reexecve_buf(strategies, argv, envp, &mut buf)
+}
+
+/// Like `reexecve()`, but use the given buffer to hold candidate paths.
+pub unsafe fn reexecve_buf(
+    strategies: &[Strategy],
+    argv: *const *const libc::c_char,
+    envp: *const *const libc::c_char,
+    buf: &mut [u8],
+) -> ReexecError {
+    let mut err = ReexecError::new(libc::ENOENT);
 
     // Order is important:
     // - First we try a special path under /proc, if available. This only requires an execve() to
@@ -40,208 +209,306 @@ pub unsafe fn reexecve(argv: *const *const libc::c_char, envp: *const *const lib
     // - Finally, when launching the program, some kernels may put the program's path in a place
     //   where the process can access it (either as a pointer or by copying into a buffer). That
     //   won't update across rename()s (and definitely not unlink()s), but it's the best we can do.
+    //
+    // DEFAULT_STRATEGIES lists the strategies in that order, though the caller may have chosen a
+    // different order.
+
+    for &strategy in strategies {
+        let path = match resolve(strategy, buf, Mode::Exec) {
+            Ok(path) => path,
+            Err(eno) => {
+                err.push(strategy, eno);
+                continue;
+            }
+        };
+
+        libc::execve(path.as_cstr().as_ptr(), argv, envp);
 
-    if let Ok(path) = reexec_path::get_procfs_reexec() {
-        try_exec!(path.as_ptr());
+        let eno = errno();
+        err.push(strategy, eno);
+        err.set_errno(eno);
+        if !matches!(eno, libc::ENOENT | libc::EACCES) {
+            return err;
+        }
     }
 
-    // The compiler will optimize this out if it's not needed
-    let mut buf = [0u8; libc::PATH_MAX as usize + 1];
+    err
+}
 
-    if reexec_path::get_procinfo(&mut buf).is_ok() {
-        try_exec!(buf.as_ptr());
+/// Find the first usable path produced by the given strategies.
+///
+/// On success, the path is written (NUL-terminated) into `buf` and `None` is returned, unless
+/// `borrow_static` is true and the path doesn't need to be copied, in which case it's returned
+/// directly.
+///
+/// This function is async-signal-safe (as long as any custom strategies are).
+fn find_path(
+    strategies: &[Strategy],
+    mode: Mode,
+    buf: &mut [u8],
+    borrow_static: bool,
+) -> Result<Option<&'static CStr>, ReexecError> {
+    let mut err = ReexecError::new(libc::ENOENT);
+
+    // If the executable has been deleted, /proc/self/exe is the only way to refer to it, and the
+    // path-based strategies might find a different executable that has been put in its place. So
+    // don't try any of them (whatever order they're in).
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    if mode == Mode::Exe && is_exe_deleted() {
+        err.set_deleted();
+        return Err(err);
     }
 
-    if let Ok(path) = reexec_path::get_initial_static() {
-        try_exec!(path);
-    }
+    // Order is important, as described in reexecve()
+    for &strategy in strategies {
+        let eno = match resolve(strategy, buf, mode) {
+            Ok(path) => {
+                if unsafe { eaccess(path.as_cstr().as_ptr(), libc::X_OK) } == 0 {
+                    match path {
+                        Candidate::Static(path) if borrow_static => return Ok(Some(path)),
+                        Candidate::Static(path) => {
+                            let path = path.to_bytes_with_nul();
+                            if path.len() <= buf.len() {
+                                buf[..path.len()].copy_from_slice(path);
+                                return Ok(None);
+                            }
+                            libc::ENAMETOOLONG
+                        }
+                        Candidate::Buffered(_) => return Ok(None),
+                    }
+                } else {
+                    errno()
+                }
+            }
+            Err(eno) => eno,
+        };
 
-    if reexec_path::get_initial_buffered(&mut buf).is_ok() {
-        try_exec!(buf.as_ptr());
+        if eno == libc::EACCES {
+            err.set_errno(libc::EACCES);
+        }
+        err.push(strategy, eno);
     }
 
-    #[cfg(target_os = "openbsd")]
-    if reexec_path::get_openbsd(&mut buf).is_ok() {
-        try_exec!(buf.as_ptr());
+    Err(err)
+}
+
+fn get_path(strategies: &[Strategy], mode: Mode) -> Result<Cow<'static, Path>, ReexecError> {
+    let mut buf = [0u8; libc::PATH_MAX as usize + 1];
+
+    match find_path(strategies, mode, &mut buf, true)? {
+        Some(path) => Ok(Candidate::Static(path).into_path()),
+        None => Ok(Candidate::Buffered(unsafe { buffered_cstr(&buf, None) }).into_path()),
     }
+}
 
-    eno
+fn get_path_into<'a>(
+    strategies: &[Strategy],
+    mode: Mode,
+    buf: &'a mut [u8],
+) -> Result<&'a CStr, ReexecError> {
+    find_path(strategies, mode, buf, false)?;
+    Ok(unsafe { buffered_cstr(buf, None) })
 }
 
-pub fn get_reexec_path() -> Result<Cow<'static, Path>, i32> {
-    unsafe {
-        let mut eno = libc::ENOENT;
-        let eno_ptr = errno_ptr();
-
-        macro_rules! try_static_path {
-            ($ptr:expr, $len:expr $(,)?) => {
-                if eaccess($ptr, libc::X_OK) == 0 {
-                    return Ok(Cow::Borrowed(
-                        OsStr::from_bytes(std::slice::from_raw_parts($ptr as *const u8, $len))
-                            .as_ref(),
-                    ));
-                }
+pub fn probe(strategy: Strategy) -> Probe {
+    let mut buf = [0u8; libc::PATH_MAX as usize + 1];
 
-                if *eno_ptr == libc::EACCES {
-                    eno = libc::EACCES;
-                }
+    match resolve(strategy, &mut buf, Mode::Path) {
+        Ok(path) => {
+            let access = if unsafe { eaccess(path.as_cstr().as_ptr(), libc::X_OK) } == 0 {
+                0
+            } else {
+                errno()
             };
+            Probe::new(strategy, Ok(path.into_path().into_owned()), Some(access))
         }
+        Err(eno) => Probe::new(strategy, Err(eno), None),
+    }
+}
 
-        macro_rules! try_buffered_path {
-            ($buf:expr, $len:expr $(,)?) => {
-                if eaccess($buf.as_ptr() as *const _, libc::X_OK) == 0 {
-                    return Ok(Cow::Owned(OsString::from_vec($buf[..$len].into()).into()));
-                }
-
-                if *eno_ptr == libc::EACCES {
-                    eno = libc::EACCES;
-                }
-            };
-        }
+#[inline]
+pub fn get_reexec_path(strategies: &[Strategy]) -> Result<Cow<'static, Path>, ReexecError> {
+    get_path(strategies, Mode::Path)
+}
 
-        // Order is important, as described in reexecve()
+#[inline]
+pub fn get_exe_path(strategies: &[Strategy]) -> Result<Cow<'static, Path>, ReexecError> {
+    get_path(strategies, Mode::Exe)
+}
 
-        if let Ok(path) = reexec_path::get_procfs_reexec() {
-            try_static_path!(path.as_ptr() as *const libc::c_char, path.len() - 1);
-        }
+#[inline]
+pub fn get_reexec_path_into<'a>(
+    strategies: &[Strategy],
+    buf: &'a mut [u8],
+) -> Result<&'a CStr, ReexecError> {
+    get_path_into(strategies, Mode::Path, buf)
+}
 
-        // The compiler will optimize this out if it's not needed
-        let mut buf = [0u8; libc::PATH_MAX as usize + 1];
+#[inline]
+pub fn get_exe_path_into<'a>(
+    strategies: &[Strategy],
+    buf: &'a mut [u8],
+) -> Result<&'a CStr, ReexecError> {
+    get_path_into(strategies, Mode::Exe, buf)
+}
 
-        if let Ok(n) = reexec_path::get_procinfo(&mut buf) {
-            try_buffered_path!(
-                buf,
-                n.unwrap_or_else(|| libc::strlen(buf.as_ptr() as *const _)),
-            );
-        }
+#[cfg(test)]
+mod tests {
+    use super::*;
 
-        if let Ok(path) = reexec_path::get_initial_static() {
-            try_static_path!(path, libc::strlen(path));
-        }
+    struct CurrentExe;
 
-        if let Ok(n) = reexec_path::get_initial_buffered(&mut buf) {
-            try_buffered_path!(
-                buf,
-                n.unwrap_or_else(|| libc::strlen(buf.as_ptr() as *const _)),
-            );
+    impl crate::CustomStrategy for CurrentExe {
+        fn name(&self) -> &'static str {
+            "current_exe()"
         }
 
-        #[cfg(target_os = "openbsd")]
-        if let Ok((n, dev, ino)) = reexec_path::get_openbsd(&mut buf) {
-            if eaccess(buf.as_ptr() as *const _, libc::X_OK) == 0 {
-                let path = &buf[..n];
-
-                return Ok(Cow::Owned(if path.first() == Some(&b'/') {
-                    OsString::from_vec(path.into()).into()
-                } else {
-                    // The working directory might change between now and when we call execve();
-                    // canonicalize the path and check that it's still the right file
-
-                    let path = Path::new(OsStr::from_bytes(path))
-                        .canonicalize()
-                        .map_err(|e| e.raw_os_error().unwrap())?;
-
-                    let meta = path.metadata().map_err(|e| e.raw_os_error().unwrap())?;
-                    if meta.dev() != dev as u64 || meta.ino() != ino as u64 {
-                        return Err(libc::ENOENT);
-                    }
-
-                    path
-                }));
-            } else {
-                eno = *eno_ptr;
+        fn resolve(&self, buf: &mut [u8]) -> Result<usize, i32> {
+            let exe = std::env::current_exe().map_err(|e| e.raw_os_error().unwrap())?;
+            let exe = exe.as_os_str().as_bytes();
+            if exe.len() >= buf.len() {
+                return Err(libc::ENAMETOOLONG);
             }
+            buf[..exe.len()].copy_from_slice(exe);
+            buf[exe.len()] = 0;
+            Ok(exe.len())
         }
-
-        Err(eno)
     }
-}
 
-pub fn get_exe_path() -> Result<Cow<'static, Path>, i32> {
-    unsafe {
-        let mut eno = libc::ENOENT;
-        let eno_ptr = errno_ptr();
-
-        macro_rules! try_static_path {
-            ($ptr:expr, $len:expr $(,)?) => {
-                if eaccess($ptr, libc::X_OK) == 0 {
-                    return Ok(Cow::Borrowed(
-                        OsStr::from_bytes(std::slice::from_raw_parts($ptr as *const u8, $len))
-                            .as_ref(),
-                    ));
-                }
-
-                if *eno_ptr == libc::EACCES {
-                    eno = libc::EACCES;
-                }
-            };
-        }
+    struct Unterminated {
+        fill: u8,
+    }
 
-        macro_rules! try_buffered_path {
-            ($buf:expr, $len:expr $(,)?) => {
-                if eaccess($buf.as_ptr() as *const _, libc::X_OK) == 0 {
-                    return Ok(Cow::Owned(OsString::from_vec($buf[..$len].into()).into()));
-                }
+    static UNTERMINATED: Unterminated = Unterminated { fill: b'/' };
 
-                if *eno_ptr == libc::EACCES {
-                    eno = libc::EACCES;
-                }
-            };
+    impl crate::CustomStrategy for Unterminated {
+        fn name(&self) -> &'static str {
+            "unterminated"
         }
 
-        // The compiler will optimize this out if it's not needed
-        let mut buf = [0u8; libc::PATH_MAX as usize + 1];
-
-        if let Ok(n) = reexec_path::get_procfs_readlink(&mut buf) {
-            try_buffered_path!(buf, n);
+        fn resolve(&self, buf: &mut [u8]) -> Result<usize, i32> {
+            for ch in buf.iter_mut() {
+                *ch = self.fill;
+            }
+            Ok(buf.len())
         }
+    }
 
-        if let Ok(n) = reexec_path::get_procinfo(&mut buf) {
-            try_buffered_path!(
-                buf,
-                n.unwrap_or_else(|| libc::strlen(buf.as_ptr() as *const _)),
-            );
-        }
+    #[test]
+    fn test_custom_strategy() {
+        let exe = std::env::current_exe().unwrap();
+
+        let strategies = [
+            Strategy::Custom(&UNTERMINATED),
+            Strategy::Custom(&CurrentExe),
+        ];
+        assert_ne!(strategies[0], strategies[1]);
+        assert_eq!(get_reexec_path(&strategies).unwrap(), exe);
+        assert_eq!(get_exe_path(&strategies).unwrap(), exe);
+
+        let err = get_reexec_path(&strategies[..1]).unwrap_err();
+        assert_eq!(err.raw_os_error(), libc::ENOENT);
+        assert_eq!(
+            err.attempts()[0].strategy(),
+            Strategy::Custom(&UNTERMINATED)
+        );
+        assert_eq!(err.strategy_error(Strategy::Custom(&CurrentExe)), None);
+        assert_eq!(err.attempts()[0].raw_os_error(), libc::EINVAL);
+    }
 
-        if let Ok(path) = reexec_path::get_initial_static() {
-            try_static_path!(path, libc::strlen(path));
-        }
+    /// Run in a copy of the test binary by test_deleted(), after the copy has been deleted
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    #[test]
+    fn test_deleted_child() {
+        use std::io::Read;
 
-        if let Ok(n) = reexec_path::get_initial_buffered(&mut buf) {
-            try_buffered_path!(
-                buf,
-                n.unwrap_or_else(|| libc::strlen(buf.as_ptr() as *const _)),
-            );
+        if std::env::var_os("REEXEC_TEST_DELETED").is_none() {
+            return;
         }
 
-        #[cfg(target_os = "openbsd")]
-        if let Ok((n, dev, ino)) = reexec_path::get_openbsd(&mut buf) {
-            if eaccess(buf.as_ptr() as *const _, libc::X_OK) == 0 {
-                let path = &buf[..n];
+        // Wait for the parent to delete the executable
+        std::io::stdin().read_to_end(&mut Vec::new()).unwrap();
+
+        assert!(is_exe_deleted());
+        let err = get_exe_path(DEFAULT_STRATEGIES).unwrap_err();
+        assert!(err.is_deleted());
+        assert_eq!(err.attempts(), &[]);
+
+        // Even if a path-based strategy comes first
+        let err = get_exe_path(&[Strategy::AuxExecFn, Strategy::Procfs]).unwrap_err();
+        assert!(err.is_deleted());
+        assert_eq!(err.attempts(), &[]);
+
+        // /proc/self/exe can still be used to re-execute the program
+        assert_eq!(
+            get_reexec_path(DEFAULT_STRATEGIES).unwrap(),
+            Path::new("/proc/self/exe")
+        );
+        crate::snapshot_running_image().unwrap();
+    }
 
-                return Ok(Cow::Owned(if path.first() == Some(&b'/') {
-                    OsString::from_vec(path.into()).into()
-                } else {
-                    // The working directory might change between now and when we call execve();
-                    // canonicalize the path and check that it's still the right file
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    #[test]
+    fn test_deleted() {
+        use std::io;
+        use std::process::{Command, Stdio};
+
+        assert!(!is_exe_deleted());
+
+        let path = std::env::temp_dir().join(format!("reexec-test-deleted-{}", std::process::id()));
+        std::fs::copy(std::env::current_exe().unwrap(), &path).unwrap();
+
+        let res = Command::new(&path)
+            .arg("--exact")
+            .arg("imp::tests::test_deleted_child")
+            .env("REEXEC_TEST_DELETED", "1")
+            .stdin(Stdio::piped())
+            .stdout(Stdio::null())
+            .spawn();
+        std::fs::remove_file(&path).unwrap();
+
+        let mut child = match res {
+            Ok(child) => child,
+            // The temporary directory might be mounted noexec
+            Err(e) if e.kind() == io::ErrorKind::PermissionDenied => return,
+            Err(e) => panic!("{}", e),
+        };
+        drop(child.stdin.take());
+        assert!(child.wait().unwrap().success());
+    }
 
-                    let path = Path::new(OsStr::from_bytes(path))
-                        .canonicalize()
-                        .map_err(|e| e.raw_os_error().unwrap())?;
+    #[test]
+    fn test_reexecve() {
+        crate::tests::check_reexec_child(|argv, envp| unsafe {
+            reexecve(DEFAULT_STRATEGIES, argv, envp);
+        });
+    }
 
-                    let meta = path.metadata().map_err(|e| e.raw_os_error().unwrap())?;
-                    if meta.dev() != dev as u64 || meta.ino() != ino as u64 {
-                        return Err(libc::ENOENT);
-                    }
+    #[test]
+    fn test_strategy_order() {
+        let err = get_reexec_path(&[]).unwrap_err();
+        assert_eq!(err.raw_os_error(), libc::ENOENT);
+        assert_eq!(err.attempts(), &[]);
 
-                    path
-                }));
-            } else {
-                eno = *eno_ptr;
+        // Every strategy should give the same result when tried individually
+        for &strategy in DEFAULT_STRATEGIES.iter().rev() {
+            if let Ok(path) = get_exe_path(&[strategy]) {
+                crate::tests::check_path(path.as_os_str());
             }
         }
 
-        Err(eno)
+        // Unsupported strategies fail with ENOSYS
+        for &strategy in &[
+            Strategy::Procfs,
+            Strategy::ProcInfo,
+            Strategy::AuxExecFn,
+            Strategy::AuxExecPath,
+            Strategy::Argv0,
+        ] {
+            if !strategy.is_supported() {
+                let err = get_reexec_path(&[strategy]).unwrap_err();
+                assert_eq!(err.strategy_error(strategy), Some(libc::ENOSYS));
+            }
+        }
     }
 }
@@ -0,0 +1,92 @@
+use std::io;
+use std::os::unix::prelude::*;
+
+use super::{errno, sys};
+
+/// Open a pidfd (with `FD_CLOEXEC` set) referring to the given process.
+pub fn open(pid: libc::pid_t) -> Result<RawFd, i32> {
+    let fd = unsafe { libc::syscall(libc::SYS_pidfd_open, pid, 0) };
+    if fd < 0 {
+        Err(errno())
+    } else {
+        Ok(fd as RawFd)
+    }
+}
+
+/// Send a signal to the process referred to by the given pidfd.
+pub fn send_signal(fd: RawFd, sig: libc::c_int) -> io::Result<()> {
+    let res = unsafe {
+        libc::syscall(
+            libc::SYS_pidfd_send_signal,
+            fd,
+            sig,
+            std::ptr::null::<libc::siginfo_t>(),
+            0,
+        )
+    };
+    if res < 0 {
+        Err(io::Error::last_os_error())
+    } else {
+        Ok(())
+    }
+}
+
+/// Wait for the child process referred to by the given pidfd to exit, and return its wait status
+/// (in the format returned by `waitpid()`).
+///
+/// If `nohang` is true, this returns `Ok(None)` if the child hasn't exited yet.
+pub fn wait(fd: RawFd, nohang: bool) -> io::Result<Option<libc::c_int>> {
+    let mut options = libc::WEXITED;
+    if nohang {
+        options |= libc::WNOHANG;
+    }
+
+    let info = loop {
+        let mut info = unsafe { std::mem::zeroed::<libc::siginfo_t>() };
+        if unsafe { libc::waitid(sys::P_PIDFD, fd as libc::id_t, &mut info, options) } == 0 {
+            break info;
+        }
+
+        let err = io::Error::last_os_error();
+        if err.kind() != io::ErrorKind::Interrupted {
+            return Err(err);
+        }
+    };
+
+    // With WNOHANG, si_pid is left as 0 if the child hasn't exited
+    if unsafe { info.si_pid() } == 0 {
+        return Ok(None);
+    }
+
+    let status = unsafe { info.si_status() };
+    Ok(Some(match info.si_code {
+        libc::CLD_EXITED => (status & 0xff) << 8,
+        libc::CLD_DUMPED => status | 0x80,
+        _ => status,
+    }))
+}
+
+/// Pidfds for a list of processes, which are closed when this is dropped.
+pub struct PidFds(pub Vec<(u32, RawFd)>);
+
+impl PidFds {
+    /// Open pidfds for each of the given processes. Processes that a pidfd can't be opened for
+    /// (for example, because the kernel doesn't support pidfds) are skipped.
+    pub fn open_all(pids: &[u32]) -> Self {
+        Self(
+            pids.iter()
+                .filter_map(|&pid| open(pid as libc::pid_t).ok().map(|fd| (pid, fd)))
+                .collect(),
+        )
+    }
+}
+
+impl Drop for PidFds {
+    fn drop(&mut self) {
+        for &(_, fd) in self.0.iter() {
+            unsafe {
+                libc::close(fd);
+            }
+        }
+    }
+}
@@ -0,0 +1,96 @@
+use std::ffi::CStr;
+use std::os::unix::prelude::*;
+
+use super::errno;
+
+/// The working directory and umask changed by `apply()`, so they can be put back if the exec
+/// fails.
+pub struct SavedState {
+    cwd_fd: Option<RawFd>,
+    umask: Option<libc::mode_t>,
+}
+
+/// Change the working directory to `cwd` and set the umask to `umask` (where given), in
+/// preparation for `execve()`.
+///
+/// If changing the working directory fails, nothing is changed and the error is returned.
+///
+/// This function is async-signal-safe.
+pub unsafe fn apply(cwd: Option<&CStr>, umask: Option<libc::mode_t>) -> Result<SavedState, i32> {
+    let cwd_fd = match cwd {
+        Some(cwd) => {
+            // O_PATH lets us save the working directory even if we can't read it
+            #[cfg(any(target_os = "linux", target_os = "android"))]
+            let flags = libc::O_PATH | libc::O_DIRECTORY | libc::O_CLOEXEC;
+            #[cfg(not(any(target_os = "linux", target_os = "android")))]
+            let flags = libc::O_RDONLY | libc::O_DIRECTORY | libc::O_CLOEXEC;
+
+            let fd = libc::open(b".\0".as_ptr() as *const _, flags);
+            if fd < 0 {
+                return Err(errno());
+            }
+
+            if libc::chdir(cwd.as_ptr()) < 0 {
+                let eno = errno();
+                libc::close(fd);
+                return Err(eno);
+            }
+            Some(fd)
+        }
+        None => None,
+    };
+
+    Ok(SavedState {
+        cwd_fd,
+        umask: umask.map(|mask| libc::umask(mask)),
+    })
+}
+
+/// Restore the state saved by `apply()`.
+///
+/// This function is async-signal-safe.
+pub unsafe fn restore(saved: SavedState) {
+    if let Some(mask) = saved.umask {
+        libc::umask(mask);
+    }
+
+    if let Some(fd) = saved.cwd_fd {
+        libc::fchdir(fd);
+        libc::close(fd);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_apply_restore() {
+        crate::tests::check_child(|| unsafe {
+            let orig = std::env::current_dir().unwrap();
+            let orig_mask = libc::umask(0o022);
+
+            let saved = apply(
+                Some(CStr::from_bytes_with_nul(b"/\0").unwrap()),
+                Some(0o077),
+            )
+            .unwrap();
+            assert_eq!(std::env::current_dir().unwrap(), std::path::Path::new("/"));
+            assert_eq!(libc::umask(0o077), 0o077);
+
+            restore(saved);
+            assert_eq!(std::env::current_dir().unwrap(), orig);
+            assert_eq!(libc::umask(orig_mask), 0o022);
+
+            // Failing to change directories changes nothing
+            let eno = apply(
+                Some(CStr::from_bytes_with_nul(b"/nonexistent-reexec-dir\0").unwrap()),
+                Some(0o077),
+            )
+            .err()
+            .unwrap();
+            assert_eq!(eno, libc::ENOENT);
+            assert_eq!(libc::umask(orig_mask), orig_mask);
+        });
+    }
+}
@@ -1,11 +1,60 @@
 #![allow(unreachable_code, unused_variables)]
 
 #[allow(unused_imports)]
-use crate::imp::sys;
+use crate::imp::{errno, sys};
+use crate::Strategy;
+
+#[cfg(any(
+    target_os = "linux",
+    all(target_os = "android", target_pointer_width = "64"),
+))]
+use std::cell::UnsafeCell;
+#[cfg(any(
+    target_os = "linux",
+    all(target_os = "android", target_pointer_width = "64"),
+))]
+use std::ffi::CStr;
+#[cfg(any(
+    target_os = "linux",
+    all(target_os = "android", target_pointer_width = "64"),
+))]
+use std::sync::atomic::{AtomicU8, Ordering};
+
+#[cfg(any(
+    target_os = "linux",
+    all(target_os = "android", target_pointer_width = "64"),
+    target_os = "solaris",
+    target_os = "illumos",
+))]
+pub const DEFAULT_STRATEGIES: &[Strategy] = &[Strategy::Procfs, Strategy::AuxExecFn];
+#[cfg(all(target_os = "android", not(target_pointer_width = "64")))]
+pub const DEFAULT_STRATEGIES: &[Strategy] = &[Strategy::Procfs];
+#[cfg(any(target_os = "netbsd", target_os = "dragonfly"))]
+pub const DEFAULT_STRATEGIES: &[Strategy] = &[Strategy::Procfs, Strategy::ProcInfo];
+#[cfg(target_os = "freebsd")]
+pub const DEFAULT_STRATEGIES: &[Strategy] = &[Strategy::ProcInfo, Strategy::AuxExecPath];
+#[cfg(any(target_os = "macos", target_os = "ios", target_os = "redox"))]
+pub const DEFAULT_STRATEGIES: &[Strategy] = &[Strategy::ProcInfo];
+#[cfg(target_os = "openbsd")]
+pub const DEFAULT_STRATEGIES: &[Strategy] = &[Strategy::Argv0];
+#[cfg(not(any(
+    target_os = "linux",
+    target_os = "android",
+    target_os = "solaris",
+    target_os = "illumos",
+    target_os = "netbsd",
+    target_os = "dragonfly",
+    target_os = "freebsd",
+    target_os = "macos",
+    target_os = "ios",
+    target_os = "redox",
+    target_os = "openbsd",
+)))]
+pub const DEFAULT_STRATEGIES: &[Strategy] = &[];
 
 /// If possible, return a path under `/proc` that may refer to the current program.
 #[inline]
-pub fn get_procfs_reexec() -> Result<&'static [u8], ()> {
+pub fn get_procfs_reexec() -> Result<&'static [u8], i32> {
     if cfg!(any(target_os = "linux", target_os = "android")) {
         Ok(b"/proc/self/exe\0")
     } else if cfg!(any(target_os = "solaris", target_os = "illumos")) {
@@ -13,13 +62,13 @@ pub fn get_procfs_reexec() -> Result<&'static [u8], ()> {
     } else if cfg!(any(target_os = "netbsd", target_os = "dragonfly")) {
         Ok(b"/proc/curproc/file\0")
     } else {
-        Err(())
+        Err(libc::ENOSYS)
     }
 }
 
 /// If possible, `readlink()` a symlink under `/proc` that may refer to the current program.
 #[inline]
-pub fn get_procfs_readlink(buf: &mut [u8]) -> Result<usize, ()> {
+pub fn get_procfs_readlink(buf: &mut [u8]) -> Result<usize, i32> {
     let path: Option<&[u8]> = if cfg!(any(target_os = "linux", target_os = "android")) {
         Some(b"/proc/self/exe\0")
     } else if cfg!(any(target_os = "solaris", target_os = "illumos")) {
@@ -33,15 +82,21 @@ pub fn get_procfs_readlink(buf: &mut [u8]) -> Result<usize, ()> {
     };
 
     if let Some(path) = path {
-        let mut n = unsafe {
+        let n = unsafe {
             libc::readlink(
                 path.as_ptr() as *const _,
                 buf.as_mut_ptr() as *mut _,
                 buf.len(),
             )
-        } as usize;
+        };
+        if n < 0 {
+            return Err(errno());
+        }
+        let mut n = n as usize;
 
-        if (1..buf.len()).contains(&n) && buf[0] == b'/' {
+        if n >= buf.len() {
+            return Err(libc::ENAMETOOLONG);
+        } else if n > 0 && buf[0] == b'/' {
             // Some OSes may add a trailing NUL byte
             if buf[n - 1] == 0 {
                 n -= 1;
@@ -51,14 +106,82 @@ pub fn get_procfs_readlink(buf: &mut [u8]) -> Result<usize, ()> {
             }
             return Ok(n);
         }
+
+        return Err(libc::ENOENT);
     }
 
-    Err(())
+    Err(libc::ENOSYS)
+}
+
+/// Check whether the current program's executable has been deleted (or replaced by another file).
+///
+/// This checks whether the file that `/proc/self/exe` refers to has any links left. (When the
+/// executable is deleted, `readlink()`ing `/proc/self/exe` gives the original path with
+/// `" (deleted)"` appended, which is not a usable path.)
+#[cfg(any(target_os = "linux", target_os = "android"))]
+#[inline]
+pub fn is_exe_deleted() -> bool {
+    let mut st = std::mem::MaybeUninit::uninit();
+    unsafe {
+        libc::stat(b"/proc/self/exe\0".as_ptr() as *const _, st.as_mut_ptr()) == 0
+            && st.assume_init().st_nlink == 0
+    }
+}
+
+/// Check that `/proc` is really a procfs instance and that `/proc/self` refers to the current
+/// process (and not, for example, to a process in another PID namespace).
+///
+/// This fails with `EXDEV` if either check fails. It is async-signal-safe.
+#[cfg(any(target_os = "linux", target_os = "android"))]
+pub fn verify_procfs() -> Result<(), i32> {
+    let mut sfs = std::mem::MaybeUninit::<libc::statfs>::uninit();
+    if unsafe { libc::statfs(b"/proc\0".as_ptr() as *const _, sfs.as_mut_ptr()) } < 0 {
+        return Err(errno());
+    }
+    #[allow(clippy::unnecessary_cast)]
+    if unsafe { sfs.assume_init() }.f_type as i64 != libc::PROC_SUPER_MAGIC as i64 {
+        return Err(libc::EXDEV);
+    }
+
+    // /proc/self is a symlink to /proc/<pid>, where <pid> is our PID as seen by the PID namespace
+    // that the procfs instance was mounted from
+    let mut buf = [0u8; 16];
+    let n = unsafe {
+        libc::readlink(
+            b"/proc/self\0".as_ptr() as *const _,
+            buf.as_mut_ptr() as *mut _,
+            buf.len(),
+        )
+    };
+    if n < 0 {
+        return Err(errno());
+    }
+
+    let mut pid = unsafe { libc::getpid() } as u32;
+    let mut digits = &buf[..n as usize];
+    loop {
+        match digits.split_last() {
+            Some((&digit, rest)) if digit == b'0' + (pid % 10) as u8 => {
+                digits = rest;
+                pid /= 10;
+                if pid == 0 {
+                    break;
+                }
+            }
+            _ => return Err(libc::EXDEV),
+        }
+    }
+
+    if digits.is_empty() {
+        Ok(())
+    } else {
+        Err(libc::EXDEV)
+    }
 }
 
 /// If possible, get the path of the currently running program via OS-specific kernel interfaces.
 #[inline]
-pub fn get_procinfo(buf: &mut [u8]) -> Result<Option<usize>, ()> {
+pub fn get_procinfo(buf: &mut [u8]) -> Result<Option<usize>, i32> {
     // FreeBSD/DragonFlyBSD/NetBSD let you get the path with sysctl()
     #[cfg(any(target_os = "freebsd", target_os = "dragonfly", target_os = "netbsd"))]
     {
@@ -89,10 +212,13 @@ pub fn get_procinfo(buf: &mut [u8]) -> Result<Option<usize>, ()> {
                 std::ptr::null(),
                 0,
             )
-        } == 0
-            && (2..buf.len()).contains(&len)
+        } != 0
         {
+            return Err(errno());
+        } else if (2..buf.len()).contains(&len) {
             return Ok(Some(len - 1));
+        } else {
+            return Err(libc::ENOENT);
         }
     }
 
@@ -102,8 +228,12 @@ pub fn get_procinfo(buf: &mut [u8]) -> Result<Option<usize>, ()> {
         let n = unsafe {
             sys::proc_pidpath(libc::getpid(), buf.as_mut_ptr() as *mut _, buf.len() as _)
         };
-        if (1..buf.len()).contains(&(n as _)) {
+        if n <= 0 {
+            return Err(errno());
+        } else if (n as usize) < buf.len() {
             return Ok(Some(n as usize));
+        } else {
+            return Err(libc::ENAMETOOLONG);
         }
     }
 
@@ -117,41 +247,196 @@ pub fn get_procinfo(buf: &mut [u8]) -> Result<Option<usize>, ()> {
             )
         };
 
-        if fd >= 0 {
-            let mut n = 0;
-            while n < buf.len() {
-                match unsafe { libc::read(fd, buf.as_mut_ptr().add(n) as *mut _, buf.len() - n) } {
-                    // Error reading from the file; abort
-                    -1 => {
-                        // Set n=0 to ensure that the (incomplete) name is not actually returned
-                        n = 0;
-                        break;
-                    }
-                    // EOF
-                    0 => break,
-                    // Add the count and continue reading
-                    count => n += count as usize,
+        if fd < 0 {
+            return Err(errno());
+        }
+
+        let mut n = 0;
+        while n < buf.len() {
+            match unsafe { libc::read(fd, buf.as_mut_ptr().add(n) as *mut _, buf.len() - n) } {
+                // Error reading from the file; abort
+                -1 => {
+                    // Set n=0 to ensure that the (incomplete) name is not actually returned
+                    n = 0;
+                    break;
                 }
+                // EOF
+                0 => break,
+                // Add the count and continue reading
+                count => n += count as usize,
             }
+        }
 
-            unsafe {
-                libc::close(fd);
-            }
-            // Only return the path if it isn't empty (i.e. not present) and it isn't full (i.e.
-            // too long)
-            if (1..buf.len()).contains(&n) {
-                buf[n] = 0;
-                return Ok(Some(n as usize));
-            }
+        unsafe {
+            libc::close(fd);
+        }
+        // Only return the path if it isn't empty (i.e. not present) and it isn't full (i.e.
+        // too long)
+        if (1..buf.len()).contains(&n) {
+            buf[n] = 0;
+            return Ok(Some(n as usize));
         }
+        return Err(libc::ENOENT);
     }
 
-    Err(())
+    Err(libc::ENOSYS)
+}
+
+/// A relative `AT_EXECFN`, resolved against the working directory at startup, along with the
+/// device ID and inode number of the running image.
+#[cfg(any(
+    target_os = "linux",
+    all(target_os = "android", target_pointer_width = "64"),
+))]
+struct StartupExecFn {
+    state: AtomicU8,
+    path: UnsafeCell<[u8; libc::PATH_MAX as usize + 1]>,
+    dev: UnsafeCell<u64>,
+    ino: UnsafeCell<u64>,
+}
+
+// The fields other than state are only written once, before state is set to READY
+#[cfg(any(
+    target_os = "linux",
+    all(target_os = "android", target_pointer_width = "64"),
+))]
+unsafe impl Sync for StartupExecFn {}
+
+#[cfg(any(
+    target_os = "linux",
+    all(target_os = "android", target_pointer_width = "64"),
+))]
+static STARTUP_EXECFN: StartupExecFn = StartupExecFn {
+    state: AtomicU8::new(STARTUP_UNINIT),
+    path: UnsafeCell::new([0; libc::PATH_MAX as usize + 1]),
+    dev: UnsafeCell::new(0),
+    ino: UnsafeCell::new(0),
+};
+
+#[cfg(any(
+    target_os = "linux",
+    all(target_os = "android", target_pointer_width = "64"),
+))]
+const STARTUP_UNINIT: u8 = 0;
+#[cfg(any(
+    target_os = "linux",
+    all(target_os = "android", target_pointer_width = "64"),
+))]
+const STARTUP_BUSY: u8 = 1;
+#[cfg(any(
+    target_os = "linux",
+    all(target_os = "android", target_pointer_width = "64"),
+))]
+const STARTUP_READY: u8 = 2;
+#[cfg(any(
+    target_os = "linux",
+    all(target_os = "android", target_pointer_width = "64"),
+))]
+const STARTUP_FAILED: u8 = 3;
+
+/// If `AT_EXECFN` is a relative path, resolve it against the current working directory and
+/// remember the result (along with the identity of the running image), so
+/// `get_initial_static()` can use it after the working directory changes.
+///
+/// This is called by [`init()`](crate::init), and (with the `launch-context` feature) by a
+/// constructor before `main()` runs. Only the first call has any effect.
+pub fn capture_startup_execfn() {
+    #[cfg(any(
+        target_os = "linux",
+        all(target_os = "android", target_pointer_width = "64"),
+    ))]
+    {
+        let startup = &STARTUP_EXECFN;
+        if startup
+            .state
+            .compare_exchange(
+                STARTUP_UNINIT,
+                STARTUP_BUSY,
+                Ordering::Acquire,
+                Ordering::Relaxed,
+            )
+            .is_err()
+        {
+            return;
+        }
+
+        let ok = unsafe {
+            resolve_startup_execfn(&mut *startup.path.get()).map(|(dev, ino)| {
+                *startup.dev.get() = dev;
+                *startup.ino.get() = ino;
+            })
+        };
+        startup.state.store(
+            if ok.is_some() {
+                STARTUP_READY
+            } else {
+                STARTUP_FAILED
+            },
+            Ordering::Release,
+        );
+    }
+}
+
+/// Join the current working directory and a relative `AT_EXECFN` into `buf`, and return the
+/// device ID and inode number of the running image.
+///
+/// If `/proc` is available, `/proc/self/exe` gives the identity of the running image. Otherwise,
+/// the file at the resolved path is only accepted if its GNU build ID matches the one in the
+/// running image's (in-memory) note segments, and its identity is used.
+#[cfg(any(
+    target_os = "linux",
+    all(target_os = "android", target_pointer_width = "64"),
+))]
+unsafe fn resolve_startup_execfn(buf: &mut [u8]) -> Option<(u64, u64)> {
+    use std::os::unix::prelude::*;
+
+    let execfn = libc::getauxval(libc::AT_EXECFN) as *const libc::c_char;
+    if execfn.is_null() || *execfn == b'/' as _ {
+        return None;
+    }
+
+    let mut execfn = CStr::from_ptr(execfn).to_bytes();
+    while execfn.starts_with(b"./") {
+        execfn = &execfn[2..];
+    }
+
+    if libc::getcwd(buf.as_mut_ptr() as *mut _, buf.len()).is_null() {
+        return None;
+    }
+    let mut len = libc::strlen(buf.as_ptr() as *const _);
+    if buf[len - 1] != b'/' {
+        buf[len] = b'/';
+        len += 1;
+    }
+    if len + execfn.len() >= buf.len() {
+        return None;
+    }
+    buf[len..len + execfn.len()].copy_from_slice(execfn);
+    buf[len + execfn.len()] = 0;
+
+    let mut st = std::mem::MaybeUninit::<libc::stat>::uninit();
+    if libc::stat(b"/proc/self/exe\0".as_ptr() as *const _, st.as_mut_ptr()) == 0 {
+        let st = st.assume_init();
+        #[allow(clippy::unnecessary_cast)]
+        return Some((st.st_dev as u64, st.st_ino as u64));
+    }
+
+    let build_id = super::elf::running_build_id()?;
+    let fd = libc::open(buf.as_ptr() as *const _, libc::O_RDONLY | libc::O_CLOEXEC);
+    if fd < 0 {
+        return None;
+    }
+    let file = std::fs::File::from_raw_fd(fd);
+    if super::file_build_id(&file).ok()?.as_deref() != Some(build_id) {
+        return None;
+    }
+    let meta = file.metadata().ok()?;
+    Some((meta.dev(), meta.ino()))
 }
 
 /// Get the path that the process was started with as a static string.
 #[inline]
-pub fn get_initial_static() -> Result<*const libc::c_char, ()> {
+pub fn get_initial_static() -> Result<*const libc::c_char, i32> {
     // On Linux, if /proc isn't mounted, getauxval(AT_EXECFN) might still give us the original path
     #[cfg(any(
         target_os = "linux",
@@ -159,9 +444,31 @@ pub fn get_initial_static() -> Result<*const libc::c_char, ()> {
     ))]
     {
         let path = unsafe { libc::getauxval(libc::AT_EXECFN) } as *const libc::c_char;
-        if !path.is_null() && unsafe { *path } == b'/' as _ {
+        if path.is_null() {
+            return Err(libc::ENOENT);
+        } else if unsafe { *path } == b'/' as _ {
             return Ok(path);
         }
+
+        // If it's relative, use the path resolved at startup, as long as it still refers to the
+        // running image (like the OpenBSD method does with argv[0])
+        let startup = &STARTUP_EXECFN;
+        if startup.state.load(Ordering::Acquire) == STARTUP_READY {
+            unsafe {
+                let path = startup.path.get() as *const libc::c_char;
+                let mut st = std::mem::MaybeUninit::<libc::stat>::uninit();
+                if libc::stat(path, st.as_mut_ptr()) == 0 {
+                    let st = st.assume_init();
+                    #[allow(clippy::unnecessary_cast)]
+                    if st.st_dev as u64 == *startup.dev.get()
+                        && st.st_ino as u64 == *startup.ino.get()
+                    {
+                        return Ok(path);
+                    }
+                }
+            }
+        }
+        return Err(libc::ENOENT);
     }
 
     #[cfg(any(target_os = "solaris", target_os = "illumos"))]
@@ -170,27 +477,32 @@ pub fn get_initial_static() -> Result<*const libc::c_char, ()> {
         if !path.is_null() && unsafe { *path } == b'/' as _ {
             return Ok(path);
         }
+        return Err(libc::ENOENT);
     }
 
-    Err(())
+    Err(libc::ENOSYS)
 }
 
 /// Get the path that the process was started with (and store it into a buffer)
 #[inline]
-pub fn get_initial_buffered(buf: &mut [u8]) -> Result<Option<usize>, ()> {
+pub fn get_initial_buffered(buf: &mut [u8]) -> Result<Option<usize>, i32> {
     // Fallback in case the sysctl() method fails on FreeBSD for some reason
     #[cfg(target_os = "freebsd")]
     {
-        if unsafe {
+        // elf_aux_info() returns the error number directly
+        let eno = unsafe {
             sys::elf_aux_info(sys::AT_EXECPATH, buf.as_mut_ptr() as *mut _, buf.len() as _)
-        } == 0
-            && buf[0] == b'/'
-        {
+        };
+        if eno != 0 {
+            return Err(eno);
+        } else if buf[0] == b'/' {
             return Ok(None);
+        } else {
+            return Err(libc::ENOENT);
         }
     }
 
-    Err(())
+    Err(libc::ENOSYS)
 }
 
 /// The OpenBSD method.
@@ -199,7 +511,7 @@ pub fn get_initial_buffered(buf: &mut [u8]) -> Result<Option<usize>, ()> {
 /// `argv[0]` contains a `/`, it then `stat()`s it to check if that matches the metadata in the
 /// `kinfo_file` we just retrieved. If everything matches, we found the executable.
 #[cfg(target_os = "openbsd")]
-pub fn get_openbsd(buf: &mut [u8]) -> Result<(usize, libc::dev_t, libc::ino_t), ()> {
+pub fn get_openbsd(buf: &mut [u8]) -> Result<(usize, libc::dev_t, libc::ino_t), i32> {
     const PTR_SIZE: usize = std::mem::size_of::<*const u8>();
 
     let pid = unsafe { libc::getpid() };
@@ -224,7 +536,7 @@ pub fn get_openbsd(buf: &mut [u8]) -> Result<(usize, libc::dev_t, libc::ino_t),
         )
     } != 0
     {
-        return Err(());
+        return Err(errno());
     }
 
     // Extract argv[0]
@@ -233,7 +545,7 @@ pub fn get_openbsd(buf: &mut [u8]) -> Result<(usize, libc::dev_t, libc::ino_t),
         arg0 = &arg0[PTR_SIZE..];
     }
     arg0 = &arg0[PTR_SIZE..];
-    arg0 = &arg0[..arg0.iter().position(|&ch| ch == 0).ok_or(())?];
+    arg0 = &arg0[..arg0.iter().position(|&ch| ch == 0).ok_or(libc::ENOENT)?];
 
     // Now try to get a kinfo_file for the executable
     // This is the first filled-in item, so we only need a 1-element buffer
@@ -259,19 +571,20 @@ pub fn get_openbsd(buf: &mut [u8]) -> Result<(usize, libc::dev_t, libc::ino_t),
     } != 0
     {
         // Even if we fail with ENOMEM, the first item may be filled in
-        if unsafe { *crate::errno_ptr() } != libc::ENOMEM {
-            return Err(());
+        let eno = errno();
+        if eno != libc::ENOMEM {
+            return Err(eno);
         }
     }
 
     // The kernel should have initialized this structure
     if kfile_len == 0 {
-        return Err(());
+        return Err(libc::ENOENT);
     }
     let kfile = unsafe { kfile.assume_init() };
     // And it should contain information on the executable file
     if kfile.fd_fd != sys::KERN_FILE_TEXT {
-        return Err(());
+        return Err(libc::ENOENT);
     }
 
     let dev = kfile.va_fsid as libc::dev_t;
@@ -296,13 +609,29 @@ pub fn get_openbsd(buf: &mut [u8]) -> Result<(usize, libc::dev_t, libc::ino_t),
         }
     }
 
-    Err(())
+    Err(libc::ENOENT)
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
 
+    #[cfg(any(
+        target_os = "linux",
+        all(target_os = "android", target_pointer_width = "64"),
+    ))]
+    use std::ffi::OsStr;
+    #[cfg(any(
+        target_os = "linux",
+        all(target_os = "android", target_pointer_width = "64"),
+    ))]
+    use std::os::unix::prelude::*;
+    #[cfg(any(
+        target_os = "linux",
+        all(target_os = "android", target_pointer_width = "64"),
+    ))]
+    use std::path::Path;
+
     use crate::tests::check_path_bytes;
 
     fn check_buflen(buf: &[u8], n: Option<usize>) -> &[u8] {
@@ -330,6 +659,17 @@ mod tests {
         }
     }
 
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    #[test]
+    fn test_verify_procfs() {
+        verify_procfs().unwrap();
+
+        assert_eq!(
+            crate::imp::get_reexec_path(&[Strategy::VerifiedProcfs]).unwrap(),
+            std::path::Path::new("/proc/self/exe")
+        );
+    }
+
     #[test]
     fn test_get_procinfo() {
         for buf in [[0; libc::PATH_MAX as usize], [255; libc::PATH_MAX as usize]].iter_mut() {
@@ -348,6 +688,57 @@ mod tests {
         }
     }
 
+    #[cfg(any(
+        target_os = "linux",
+        all(target_os = "android", target_pointer_width = "64"),
+    ))]
+    #[test]
+    fn test_get_initial_static_relative_child() {
+        if std::env::var_os("REEXEC_TEST_EXECFN").is_none() {
+            return;
+        }
+
+        let execfn = unsafe { libc::getauxval(libc::AT_EXECFN) } as *const libc::c_char;
+        assert_ne!(unsafe { *execfn }, b'/' as _);
+
+        // Without the constructor, the starting directory isn't known until init() is called
+        #[cfg(not(feature = "launch-context"))]
+        assert_eq!(get_initial_static().unwrap_err(), libc::ENOENT);
+        crate::init();
+
+        // The path is still found after changing directories
+        std::env::set_current_dir("/").unwrap();
+        let path = unsafe { CStr::from_ptr(get_initial_static().unwrap()) }.to_bytes();
+        assert_eq!(path[0], b'/');
+        assert_eq!(
+            std::fs::metadata(OsStr::from_bytes(path)).unwrap().ino(),
+            std::fs::metadata(std::env::current_exe().unwrap())
+                .unwrap()
+                .ino()
+        );
+    }
+
+    #[cfg(any(
+        target_os = "linux",
+        all(target_os = "android", target_pointer_width = "64"),
+    ))]
+    #[test]
+    fn test_get_initial_static_relative() {
+        let exe = std::env::current_exe().unwrap();
+
+        let status = std::process::Command::new(Path::new(".").join(exe.file_name().unwrap()))
+            .args(vec![
+                "--exact",
+                "imp::reexec_path::tests::test_get_initial_static_relative_child",
+            ])
+            .current_dir(exe.parent().unwrap())
+            .env("REEXEC_TEST_EXECFN", "1")
+            .stdout(std::process::Stdio::null())
+            .status()
+            .unwrap();
+        assert!(status.success());
+    }
+
     #[test]
     fn test_get_initial_buffered() {
         for buf in [[0; libc::PATH_MAX as usize], [255; libc::PATH_MAX as usize]].iter_mut() {
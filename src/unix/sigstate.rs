@@ -0,0 +1,290 @@
+use std::mem::MaybeUninit;
+
+#[cfg(not(target_os = "redox"))]
+use super::sys;
+
+#[cfg(not(target_os = "redox"))]
+const TIMERS: [libc::c_int; 3] = [sys::ITIMER_REAL, sys::ITIMER_VIRTUAL, sys::ITIMER_PROF];
+
+/// The highest signal number whose handler `normalize()` checks for. (Numbers that aren't valid
+/// signals on the current system are skipped.)
+#[cfg(target_os = "freebsd")]
+const MAX_SIGNAL: usize = 128;
+#[cfg(any(target_os = "solaris", target_os = "illumos"))]
+const MAX_SIGNAL: usize = 74;
+#[cfg(any(
+    target_os = "linux",
+    target_os = "android",
+    target_os = "netbsd",
+    target_os = "dragonfly"
+))]
+const MAX_SIGNAL: usize = 64;
+#[cfg(not(any(
+    target_os = "freebsd",
+    target_os = "solaris",
+    target_os = "illumos",
+    target_os = "linux",
+    target_os = "android",
+    target_os = "netbsd",
+    target_os = "dragonfly"
+)))]
+const MAX_SIGNAL: usize = 32;
+
+/// Check whether `normalize()` should leave the given signal alone.
+#[inline]
+fn skip_signal(sig: libc::c_int) -> bool {
+    // The C library reserves the signals between 32 and SIGRTMIN for internal use; their
+    // dispositions must not be touched
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    if sig >= 32 && sig < libc::SIGRTMIN() {
+        return true;
+    }
+
+    sig == libc::SIGKILL || sig == libc::SIGSTOP
+}
+
+/// The state changed by `normalize()`, so it can be put back if the exec fails.
+pub struct SavedState {
+    mask: libc::sigset_t,
+    ignored: libc::sigset_t,
+    handled: libc::sigset_t,
+    handlers: [libc::sigaction; MAX_SIGNAL],
+    #[cfg(not(target_os = "redox"))]
+    timers: [libc::itimerval; 3],
+}
+
+/// Put the calling thread into a predictable state for `execve()`.
+///
+/// This:
+/// - Restores the default disposition of any of `default_signals` that are currently ignored.
+///   (Signals with handlers are reset to their default dispositions by `execve()` anyway, but
+///   ignored signals stay ignored.)
+/// - Cancels all interval timers (including any set by `alarm()`), since they keep running in the
+///   new program.
+/// - Unblocks all signals, since the signal mask is inherited by the new program.
+///
+/// Unblocking the signals would deliver any pending signals to this program's handlers before
+/// `execve()` is even called. (For example, a pending restart signal could re-enter
+/// `restart_on_signal()`'s handler.) To prevent that, all signals are blocked while the changes
+/// are made, and every signal with a handler is first reset to its default disposition -- just as
+/// `execve()` would. So a pending signal gets the same treatment it would get right after
+/// `execve()`: if its default action is to terminate the process, it does.
+///
+/// Since dispositions are process-wide, this affects other threads until `restore()` is called.
+///
+/// This function is async-signal-safe.
+pub unsafe fn normalize(default_signals: &[i32]) -> SavedState {
+    let mut all = MaybeUninit::uninit();
+    libc::sigfillset(all.as_mut_ptr());
+    let mut mask = MaybeUninit::uninit();
+    libc::pthread_sigmask(libc::SIG_SETMASK, all.as_ptr(), mask.as_mut_ptr());
+
+    let mut ignored = MaybeUninit::uninit();
+    libc::sigemptyset(ignored.as_mut_ptr());
+    let mut ignored = ignored.assume_init();
+
+    for &sig in default_signals {
+        let mut act = MaybeUninit::<libc::sigaction>::uninit();
+        if libc::sigaction(sig, std::ptr::null(), act.as_mut_ptr()) == 0 {
+            let mut act = act.assume_init();
+            if act.sa_sigaction == libc::SIG_IGN {
+                act.sa_sigaction = libc::SIG_DFL;
+                if libc::sigaction(sig, &act, std::ptr::null_mut()) == 0 {
+                    libc::sigaddset(&mut ignored, sig);
+                }
+            }
+        }
+    }
+
+    let mut handled = MaybeUninit::uninit();
+    libc::sigemptyset(handled.as_mut_ptr());
+    let mut handled = handled.assume_init();
+    let mut handlers = [MaybeUninit::<libc::sigaction>::zeroed().assume_init(); MAX_SIGNAL];
+
+    for (i, old) in handlers.iter_mut().enumerate() {
+        let sig = i as libc::c_int + 1;
+        if skip_signal(sig) || libc::sigaction(sig, std::ptr::null(), old) != 0 {
+            continue;
+        }
+
+        if old.sa_sigaction != libc::SIG_DFL && old.sa_sigaction != libc::SIG_IGN {
+            let mut act = *old;
+            act.sa_sigaction = libc::SIG_DFL;
+            if libc::sigaction(sig, &act, std::ptr::null_mut()) == 0 {
+                libc::sigaddset(&mut handled, sig);
+            }
+        }
+    }
+
+    #[cfg(not(target_os = "redox"))]
+    let timers = {
+        let mut timers = [MaybeUninit::<libc::itimerval>::zeroed().assume_init(); 3];
+        let zero = MaybeUninit::<libc::itimerval>::zeroed().assume_init();
+        for (&which, old) in TIMERS.iter().zip(timers.iter_mut()) {
+            libc::setitimer(which, &zero, old);
+        }
+        timers
+    };
+
+    let mut empty = MaybeUninit::uninit();
+    libc::sigemptyset(empty.as_mut_ptr());
+    libc::pthread_sigmask(libc::SIG_SETMASK, empty.as_ptr(), std::ptr::null_mut());
+
+    SavedState {
+        mask: mask.assume_init(),
+        ignored,
+        handled,
+        handlers,
+        #[cfg(not(target_os = "redox"))]
+        timers,
+    }
+}
+
+/// Undo the changes made by `normalize()`. `default_signals` must be the same list that was passed
+/// to `normalize()`.
+///
+/// Interval timers are restarted with the amount of time they had left when they were cancelled.
+///
+/// This function is async-signal-safe.
+pub unsafe fn restore(saved: &SavedState, default_signals: &[i32]) {
+    // Keep everything blocked until the handlers are back in place
+    let mut all = MaybeUninit::uninit();
+    libc::sigfillset(all.as_mut_ptr());
+    libc::pthread_sigmask(libc::SIG_SETMASK, all.as_ptr(), std::ptr::null_mut());
+
+    for (i, act) in saved.handlers.iter().enumerate() {
+        let sig = i as libc::c_int + 1;
+        if libc::sigismember(&saved.handled, sig) == 1 {
+            libc::sigaction(sig, act, std::ptr::null_mut());
+        }
+    }
+
+    #[cfg(not(target_os = "redox"))]
+    for (&which, timer) in TIMERS.iter().zip(saved.timers.iter()) {
+        libc::setitimer(which, timer, std::ptr::null_mut());
+    }
+
+    for &sig in default_signals {
+        if libc::sigismember(&saved.ignored, sig) == 1 {
+            let mut act = MaybeUninit::<libc::sigaction>::uninit();
+            if libc::sigaction(sig, std::ptr::null(), act.as_mut_ptr()) == 0 {
+                let mut act = act.assume_init();
+                act.sa_sigaction = libc::SIG_IGN;
+                libc::sigaction(sig, &act, std::ptr::null_mut());
+            }
+        }
+    }
+
+    libc::pthread_sigmask(libc::SIG_SETMASK, &saved.mask, std::ptr::null_mut());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::sync::Mutex;
+
+    /// normalize() changes process-wide state, so the tests must not run concurrently
+    static LOCK: Mutex<()> = Mutex::new(());
+
+    unsafe fn disposition(sig: i32) -> libc::sighandler_t {
+        let mut act = MaybeUninit::<libc::sigaction>::uninit();
+        assert_eq!(libc::sigaction(sig, std::ptr::null(), act.as_mut_ptr()), 0);
+        act.assume_init().sa_sigaction
+    }
+
+    unsafe fn current_mask() -> libc::sigset_t {
+        let mut mask = MaybeUninit::uninit();
+        libc::pthread_sigmask(libc::SIG_SETMASK, std::ptr::null(), mask.as_mut_ptr());
+        mask.assume_init()
+    }
+
+    #[test]
+    fn test_normalize() {
+        let _guard = LOCK.lock().unwrap();
+
+        unsafe {
+            // SIGWINCH's default action is to be ignored, so this is harmless even if it's
+            // delivered while we're changing things
+            let orig_disposition = disposition(libc::SIGWINCH);
+            let mut act = MaybeUninit::<libc::sigaction>::zeroed().assume_init();
+            act.sa_sigaction = libc::SIG_IGN;
+            libc::sigaction(libc::SIGWINCH, &act, std::ptr::null_mut());
+
+            let mut block = MaybeUninit::uninit();
+            libc::sigemptyset(block.as_mut_ptr());
+            let mut block = block.assume_init();
+            libc::sigaddset(&mut block, libc::SIGWINCH);
+            libc::pthread_sigmask(libc::SIG_BLOCK, &block, std::ptr::null_mut());
+
+            let mut timer = MaybeUninit::<libc::itimerval>::zeroed().assume_init();
+            timer.it_value.tv_sec = 1000;
+            libc::setitimer(sys::ITIMER_VIRTUAL, &timer, std::ptr::null_mut());
+
+            let saved = normalize(&[libc::SIGWINCH]);
+
+            assert_eq!(disposition(libc::SIGWINCH), libc::SIG_DFL);
+            assert_eq!(libc::sigismember(&current_mask(), libc::SIGWINCH), 0);
+            libc::getitimer(sys::ITIMER_VIRTUAL, &mut timer);
+            assert_eq!(timer.it_value.tv_sec, 0);
+            assert_eq!(timer.it_value.tv_usec, 0);
+
+            restore(&saved, &[libc::SIGWINCH]);
+
+            assert_eq!(disposition(libc::SIGWINCH), libc::SIG_IGN);
+            assert_eq!(libc::sigismember(&current_mask(), libc::SIGWINCH), 1);
+            libc::getitimer(sys::ITIMER_VIRTUAL, &mut timer);
+            assert!(timer.it_value.tv_sec > 990);
+
+            // Clean up
+            let timer = MaybeUninit::<libc::itimerval>::zeroed().assume_init();
+            libc::setitimer(sys::ITIMER_VIRTUAL, &timer, std::ptr::null_mut());
+            libc::pthread_sigmask(libc::SIG_UNBLOCK, &block, std::ptr::null_mut());
+            act.sa_sigaction = orig_disposition;
+            libc::sigaction(libc::SIGWINCH, &act, std::ptr::null_mut());
+        }
+    }
+
+    static URG_RECEIVED: AtomicBool = AtomicBool::new(false);
+
+    extern "C" fn handle_urg(_sig: libc::c_int) {
+        URG_RECEIVED.store(true, Ordering::SeqCst);
+    }
+
+    #[test]
+    fn test_normalize_pending() {
+        let _guard = LOCK.lock().unwrap();
+
+        unsafe {
+            // SIGURG's default action is to be ignored, so it's harmless if it's delivered
+            // (including to another thread) while its handler is reset
+            let mut act = MaybeUninit::<libc::sigaction>::zeroed().assume_init();
+            act.sa_sigaction = handle_urg as extern "C" fn(libc::c_int) as libc::sighandler_t;
+            let mut orig = MaybeUninit::<libc::sigaction>::uninit();
+            libc::sigaction(libc::SIGURG, &act, orig.as_mut_ptr());
+
+            let mut block = MaybeUninit::uninit();
+            libc::sigemptyset(block.as_mut_ptr());
+            let mut block = block.assume_init();
+            libc::sigaddset(&mut block, libc::SIGURG);
+            libc::pthread_sigmask(libc::SIG_BLOCK, &block, std::ptr::null_mut());
+            libc::pthread_kill(libc::pthread_self(), libc::SIGURG);
+
+            // The pending signal must not reach the handler; it gets the default action instead
+            let saved = normalize(&[]);
+            assert_eq!(disposition(libc::SIGURG), libc::SIG_DFL);
+            assert_eq!(libc::sigismember(&current_mask(), libc::SIGURG), 0);
+            assert!(!URG_RECEIVED.load(Ordering::SeqCst));
+
+            restore(&saved, &[]);
+            assert_eq!(disposition(libc::SIGURG), act.sa_sigaction);
+            assert_eq!(libc::sigismember(&current_mask(), libc::SIGURG), 1);
+            assert!(!URG_RECEIVED.load(Ordering::SeqCst));
+
+            // Clean up
+            libc::pthread_sigmask(libc::SIG_UNBLOCK, &block, std::ptr::null_mut());
+            libc::sigaction(libc::SIGURG, orig.as_ptr(), std::ptr::null_mut());
+        }
+    }
+}
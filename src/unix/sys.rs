@@ -1,6 +1,26 @@
 #[cfg(target_os = "freebsd")]
 pub const AT_EXECPATH: libc::c_int = 15;
 
+#[cfg(any(target_os = "linux", target_os = "android"))]
+pub const FSOPEN_CLOEXEC: libc::c_uint = 1;
+#[cfg(any(target_os = "linux", target_os = "android"))]
+pub const FSCONFIG_CMD_CREATE: libc::c_uint = 6;
+#[cfg(any(target_os = "linux", target_os = "android"))]
+pub const FSMOUNT_CLOEXEC: libc::c_uint = 1;
+#[cfg(any(target_os = "linux", target_os = "android"))]
+pub const P_PIDFD: libc::idtype_t = 3;
+#[cfg(any(target_os = "linux", target_os = "android"))]
+pub const CLOSE_RANGE_CLOEXEC: libc::c_uint = 1 << 2;
+
+#[cfg(target_os = "android")]
+pub const ITIMER_REAL: libc::c_int = 0;
+#[cfg(target_os = "android")]
+pub const ITIMER_VIRTUAL: libc::c_int = 1;
+#[cfg(target_os = "android")]
+pub const ITIMER_PROF: libc::c_int = 2;
+#[cfg(not(any(target_os = "android", target_os = "redox")))]
+pub use libc::{ITIMER_PROF, ITIMER_REAL, ITIMER_VIRTUAL};
+
 #[cfg(target_os = "openbsd")]
 pub const ARG_MAX: usize = 512 * 1024;
 #[cfg(target_os = "openbsd")]
@@ -8,52 +8,112 @@ use winapi::{
     um::winbase::QueryFullProcessImageNameA, um::winbase::QueryFullProcessImageNameW,
 };
 
-use crate::errno_ptr;
+use crate::{errno_ptr, Probe, ReexecError, Strategy};
+
+pub const DEFAULT_STRATEGIES: &[Strategy] = &[Strategy::ProcInfo];
+
+/// Record a failure of the (only) strategy in a new `ReexecError`.
+#[inline]
+fn strategy_error(eno: i32) -> ReexecError {
+    let mut err = ReexecError::new(eno);
+    err.push(Strategy::ProcInfo, eno);
+    err
+}
+
+/// Check that the only strategy supported on Windows was requested, recording failures for any
+/// strategies that were requested before it.
+fn check_strategies(strategies: &[Strategy]) -> Result<(), ReexecError> {
+    let mut err = ReexecError::new(libc::ENOENT);
+    for &strategy in strategies {
+        if strategy == Strategy::ProcInfo {
+            return Ok(());
+        }
+        err.push(strategy, libc::ENOSYS);
+    }
+    Err(err)
+}
+
+pub unsafe fn reexecve(
+    strategies: &[Strategy],
+    argv: *const *const libc::c_char,
+    envp: *const *const libc::c_char,
+) -> ReexecError {
+    if let Err(err) = check_strategies(strategies) {
+        return err;
+    }
 
-pub unsafe fn reexecve(argv: *const *const libc::c_char, envp: *const *const libc::c_char) -> i32 {
     let mut buf = [0; MAX_PATH];
     let mut len = buf.len() as _;
     if QueryFullProcessImageNameA(GetCurrentProcess(), 0, buf.as_mut_ptr(), &mut len) == 0 {
-        return libc::ENOENT;
+        return strategy_error(libc::ENOENT);
     }
 
     libc::execve(buf.as_ptr(), argv, envp);
-    *errno_ptr()
+    strategy_error(*errno_ptr())
 }
 
 pub unsafe fn wreexecve(
+    strategies: &[Strategy],
     argv: *const *const libc::wchar_t,
     envp: *const *const libc::wchar_t,
-) -> i32 {
+) -> ReexecError {
+    if let Err(err) = check_strategies(strategies) {
+        return err;
+    }
+
     let mut buf = [0; MAX_PATH];
     let mut len = buf.len() as _;
     if QueryFullProcessImageNameW(GetCurrentProcess(), 0, buf.as_mut_ptr(), &mut len) == 0 {
-        return libc::ENOENT;
+        return strategy_error(libc::ENOENT);
     }
 
     libc::wexecve(buf.as_ptr(), argv, envp);
-    *errno_ptr()
+    strategy_error(*errno_ptr())
+}
+
+pub fn probe(strategy: Strategy) -> Probe {
+    if strategy != Strategy::ProcInfo {
+        return Probe::new(strategy, Err(libc::ENOSYS), None);
+    }
+
+    let mut buf = [0; MAX_PATH];
+    let mut len = buf.len() as _;
+    if unsafe {
+        QueryFullProcessImageNameW(GetCurrentProcess(), 0, buf.as_mut_ptr(), &mut len) == 0
+    } {
+        return Probe::new(strategy, Err(libc::ENOENT), None);
+    }
+    let path = PathBuf::from(OsString::from_wide(&buf[..len as usize]));
+
+    let access = if matches!(path.metadata(), Ok(m) if m.is_file()) {
+        0
+    } else {
+        libc::ENOENT
+    };
+    Probe::new(strategy, Ok(path), Some(access))
 }
 
 #[inline]
-pub fn get_reexec_path() -> Result<Cow<'static, Path>, i32> {
-    get_exe_path()
+pub fn get_reexec_path(strategies: &[Strategy]) -> Result<Cow<'static, Path>, ReexecError> {
+    get_exe_path(strategies)
 }
 
-pub fn get_exe_path() -> Result<Cow<'static, Path>, i32> {
+pub fn get_exe_path(strategies: &[Strategy]) -> Result<Cow<'static, Path>, ReexecError> {
+    check_strategies(strategies)?;
+
     let mut buf = [0; MAX_PATH];
     let mut len = buf.len() as _;
     if unsafe {
         QueryFullProcessImageNameW(GetCurrentProcess(), 0, buf.as_mut_ptr(), &mut len) == 0
     } {
-        return Err(libc::ENOENT);
+        return Err(strategy_error(libc::ENOENT));
     }
     let path = PathBuf::from(OsString::from_wide(&buf[..len as usize]));
 
     // Check that the path exists and is a regular file
     // TODO: Is there a way to check if the path is executable?
     if !matches!(path.metadata(), Ok(m) if m.is_file()) {
-        return Err(libc::ENOENT);
+        return Err(strategy_error(libc::ENOENT));
     }
 
     Ok(Cow::Owned(path))